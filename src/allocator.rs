@@ -52,6 +52,26 @@ pub fn init_heap(
     Ok(())
 }
 
+/// ヒープの使用状況
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeapStats {
+    pub size: usize,
+    /// 割り当て済みのバイト数．ブロックリストにある空きブロックも含む
+    pub used: usize,
+    /// ブロックリストにある，再利用を待っている空きブロックのバイト数
+    pub cached: usize,
+}
+
+impl HeapStats {
+    pub fn free(&self) -> usize {
+        self.size - self.used
+    }
+}
+
+pub fn heap_stats() -> HeapStats {
+    x86_64::instructions::interrupts::without_interrupts(|| ALLOCATOR.lock().stats())
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy {
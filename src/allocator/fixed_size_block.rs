@@ -1,6 +1,7 @@
-use super::Locked;
+use super::{HeapStats, Locked};
 use alloc::alloc::GlobalAlloc;
 use core::{alloc::Layout, mem, ptr::NonNull};
+use x86_64::instructions::interrupts;
 
 /// 使用するブロックサイズ
 ///
@@ -36,6 +37,28 @@ impl FixedSizeBlockAllcator {
         self.failback_allocator.init(heap_start, heap_size);
     }
 
+    pub fn stats(&self) -> HeapStats {
+        let cached = self
+            .list_heads
+            .iter()
+            .zip(BLOCK_SIZES)
+            .map(|(head, &block_size)| {
+                let mut count = 0;
+                let mut node = head.as_deref();
+                while let Some(current) = node {
+                    count += 1;
+                    node = current.next.as_deref();
+                }
+                count * block_size
+            })
+            .sum();
+        HeapStats {
+            size: self.failback_allocator.size(),
+            used: self.failback_allocator.used(),
+            cached,
+        }
+    }
+
     /// 代替アロケータを使って割り当てを行う
     fn failbac_alloc(&mut self, layout: Layout) -> *mut u8 {
         match self.failback_allocator.allocate_first_fit(layout) {
@@ -51,8 +74,20 @@ fn list_index(layout: &Layout) -> Option<usize> {
     BLOCK_SIZES.iter().position(|&s| s >= requierd_block_size)
 }
 
+// ロックを持ったまま割り込まれると，割り込みハンドラや他のスレッドが同じロックを待って止まってしまう
+// そうならないように，ロックを持っている間は割り込みを無効にする
 unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllcator> {
     unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.alloc_locked(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.dealloc_locked(ptr, layout))
+    }
+}
+
+impl Locked<FixedSizeBlockAllcator> {
+    unsafe fn alloc_locked(&self, layout: Layout) -> *mut u8 {
         let mut allocator = self.lock();
 
         match list_index(&layout) {
@@ -77,7 +112,7 @@ unsafe impl GlobalAlloc for Locked<FixedSizeBlockAllcator> {
         }
     }
 
-    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+    unsafe fn dealloc_locked(&self, ptr: *mut u8, layout: Layout) {
         let mut allocator = self.lock();
 
         match list_index(&layout) {
@@ -1,3 +1,4 @@
+use alloc::boxed::Box;
 use lazy_static::lazy_static;
 use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
 use x86_64::structures::tss::TaskStateSegment;
@@ -17,36 +18,85 @@ lazy_static! {
 
             stack_start + STACK_SIZE
         };
+        // ring3で割り込みが起きたとき，CPUはこのスタックに切り替える
+        tss.privilege_stack_table[0] = {
+            const STACK_SIZE: usize = 4096 * 5;
+            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
+
+            let stack_start = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(STACK) });
+
+            stack_start + STACK_SIZE
+        };
         tss
     };
 }
 
 lazy_static! {
-    static ref GDT: (GlobalDescriptorTable, Selectors) = {
-        let mut gdt = GlobalDescriptorTable::new();
-        let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
-        (
-            gdt,
-            Selectors {
-                code_selector,
-                tss_selector,
-            },
-        )
-    };
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(&TSS);
+}
+
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    // sysret/syscallはSTARに書いたセレクタからの相対位置でセグメントを決めるので，
+    // カーネルコード，カーネルデータ，ユーザデータ，ユーザコードの順に並べる必要がある
+    let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
+    let data_selector = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data_selector = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code_selector = gdt.add_entry(Descriptor::user_code_segment());
+    let tss_selector = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            code_selector,
+            data_selector,
+            user_code_selector,
+            user_data_selector,
+            tss_selector,
+        },
+    )
 }
 
-struct Selectors {
-    code_selector: SegmentSelector,
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub code_selector: SegmentSelector,
+    pub data_selector: SegmentSelector,
+    pub user_code_selector: SegmentSelector,
+    pub user_data_selector: SegmentSelector,
     tss_selector: SegmentSelector,
 }
 
+/// GDTに登録したセグメントのセレクタを返す
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
 pub fn init() {
-    use x86_64::instructions::segmentation::set_cs;
+    load(&GDT.0, &GDT.1);
+}
+
+/// APのためのGDTとTSSを作ってロードする
+///
+/// TSSはCPUごとに別のものが必要なので，ヒープに作って解放しない
+/// スタックは呼び出し元が用意する
+/// セレクタの値はBSPのものと同じになる
+pub fn init_ap(double_fault_stack_top: VirtAddr, privilege_stack_top: VirtAddr) {
+    let mut tss = TaskStateSegment::new();
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = double_fault_stack_top;
+    tss.privilege_stack_table[0] = privilege_stack_top;
+    let tss: &'static TaskStateSegment = Box::leak(Box::new(tss));
+
+    let (gdt, selectors) = build_gdt(tss);
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+    load(gdt, &selectors);
+}
+
+fn load(gdt: &'static GlobalDescriptorTable, selectors: &Selectors) {
+    use x86_64::instructions::segmentation::{Segment, CS, SS};
     use x86_64::instructions::tables::load_tss;
-    GDT.0.load();
+    gdt.load();
     unsafe {
-        set_cs(GDT.1.code_selector);
-        load_tss(GDT.1.tss_selector);
+        CS::set_reg(selectors.code_selector);
+        SS::set_reg(selectors.data_selector);
+        load_tss(selectors.tss_selector);
     }
 }
@@ -1,5 +1,9 @@
+use crate::process::{self, Fault};
+use crate::smp::lapic;
 use crate::{gdt, hit_loop, print, println};
+use core::sync::atomic::{AtomicU64, Ordering};
 use lazy_static::lazy_static;
+use x86_64::instructions::interrupts;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode};
 
 use pic8259::ChainedPics;
@@ -21,9 +25,15 @@ lazy_static! {
                 .set_handler_fn(double_fault_handler)
                 .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
         }
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        for (irq, &stub) in IRQ_STUBS.iter().enumerate() {
+            idt[usize::from(PIC_1_OFFSET) + irq].set_handler_fn(stub);
+        }
         idt.page_fault.set_handler_fn(page_fault_handler);
+        idt.general_protection_fault
+            .set_handler_fn(general_protection_fault_handler);
+        idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
+        idt[usize::from(lapic::WAKEUP_VECTOR)].set_handler_fn(wakeup_ipi_handler);
+        idt[usize::from(lapic::SPURIOUS_VECTOR)].set_handler_fn(lapic_spurious_handler);
         idt
     };
 }
@@ -31,20 +41,222 @@ pub fn init_idt() {
     IDT.load();
 }
 
-#[derive(Debug, Clone, Copy)]
+/// レガシーPICのIRQ番号
+///
+/// ベクタ番号は `PIC_1_OFFSET + IRQ番号` になる
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u8)]
 pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+    Timer = 0,
+    Keyboard = 1,
+    // セカンダリPICがつながっている
+    Cascade = 2,
+    Com2 = 3,
+    Com1 = 4,
+    Rtc = 8,
+    Mouse = 12,
+    PrimaryAta = 14,
+    SecondaryAta = 15,
 }
 
 impl InterruptIndex {
-    fn as_u8(self) -> u8 {
+    pub fn as_irq(self) -> u8 {
         self as u8
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    pub fn as_vector(self) -> u8 {
+        PIC_1_OFFSET + self.as_irq()
+    }
+}
+
+/// IRQハンドラ
+///
+/// 割り込みコンテキストで呼び出されるので，ブロックしたりアロケートしてはいけない
+/// EOIの送信はディスパッチャが行うので，ハンドラで送る必要はない
+pub type IrqHandler = fn();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IrqError {
+    /// IRQ番号が0..16の範囲外
+    InvalidIrq(u8),
+    /// カスケード用のIRQ2にはハンドラを登録できない
+    ReservedIrq(u8),
+    AlreadyRegistered(u8),
+    NotRegistered(u8),
+}
+
+const IRQ_COUNT: usize = 16;
+
+static IRQ_HANDLERS: Mutex<[Option<IrqHandler>; IRQ_COUNT]> = Mutex::new([None; IRQ_COUNT]);
+
+/// IRQにハンドラを登録し，PICでそのIRQのマスクを外す
+pub fn register_irq(irq: u8, handler: IrqHandler) -> Result<(), IrqError> {
+    check_irq(irq)?;
+
+    // 割り込みハンドラも同じロックを取るので，割り込みを無効化してデッドロックを防ぐ
+    interrupts::without_interrupts(|| {
+        let mut handlers = IRQ_HANDLERS.lock();
+        let slot = &mut handlers[usize::from(irq)];
+        if slot.is_some() {
+            return Err(IrqError::AlreadyRegistered(irq));
+        }
+        *slot = Some(handler);
+        set_irq_masked(irq, false);
+        Ok(())
+    })
+}
+
+/// IRQのハンドラを外し，PICでそのIRQをマスクする
+pub fn unregister_irq(irq: u8) -> Result<IrqHandler, IrqError> {
+    check_irq(irq)?;
+
+    interrupts::without_interrupts(|| {
+        let handler = IRQ_HANDLERS.lock()[usize::from(irq)]
+            .take()
+            .ok_or(IrqError::NotRegistered(irq))?;
+        set_irq_masked(irq, true);
+        Ok(handler)
+    })
+}
+
+fn check_irq(irq: u8) -> Result<(), IrqError> {
+    if usize::from(irq) >= IRQ_COUNT {
+        Err(IrqError::InvalidIrq(irq))
+    } else if irq == InterruptIndex::Cascade.as_irq() {
+        Err(IrqError::ReservedIrq(irq))
+    } else {
+        Ok(())
+    }
+}
+
+/// PICを初期化し，カーネル標準のIRQハンドラを登録する
+///
+/// ハンドラが登録されていないIRQはマスクしておく
+pub fn init_pics() {
+    unsafe {
+        let mut pics = PICS.lock();
+        pics.initialize();
+        // カスケード以外をすべてマスクする
+        pics.write_masks(!(1 << InterruptIndex::Cascade.as_irq()), 0xff);
+    }
+
+    register_irq(InterruptIndex::Timer.as_irq(), timer_irq_handler)
+        .expect("timer IRQ already registered");
+    register_irq(InterruptIndex::Keyboard.as_irq(), keyboard_irq_handler)
+        .expect("keyboard IRQ already registered");
+}
+
+fn set_irq_masked(irq: u8, masked: bool) {
+    let mut pics = PICS.lock();
+    unsafe {
+        let [mut primary, mut secondary] = pics.read_masks();
+        let (mask, bit) = if irq < 8 {
+            (&mut primary, irq)
+        } else {
+            (&mut secondary, irq - 8)
+        };
+        if masked {
+            *mask |= 1 << bit;
+        } else {
+            *mask &= !(1 << bit);
+        }
+        pics.write_masks(primary, secondary);
+    }
+}
+
+/// IRQごとのエントリポイントを生成する
+///
+/// IDTには`extern "x86-interrupt"`な関数しか登録できないので，
+/// IRQ番号を埋め込んだスタブから共通のディスパッチャを呼ぶ
+macro_rules! irq_stubs {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch_irq($irq);
+            }
+        )*
+
+        const IRQ_STUBS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQ_COUNT] =
+            [$($name),*];
+    };
+}
+
+irq_stubs! {
+    0 => irq0_handler,
+    1 => irq1_handler,
+    2 => irq2_handler,
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+}
+
+/// ベクタ番号ごとの割り込み回数
+static INTERRUPT_COUNTS: [AtomicU64; 256] = [const { AtomicU64::new(0) }; 256];
+/// 無視したスプリアス割り込みの回数 (0: IRQ7, 1: IRQ15)
+static SPURIOUS_COUNTS: [AtomicU64; 2] = [const { AtomicU64::new(0) }; 2];
+
+/// 割り込みハンドラから呼び出される
+///
+/// ロックを取らないので，どの割り込みハンドラから呼んでも安全
+fn count_interrupt(vector: u8) {
+    INTERRUPT_COUNTS[usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// 指定したベクタの割り込みが何回発生したかを返す
+///
+/// スプリアス割り込みとして無視したものは含まない
+pub fn interrupt_count(vector: u8) -> u64 {
+    INTERRUPT_COUNTS[usize::from(vector)].load(Ordering::Relaxed)
+}
+
+/// タイマ割り込みが起きた回数
+///
+/// PITの設定は変えていないので，約18.2Hzで増えていく
+pub fn timer_ticks() -> u64 {
+    interrupt_count(InterruptIndex::Timer.as_vector())
+}
+
+/// 一度でも発生した割り込みの (ベクタ番号, 回数) を返す
+///
+/// 診断表示用
+pub fn interrupt_stats() -> impl Iterator<Item = (u8, u64)> {
+    (0..=u8::MAX)
+        .map(|vector| (vector, interrupt_count(vector)))
+        .filter(|&(_, count)| count != 0)
+}
+
+/// 無視したスプリアス割り込みの回数を (IRQ7, IRQ15) で返す
+pub fn spurious_interrupt_counts() -> (u64, u64) {
+    (
+        SPURIOUS_COUNTS[0].load(Ordering::Relaxed),
+        SPURIOUS_COUNTS[1].load(Ordering::Relaxed),
+    )
+}
+
+/// PICのISR(In-Service Register)を読む
+///
+/// 上位8ビットがセカンダリ，下位8ビットがプライマリ
+fn read_pic_isr() -> u16 {
+    use x86_64::instructions::port::Port;
+
+    // OCW3でISRを読むように指示してから，コマンドポートを読む
+    const READ_ISR: u8 = 0x0b;
+    let mut primary_command: Port<u8> = Port::new(0x20);
+    let mut secondary_command: Port<u8> = Port::new(0xa0);
+    unsafe {
+        primary_command.write(READ_ISR);
+        secondary_command.write(READ_ISR);
+        (u16::from(secondary_command.read()) << 8) | u16::from(primary_command.read())
     }
 }
 
@@ -54,43 +266,121 @@ extern "x86-interrupt" fn page_fault_handler(
 ) {
     use x86_64::registers::control::Cr2;
 
+    count_interrupt(14);
+    let address = Cr2::read();
+    // システムコールがプロセスの代わりにユーザ領域に触って起きた例外も，プロセスのせいにする
+    let on_behalf_of_user =
+        process::current_pid() != process::Pid::KERNEL && process::is_user_address(address);
+    if from_user_mode(&stack_frame) || on_behalf_of_user {
+        // ユーザプロセスだけを終了させて，カーネルは動き続ける
+        process::kill_current(Fault::PageFault(address));
+    }
+
     println!("EXEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
+    println!("Accessed Address: {:?}", address);
     println!("Error Code: {:?}", error_code);
     println!("{:#?}", stack_frame);
     hit_loop();
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use x86_64::instructions::port::Port;
+fn dispatch_irq(irq: u8) {
+    // IRQ7とIRQ15は，8259がスプリアス割り込みを通知するときにも使われる
+    // ISRのビットが立っていなければスプリアス割り込み
+    if irq == 7 && read_pic_isr() & (1 << 7) == 0 {
+        // その場合はEOIを送ってはいけない
+        SPURIOUS_COUNTS[0].fetch_add(1, Ordering::Relaxed);
+        return;
+    }
+    if irq == 15 && read_pic_isr() & (1 << 15) == 0 {
+        SPURIOUS_COUNTS[1].fetch_add(1, Ordering::Relaxed);
+        // プライマリはセカンダリからのカスケード割り込みを本物として受け取っているので，
+        // プライマリにだけEOIを送る
+        unsafe {
+            PICS.lock()
+                .notify_end_of_interrupt(InterruptIndex::Cascade.as_vector());
+        }
+        return;
+    }
+
+    let vector = PIC_1_OFFSET + irq;
+    count_interrupt(vector);
 
-    let mut port = Port::new(0x60);
-    let scancode: u8 = unsafe { port.read() };
-    crate::task::keyboard::add_scancode(scancode);
+    // ハンドラを呼んでいる間はロックを持たない
+    let handler = IRQ_HANDLERS.lock()[usize::from(irq)];
+    if let Some(handler) = handler {
+        handler();
+    }
+
+    // 割り込みの終了をPICに通知する
     unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+        PICS.lock().notify_end_of_interrupt(vector);
     }
+
+    // 切り替えた先のスレッドがしばらく戻ってこないことがあるので，EOIを送った後に切り替える
+    crate::thread::preempt();
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+fn keyboard_irq_handler() {
+    if let Some(scancode) = crate::ps2::read_irq_data() {
+        crate::task::keyboard::add_scancode(scancode);
+    }
+}
+
+fn timer_irq_handler() {
     // print!(".");
+    crate::thread::timer_tick();
+}
 
-    // 割り込みの終了をPICに通知する
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+/// hltしているAPを起こすIPI
+///
+/// 起きればよいので，数えてEOIを送るだけ
+extern "x86-interrupt" fn wakeup_ipi_handler(_stack_frame: InterruptStackFrame) {
+    count_interrupt(lapic::WAKEUP_VECTOR);
+    lapic::end_of_interrupt();
 }
 
+/// ローカルAPICのスプリアス割り込みにはEOIを送らない
+extern "x86-interrupt" fn lapic_spurious_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    count_interrupt(3);
     println!("EXEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+extern "x86-interrupt" fn general_protection_fault_handler(
+    stack_frame: InterruptStackFrame,
+    error_code: u64,
+) {
+    count_interrupt(13);
+    if from_user_mode(&stack_frame) {
+        process::kill_current(Fault::GeneralProtection(error_code));
+    }
+
+    panic!(
+        "EXEPTION: GENERAL PROTECTION FAULT (error code {:#x})\n{:#?}",
+        error_code, stack_frame
+    );
+}
+
+extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    count_interrupt(6);
+    if from_user_mode(&stack_frame) {
+        process::kill_current(Fault::InvalidOpcode);
+    }
+
+    panic!("EXEPTION: INVALID OPCODE\n{:#?}", stack_frame);
+}
+
+/// 例外がring3で起きたかどうか
+fn from_user_mode(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 0b11 == 3
+}
+
 extern "x86-interrupt" fn double_fault_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) -> ! {
+    count_interrupt(8);
     panic!("EXEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
 
@@ -98,3 +388,37 @@ extern "x86-interrupt" fn double_fault_handler(
 fn test_breakpoint_exception() {
     x86_64::instructions::interrupts::int3();
 }
+
+#[test_case]
+fn test_breakpoint_is_counted() {
+    let before = interrupt_count(3);
+    x86_64::instructions::interrupts::int3();
+    assert_eq!(interrupt_count(3), before + 1);
+    assert!(interrupt_stats().any(|(vector, _)| vector == 3));
+}
+
+#[test_case]
+fn test_register_irq() {
+    fn dummy_handler() {}
+    let irq = InterruptIndex::SecondaryAta.as_irq();
+
+    assert_eq!(register_irq(irq, dummy_handler), Ok(()));
+    assert_eq!(
+        register_irq(irq, dummy_handler),
+        Err(IrqError::AlreadyRegistered(irq))
+    );
+    assert!(unregister_irq(irq).is_ok());
+    assert!(matches!(
+        unregister_irq(irq),
+        Err(IrqError::NotRegistered(_))
+    ));
+
+    assert_eq!(
+        register_irq(16, dummy_handler),
+        Err(IrqError::InvalidIrq(16))
+    );
+    assert_eq!(
+        register_irq(InterruptIndex::Cascade.as_irq(), dummy_handler),
+        Err(IrqError::ReservedIrq(2))
+    );
+}
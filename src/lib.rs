@@ -11,8 +11,14 @@ pub mod allocator;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod process;
+pub mod ps2;
 pub mod serial;
+pub mod shell;
+pub mod smp;
+pub mod syscall;
 pub mod task;
+pub mod thread;
 pub mod vga_buffer;
 
 extern crate alloc;
@@ -28,8 +34,11 @@ entry_point!(test_kernel_main);
 pub fn init() {
     interrupts::init_idt();
     gdt::init();
-    unsafe {
-        interrupts::PICS.lock().initialize();
+    syscall::init();
+    interrupts::init_pics();
+    // PS/2コントローラがなくても，キーボード以外は動かせる
+    if let Err(err) = ps2::init() {
+        println!("WARNING: PS/2 controller initialization failed: {:?}", err);
     }
     x86_64::instructions::interrupts::enable();
 }
@@ -46,6 +55,14 @@ pub fn hit_loop() -> ! {
     }
 }
 
+/// キーボードコントローラ経由でCPUをリセットする
+pub fn reboot() -> ! {
+    x86_64::instructions::interrupts::disable();
+    ps2::pulse_reset_line();
+    // リセットされなかったら止まっておく
+    hit_loop();
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(u32)]
 pub enum QemuExitCode {
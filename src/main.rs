@@ -11,7 +11,9 @@ use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use wos_os_n71::{
     println, serial_println,
-    task::{keyboard, simple_executor::SimpleExecutor, Task},
+    shell::Shell,
+    smp,
+    task::{keyboard, mouse, Priority},
     vga_buffer::{colored_letter, ColorCode},
 };
 use x86_64::structures::paging::Page;
@@ -34,6 +36,11 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     let mut mapper = unsafe { memory::init(phys_mem_offset) };
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    // APのスタックなどをヒープとフレームアロケータから取るので，その後に起こす
+    match smp::init(&mut mapper, &mut frame_allocator) {
+        Ok(cpus) => println!("{} CPU(s) online", cpus),
+        Err(err) => println!("WARNING: application processors not started: {:?}", err),
+    }
 
     // let heap_value = Box::new(41);
     // println!("heap_value at {:p}", heap_value);
@@ -79,10 +86,25 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     #[cfg(test)]
     test_main();
 
-    // let mut executor = SimpleExecutor::new();
     let mut executor = Executor::new();
-    // executor.spawn(Task::new(example_task()));
-    executor.spawn(Task::new(keyboard::introduction::print_keypresses()));
+    // executor.spawn(example_task());
+    let shell = Shell::new(executor.monitor());
+    executor.spawn_named(shell.run(), "shell", Priority::High);
+    executor.spawn_named(
+        keyboard::report_dropped_input(),
+        "input warnings",
+        Priority::Low,
+    );
+    match mouse::init() {
+        Ok(_) => {
+            executor.spawn_named(
+                keyboard::introduction::click_sections(),
+                "mouse cursor",
+                Priority::High,
+            );
+        }
+        Err(err) => println!("mouse unavailable: {:?}", err),
+    }
     executor.run();
 
     println!("It did not crash!");
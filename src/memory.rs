@@ -1,11 +1,18 @@
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
 use x86_64::{
+    registers::control::Cr3,
     structures::paging::{
-        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PhysFrame, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTable,
+        PageTableFlags, PhysFrame, Size4KiB,
     },
     PhysAddr, VirtAddr,
 };
 
+/// `init`に渡された物理メモリのオフセット．0なら初期化前
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
 /// 与えられたページを フレーム 0xb8000 に試しにマップする（VGAバッファ）
 pub fn create_example_mapping(
     page: Page,
@@ -24,6 +31,36 @@ pub fn create_example_mapping(
     map_to_result.expect("map to failed").flush();
 }
 
+/// `start`から`size`バイトを新しいフレームにマップし，ユーザからアクセスできるようにする
+///
+/// フレームは物理メモリのマッピングを通して0で埋めるので，
+/// `flags`にWRITABLEがなくても中身は初期化される
+pub fn map_user_region(
+    start: VirtAddr,
+    size: u64,
+    flags: PageTableFlags,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    let start_page: Page = Page::containing_address(start);
+    let end_page: Page = Page::containing_address(start + size - 1u64);
+
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = frame_allocator
+            .allocate_frame()
+            .ok_or(MapToError::FrameAllocationFailed)?;
+        let frame_ptr: *mut u8 =
+            (mapper.phys_offset() + frame.start_address().as_u64()).as_mut_ptr();
+        unsafe {
+            core::ptr::write_bytes(frame_ptr, 0, 4096);
+            mapper.map_to(page, frame, flags, frame_allocator)?.flush();
+        }
+    }
+
+    Ok(())
+}
+
 /// つねにNoneを返す
 pub struct EmptyFrameAllocator;
 
@@ -33,6 +70,64 @@ unsafe impl FrameAllocator<Size4KiB> for EmptyFrameAllocator {
     }
 }
 
+/// 使い終わって返されたフレームのリスト
+///
+/// ヒープを使わずに済むように，各フレームの先頭に次のフレームのアドレスを書いてつなぐ
+struct FreeFrames {
+    head: Option<PhysFrame>,
+    len: usize,
+}
+
+static FREE_FRAMES: Mutex<FreeFrames> = Mutex::new(FreeFrames { head: None, len: 0 });
+
+/// 物理メモリのマッピングを通して，フレームの先頭の8バイトを指す
+fn frame_link(frame: PhysFrame) -> Option<*mut u64> {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return None;
+    }
+    Some((VirtAddr::new(offset) + frame.start_address().as_u64()).as_mut_ptr())
+}
+
+/// 使い終わったフレームを返す．`BootInfoFrameAllocator`が次に割り当てる
+///
+/// `init`の前に返されたフレームはつなげないので，使われないままになる
+///
+/// unsafe
+/// 呼び出し元は，フレームがもうどこからもマップされていないことを保証しなくてはならない
+pub unsafe fn deallocate_frame(frame: PhysFrame) {
+    let link = match frame_link(frame) {
+        Some(link) => link,
+        None => return,
+    };
+    let mut free = FREE_FRAMES.lock();
+    let next = free.head.map_or(0, |head| head.start_address().as_u64());
+    link.write(next);
+    free.head = Some(frame);
+    free.len += 1;
+}
+
+/// 返されてまだ使われていないフレームの数
+pub fn free_frame_count() -> usize {
+    FREE_FRAMES.lock().len
+}
+
+fn pop_free_frame() -> Option<PhysFrame> {
+    let mut free = FREE_FRAMES.lock();
+    let frame = free.head?;
+    let next = unsafe { frame_link(frame)?.read() };
+    // 0番地のフレームはLOW_MEMORY_ENDより下なので，リストの終わりと区別できる
+    free.head = match next {
+        0 => None,
+        next => Some(PhysFrame::containing_address(PhysAddr::new(next))),
+    };
+    free.len -= 1;
+    Some(frame)
+}
+
+/// これより下の物理メモリは，リアルモードから使えるように取っておく
+pub const LOW_MEMORY_END: u64 = 0x10_0000;
+
 /// ブートローダのメモリマップから使用可能なフレームを返す
 /// FrameAllocator
 pub struct BootInfoFrameAllocator {
@@ -66,7 +161,10 @@ impl BootInfoFrameAllocator {
         let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
 
         // フレームの開始アドレスのイテレータへと変換する
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
+        // 1MiB未満はAPの起動コードを置くのに使うので割り当てない
+        let frame_addresses = addr_ranges
+            .flat_map(|r| r.step_by(4096))
+            .filter(|&addr| addr >= LOW_MEMORY_END);
 
         // 開始アドレスから PhysFrame 型をつくる
         frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
@@ -74,7 +172,11 @@ impl BootInfoFrameAllocator {
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    /// 返されたフレームがあれば，それから使う
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        if let Some(frame) = pop_free_frame() {
+            return Some(frame);
+        }
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         frame
@@ -89,10 +191,61 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 /// また，&mut参照が複数の名称を持つことにつながるので，この関数は1度しか呼び出してはならない
 /// (mutable aliasingというらしい 動作が未定義)
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level4_table(physical_memory_offset);
     OffsetPageTable::new(level_4_table, physical_memory_offset)
 }
 
+/// 今のページテーブルで，`start`から`size`バイトがring3から読めるか確かめる
+///
+/// すべてのページが，どの段のテーブルでも`USER_ACCESSIBLE`でマップされていればtrue．
+/// `init`の前はfalse
+pub fn is_user_accessible(start: VirtAddr, size: u64) -> bool {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    if offset == 0 {
+        return false;
+    }
+    if size == 0 {
+        return true;
+    }
+    let last = match start.as_u64().checked_add(size - 1).map(VirtAddr::try_new) {
+        Some(Ok(last)) => last,
+        _ => return false,
+    };
+    let start_page: Page = Page::containing_address(start);
+    let end_page: Page = Page::containing_address(last);
+    Page::range_inclusive(start_page, end_page)
+        .all(|page| is_user_page(VirtAddr::new(offset), page.start_address()))
+}
+
+/// 有効なページテーブルを読むだけでたどる
+///
+/// `OffsetPageTable`を作ると有効なテーブルへの`&mut`が増えてしまうので，自分でたどる
+fn is_user_page(physical_memory_offset: VirtAddr, addr: VirtAddr) -> bool {
+    let (level_4_table_frame, _) = Cr3::read();
+    let indexes = [
+        addr.p4_index(),
+        addr.p3_index(),
+        addr.p2_index(),
+        addr.p1_index(),
+    ];
+    let mut table_addr = level_4_table_frame.start_address();
+
+    for (level, &index) in indexes.iter().enumerate() {
+        let table_ptr: *const PageTable = (physical_memory_offset + table_addr.as_u64()).as_ptr();
+        let entry = unsafe { &(&*table_ptr)[index] };
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE) {
+            return false;
+        }
+        if level == indexes.len() - 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            return true;
+        }
+        table_addr = entry.addr();
+    }
+    false
+}
+
 /// 有効なレベル4テーブルへの可変参照を返す
 ///
 /// この関数はunsafeであり，また1度しか呼び出してはならない
@@ -101,8 +254,6 @@ pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static>
 /// また，&mut参照が複数の名称を持つことにつながるので，この関数は1度しか呼び出してはならない
 /// (mutable aliasingというらしい 動作が未定義)
 unsafe fn active_level4_table(phisycal_memory_offset: VirtAddr) -> &'static mut PageTable {
-    use x86_64::registers::control::Cr3;
-
     let (level_4_page_table_frame, _) = Cr3::read();
 
     let phys = level_4_page_table_frame.start_address();
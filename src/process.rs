@@ -0,0 +1,308 @@
+pub mod elf;
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, page_table::PageTableEntry, FrameAllocator, Mapper, OffsetPageTable,
+        Page, PageTable, PageTableFlags, PhysFrame, Size4KiB, Translate,
+    },
+    VirtAddr,
+};
+
+use crate::{memory, syscall};
+
+/// ユーザプロセスが使える仮想アドレスの範囲
+///
+/// レベル4テーブルの128..136番目のエントリにあたる
+/// この範囲はプロセスごとのページテーブルにだけマップされる
+pub const USER_REGION_START: u64 = 0x0000_4000_0000_0000;
+pub const USER_REGION_END: u64 = 0x0000_4400_0000_0000;
+
+/// ユーザスタックの一番上
+///
+/// すぐ上の1ページはガードページとしてマップしない
+pub const USER_STACK_TOP: u64 = USER_REGION_END - 4096;
+pub const USER_STACK_SIZE: u64 = 4096 * 4;
+
+const USER_P4_INDEX_RANGE: core::ops::Range<usize> =
+    (USER_REGION_START >> 39) as usize..(USER_REGION_END >> 39) as usize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    /// カーネル自身を表すID
+    pub const KERNEL: Pid = Pid(0);
+
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+/// 実行中のプロセスのID
+static CURRENT_PID: AtomicU64 = AtomicU64::new(0);
+
+/// ユーザ領域の中のアドレスならtrue
+pub fn is_user_address(addr: VirtAddr) -> bool {
+    (USER_REGION_START..USER_REGION_END).contains(&addr.as_u64())
+}
+
+/// 現在ring3で実行中のプロセスのIDを返す
+///
+/// プロセスを実行していなければ`Pid::KERNEL`
+pub fn current_pid() -> Pid {
+    Pid(CURRENT_PID.load(Ordering::Relaxed))
+}
+
+/// プロセスが終了した理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// exitシステムコールで終了した
+    Exited(i64),
+    /// ユーザモードでの例外によって強制終了された
+    Killed(Fault),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// アクセスしようとしたアドレス
+    PageFault(VirtAddr),
+    /// エラーコード
+    GeneralProtection(u64),
+    InvalidOpcode,
+}
+
+#[derive(Debug)]
+pub enum ProcessError {
+    MapFailed(MapToError<Size4KiB>),
+    FrameAllocationFailed,
+    /// ユーザ領域の外のアドレスを指定した
+    OutsideUserRegion(VirtAddr),
+    /// マップされていないアドレスに書き込もうとした
+    NotMapped(VirtAddr),
+}
+
+impl From<MapToError<Size4KiB>> for ProcessError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        ProcessError::MapFailed(err)
+    }
+}
+
+/// ring3で動くプロセス
+///
+/// カーネルの部分を共有した，自分専用のレベル4テーブルを持つ
+pub struct Process {
+    pid: Pid,
+    page_table_frame: PhysFrame,
+    physical_memory_offset: VirtAddr,
+    entry: VirtAddr,
+    stack_top: VirtAddr,
+    exit_status: Option<ExitStatus>,
+}
+
+impl Process {
+    /// 新しいアドレス空間とユーザスタックを持つプロセスを作る
+    ///
+    /// カーネルの部分は，`kernel_mapper`のレベル4テーブルからエントリをコピーする
+    /// エントリポイントは`set_entry`で設定する
+    pub fn new(
+        kernel_mapper: &mut OffsetPageTable,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<Self, ProcessError> {
+        let physical_memory_offset = kernel_mapper.phys_offset();
+        let page_table_frame = frame_allocator
+            .allocate_frame()
+            .ok_or(ProcessError::FrameAllocationFailed)?;
+
+        let table = unsafe { &mut *table_ptr(physical_memory_offset, page_table_frame) };
+        table.zero();
+        // ユーザ領域以外はカーネルと同じテーブルを指す
+        // 下位のテーブルは共有なので，カーネル側でのマップの変更はプロセスからも見える
+        for (index, entry) in kernel_mapper.level_4_table().iter().enumerate() {
+            if !USER_P4_INDEX_RANGE.contains(&index) {
+                table[index] = entry.clone();
+            }
+        }
+
+        let mut process = Process {
+            pid: Pid::new(),
+            page_table_frame,
+            physical_memory_offset,
+            entry: VirtAddr::new(USER_REGION_START),
+            stack_top: VirtAddr::new(USER_STACK_TOP),
+            exit_status: None,
+        };
+        process.map_region(
+            VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE),
+            USER_STACK_SIZE,
+            PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE,
+            frame_allocator,
+        )?;
+
+        Ok(process)
+    }
+
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn exit_status(&self) -> Option<ExitStatus> {
+        self.exit_status
+    }
+
+    pub fn set_entry(&mut self, entry: VirtAddr) {
+        self.entry = entry;
+    }
+
+    /// ユーザスタックの初期値を変える
+    ///
+    /// 引数などをスタックに積んだあとに使う
+    pub fn set_stack_top(&mut self, stack_top: VirtAddr) {
+        self.stack_top = stack_top;
+    }
+
+    /// このプロセスのアドレス空間に，新しいフレームを使って領域をマップする
+    ///
+    /// フレームは0で埋められる
+    pub fn map_region(
+        &mut self,
+        start: VirtAddr,
+        size: u64,
+        flags: PageTableFlags,
+        frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    ) -> Result<(), ProcessError> {
+        check_user_range(start, size)?;
+
+        let flags = flags | PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        let start_page: Page = Page::containing_address(start);
+        let end_page: Page = Page::containing_address(start + size - 1u64);
+        let mut mapper = self.mapper();
+
+        for page in Page::range_inclusive(start_page, end_page) {
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(ProcessError::FrameAllocationFailed)?;
+            unsafe {
+                core::ptr::write_bytes(self.frame_ptr(frame), 0, 4096);
+                // このページテーブルはまだ有効になっていないので，flushは不要
+                mapper.map_to(page, frame, flags, frame_allocator)?.ignore();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// このプロセスのアドレス空間の`addr`に`data`を書き込む
+    ///
+    /// ページの書き込み権限に関係なく，物理メモリのマッピングを通して書き込む
+    pub fn copy_to_user(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), ProcessError> {
+        check_user_range(addr, data.len() as u64)?;
+
+        let mapper = self.mapper();
+        let mut written = 0;
+        while written < data.len() {
+            let dest = addr + written;
+            let phys = mapper
+                .translate_addr(dest)
+                .ok_or(ProcessError::NotMapped(dest))?;
+            // ページの境界をまたがないように書き込む
+            let chunk =
+                core::cmp::min(data.len() - written, 4096 - (dest.as_u64() % 4096) as usize);
+            let ptr: *mut u8 = (self.physical_memory_offset + phys.as_u64()).as_mut_ptr();
+            unsafe {
+                core::ptr::copy_nonoverlapping(data[written..].as_ptr(), ptr, chunk);
+            }
+            written += chunk;
+        }
+
+        Ok(())
+    }
+
+    /// ページテーブルを切り替えてring3でプロセスを実行する
+    ///
+    /// プロセスが終了するか，例外で強制終了されるまで戻らない
+    pub fn run(&mut self) -> ExitStatus {
+        let (kernel_table, flags) = Cr3::read();
+
+        CURRENT_PID.store(self.pid.0, Ordering::Relaxed);
+        let status = unsafe {
+            Cr3::write(self.page_table_frame, flags);
+            let status = syscall::enter_user_mode(self.entry, self.stack_top);
+            Cr3::write(kernel_table, flags);
+            status
+        };
+        CURRENT_PID.store(Pid::KERNEL.0, Ordering::Relaxed);
+
+        self.exit_status = Some(status);
+        status
+    }
+
+    fn mapper(&self) -> OffsetPageTable<'static> {
+        let table = unsafe { &mut *table_ptr(self.physical_memory_offset, self.page_table_frame) };
+        unsafe { OffsetPageTable::new(table, self.physical_memory_offset) }
+    }
+
+    fn frame_ptr(&self, frame: PhysFrame) -> *mut u8 {
+        (self.physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+    }
+}
+
+impl Drop for Process {
+    /// ユーザ領域に使ったフレームとページテーブルを返す
+    ///
+    /// カーネルの部分のテーブルは共有しているので触らない
+    fn drop(&mut self) {
+        let table = unsafe { &*table_ptr(self.physical_memory_offset, self.page_table_frame) };
+        for index in USER_P4_INDEX_RANGE {
+            free_entry(self.physical_memory_offset, &table[index], 3);
+        }
+        unsafe { memory::deallocate_frame(self.page_table_frame) };
+    }
+}
+
+/// `entry`が指すフレームと，その下のフレームをすべて返す
+///
+/// `level`は`entry`が指すテーブルの段．0ならデータのフレームを指す
+fn free_entry(physical_memory_offset: VirtAddr, entry: &PageTableEntry, level: usize) {
+    if !entry.flags().contains(PageTableFlags::PRESENT) {
+        return;
+    }
+    // ユーザ領域には4KiBのページしかマップしないので，ヒュージページは来ない
+    let frame = match entry.frame() {
+        Ok(frame) => frame,
+        Err(_) => return,
+    };
+    if level > 0 {
+        let table = unsafe { &*table_ptr(physical_memory_offset, frame) };
+        for child in table.iter() {
+            free_entry(physical_memory_offset, child, level - 1);
+        }
+    }
+    unsafe { memory::deallocate_frame(frame) };
+}
+
+fn table_ptr(physical_memory_offset: VirtAddr, frame: PhysFrame) -> *mut PageTable {
+    (physical_memory_offset + frame.start_address().as_u64()).as_mut_ptr()
+}
+
+fn check_user_range(start: VirtAddr, size: u64) -> Result<(), ProcessError> {
+    let end = start.as_u64().checked_add(size);
+    if start.as_u64() < USER_REGION_START || end.is_none_or(|end| end > USER_REGION_END) {
+        return Err(ProcessError::OutsideUserRegion(start));
+    }
+    Ok(())
+}
+
+/// ユーザモードで起きた例外から呼び出され，実行中のプロセスを強制終了する
+///
+/// 例外ハンドラのスタックは捨てられ，`Process::run`に戻る
+pub(crate) fn kill_current(fault: Fault) -> ! {
+    syscall::exit_user_mode(ExitStatus::Killed(fault))
+}
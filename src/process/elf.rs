@@ -0,0 +1,300 @@
+use alloc::vec::Vec;
+use core::convert::TryInto;
+
+use x86_64::{
+    structures::paging::{FrameAllocator, OffsetPageTable, PageTableFlags, Size4KiB},
+    VirtAddr,
+};
+
+use super::{
+    Process, ProcessError, USER_REGION_END, USER_REGION_START, USER_STACK_SIZE, USER_STACK_TOP,
+};
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EV_CURRENT: u8 = 1;
+const ET_EXEC: u16 = 2;
+const EM_X86_64: u16 = 0x3e;
+
+const ELF_HEADER_SIZE: usize = 64;
+const PROGRAM_HEADER_SIZE: usize = 56;
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const PAGE_SIZE: u64 = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    /// ヘッダを読むのに必要な長さがない
+    TooShort,
+    BadMagic,
+    /// 64ビット，リトルエンディアン，バージョン1以外
+    UnsupportedFormat,
+    /// 静的リンクされた実行ファイルではない
+    NotExecutable,
+    /// x86_64用ではない
+    WrongMachine,
+    /// プログラムヘッダがファイルに収まっていない
+    BadProgramHeader,
+    /// セグメントの中身がファイルに収まっていないか，filesz > memszか，ユーザ領域の外にある
+    BadSegment,
+    /// 2つのPT_LOADセグメントが同じページにまたがっている
+    OverlappingSegments,
+}
+
+#[derive(Debug)]
+pub enum LoadError {
+    Elf(ElfError),
+    Process(ProcessError),
+    /// 引数がユーザスタックに収まらない
+    ArgumentsTooLong,
+}
+
+impl From<ElfError> for LoadError {
+    fn from(err: ElfError) -> Self {
+        LoadError::Elf(err)
+    }
+}
+
+impl From<ProcessError> for LoadError {
+    fn from(err: ProcessError) -> Self {
+        LoadError::Process(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProgramHeader {
+    pub p_type: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+impl ProgramHeader {
+    /// セグメントのフラグをページテーブルのフラグに変換する
+    fn page_table_flags(&self) -> PageTableFlags {
+        let mut flags = PageTableFlags::empty();
+        if self.flags & PF_W != 0 {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if self.flags & PF_X == 0 {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+        flags
+    }
+}
+
+/// 検証済みのELF64ファイル
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    entry: u64,
+    phoff: usize,
+    phnum: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    /// ヘッダを検証する
+    ///
+    /// プログラムヘッダもすべて読んで，ファイルに収まっていることを確かめる
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < ELF_HEADER_SIZE {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || data[6] != EV_CURRENT {
+            return Err(ElfError::UnsupportedFormat);
+        }
+        if read_u16(data, 16) != ET_EXEC {
+            return Err(ElfError::NotExecutable);
+        }
+        if read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::WrongMachine);
+        }
+
+        let entry = read_u64(data, 24);
+        let phoff = read_u64(data, 32) as usize;
+        let phentsize = usize::from(read_u16(data, 54));
+        let phnum = usize::from(read_u16(data, 56));
+        if phentsize != PROGRAM_HEADER_SIZE {
+            return Err(ElfError::BadProgramHeader);
+        }
+        let phend = phnum
+            .checked_mul(PROGRAM_HEADER_SIZE)
+            .and_then(|size| size.checked_add(phoff))
+            .ok_or(ElfError::BadProgramHeader)?;
+        if phend > data.len() {
+            return Err(ElfError::BadProgramHeader);
+        }
+
+        let elf = ElfFile {
+            data,
+            entry,
+            phoff,
+            phnum,
+        };
+        for header in elf.program_headers().filter(|h| h.p_type == PT_LOAD) {
+            let in_file = header
+                .offset
+                .checked_add(header.filesz)
+                .is_some_and(|end| end <= data.len() as u64);
+            // ここで範囲を確かめておけば，loadでページ境界に切り上げても溢れない
+            let in_user_region = header.vaddr >= USER_REGION_START
+                && header
+                    .vaddr
+                    .checked_add(header.memsz)
+                    .is_some_and(|end| end <= USER_REGION_END);
+            if header.filesz > header.memsz || !in_file || !in_user_region {
+                return Err(ElfError::BadSegment);
+            }
+        }
+
+        Ok(elf)
+    }
+
+    pub fn entry(&self) -> VirtAddr {
+        VirtAddr::new_truncate(self.entry)
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.phnum).map(move |i| {
+            let base = self.phoff + i * PROGRAM_HEADER_SIZE;
+            ProgramHeader {
+                p_type: read_u32(self.data, base),
+                flags: read_u32(self.data, base + 4),
+                offset: read_u64(self.data, base + 8),
+                vaddr: read_u64(self.data, base + 16),
+                filesz: read_u64(self.data, base + 32),
+                memsz: read_u64(self.data, base + 40),
+            }
+        })
+    }
+
+    /// セグメントのうち，ファイルに含まれている部分
+    fn segment_data(&self, header: &ProgramHeader) -> &'a [u8] {
+        let start = header.offset as usize;
+        &self.data[start..start + header.filesz as usize]
+    }
+}
+
+/// ELFイメージを読み込んだ新しいプロセスを作る
+///
+/// 返されたプロセスを`Process::run`すると実行が始まる
+pub fn spawn(
+    kernel_mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    image: &[u8],
+    args: &[&str],
+) -> Result<Process, LoadError> {
+    let elf = ElfFile::parse(image)?;
+    let mut process = Process::new(kernel_mapper, frame_allocator)?;
+    load(&mut process, &elf, frame_allocator)?;
+    let stack_top = push_args(&mut process, args)?;
+
+    process.set_entry(elf.entry());
+    process.set_stack_top(stack_top);
+    Ok(process)
+}
+
+/// PT_LOADセグメントをプロセスのアドレス空間にマップする
+///
+/// memsz > fileszの部分(.bss)は0で埋められる
+pub fn load(
+    process: &mut Process,
+    elf: &ElfFile,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), LoadError> {
+    let mut loaded_pages: Vec<(u64, u64)> = Vec::new();
+
+    for header in elf.program_headers().filter(|h| h.p_type == PT_LOAD) {
+        if header.memsz == 0 {
+            continue;
+        }
+
+        let page_start = header.vaddr & !(PAGE_SIZE - 1);
+        let page_end = align_up(header.vaddr + header.memsz, PAGE_SIZE);
+        if loaded_pages
+            .iter()
+            .any(|&(start, end)| page_start < end && start < page_end)
+        {
+            return Err(ElfError::OverlappingSegments.into());
+        }
+        loaded_pages.push((page_start, page_end));
+
+        process.map_region(
+            VirtAddr::try_new(page_start).map_err(|_| ElfError::BadSegment)?,
+            page_end - page_start,
+            header.page_table_flags(),
+            frame_allocator,
+        )?;
+        process.copy_to_user(VirtAddr::new(header.vaddr), elf.segment_data(&header))?;
+    }
+
+    Ok(())
+}
+
+/// System V ABIの形で引数をユーザスタックに積み，新しいスタックの一番上を返す
+///
+/// rsp -> argc, argv[0..argc], NULL, envp: NULL, auxv: AT_NULL
+fn push_args(process: &mut Process, args: &[&str]) -> Result<VirtAddr, LoadError> {
+    let stack_bottom = USER_STACK_TOP - USER_STACK_SIZE;
+    let mut sp = USER_STACK_TOP;
+
+    // 文字列を先に積む
+    let mut argv = Vec::with_capacity(args.len());
+    for arg in args {
+        let len = arg.len() as u64 + 1;
+        sp = sp
+            .checked_sub(len)
+            .filter(|&sp| sp >= stack_bottom)
+            .ok_or(LoadError::ArgumentsTooLong)?;
+        process.copy_to_user(VirtAddr::new(sp), arg.as_bytes())?;
+        process.copy_to_user(VirtAddr::new(sp + arg.len() as u64), &[0])?;
+        argv.push(sp);
+    }
+
+    let mut words: Vec<u64> = Vec::with_capacity(args.len() + 5);
+    words.push(args.len() as u64);
+    words.extend_from_slice(&argv);
+    // argvの終端，envpの終端，auxvのAT_NULL
+    words.extend_from_slice(&[0, 0, 0, 0]);
+
+    // エントリポイントではrspが16バイト境界に揃っている必要がある
+    let size = words.len() as u64 * 8;
+    sp = (sp & !0xf)
+        .checked_sub(align_up(size, 16))
+        .filter(|&sp| sp >= stack_bottom)
+        .ok_or(LoadError::ArgumentsTooLong)?;
+
+    let mut bytes = Vec::with_capacity(size as usize);
+    for word in words {
+        bytes.extend_from_slice(&word.to_le_bytes());
+    }
+    process.copy_to_user(VirtAddr::new(sp), &bytes)?;
+
+    Ok(VirtAddr::new(sp))
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(data[offset..offset + 2].try_into().unwrap())
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
@@ -0,0 +1,510 @@
+use spin::Mutex;
+use x86_64::instructions::{
+    interrupts,
+    port::{Port, PortReadOnly, PortWriteOnly},
+};
+
+use crate::interrupts::IrqError;
+
+const DATA_PORT: u16 = 0x60;
+/// 読むとステータス，書くとコントローラへのコマンド
+const STATUS_PORT: u16 = 0x64;
+
+/// ステータスレジスタ: 読めるデータがある
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// ステータスレジスタ: まだコントローラが前の書き込みを受け取っていない
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+/// ステータスレジスタ: 読めるデータは2つ目のポートから来た
+const STATUS_SECOND_PORT: u8 = 1 << 5;
+
+// コントローラへのコマンド
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_SECOND_PORT: u8 = 0xa7;
+const ENABLE_SECOND_PORT: u8 = 0xa8;
+const TEST_SECOND_PORT: u8 = 0xa9;
+const SELF_TEST: u8 = 0xaa;
+const TEST_FIRST_PORT: u8 = 0xab;
+const DISABLE_FIRST_PORT: u8 = 0xad;
+const ENABLE_FIRST_PORT: u8 = 0xae;
+/// 次に書くデータを2つ目のポートに送る
+const WRITE_SECOND_PORT: u8 = 0xd4;
+const PULSE_RESET: u8 = 0xfe;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+// 設定バイト
+const CONFIG_FIRST_IRQ: u8 = 1 << 0;
+const CONFIG_SECOND_IRQ: u8 = 1 << 1;
+const CONFIG_FIRST_CLOCK_DISABLED: u8 = 1 << 4;
+const CONFIG_SECOND_CLOCK_DISABLED: u8 = 1 << 5;
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+// キーボードへのコマンド
+const SET_LEDS: u8 = 0xed;
+const SET_TYPEMATIC: u8 = 0xf3;
+/// 引数が0なら今のセットを返し，1〜3ならそのセットに切り替える
+const SCANCODE_SET: u8 = 0xf0;
+/// マウスではデータの報告を始める
+const ENABLE_SCANNING: u8 = 0xf4;
+
+// マウスへのコマンド
+const GET_DEVICE_ID: u8 = 0xf2;
+const SET_SAMPLE_RATE: u8 = 0xf3;
+const SET_DEFAULTS: u8 = 0xf6;
+/// ホイール付きのIntelliMouseのデバイスID
+const INTELLIMOUSE_ID: u8 = 3;
+
+const ACK: u8 = 0xfa;
+const RESEND: u8 = 0xfe;
+
+/// ステータスを見に行く回数の上限
+const TIMEOUT_SPINS: usize = 100_000;
+/// RESENDが返ってきたときに送り直す回数
+const MAX_RESENDS: usize = 3;
+/// 1つのコマンドのACKを待つ間に，他へ回すデータの数の上限
+///
+/// キーを押し続けられても，待ち続けないようにする
+const MAX_FORWARDED_BYTES: usize = 32;
+
+pub const LED_SCROLL_LOCK: u8 = 1 << 0;
+pub const LED_NUM_LOCK: u8 = 1 << 1;
+pub const LED_CAPS_LOCK: u8 = 1 << 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// コントローラかデバイスが応答しない
+    Timeout,
+    /// コントローラのセルフテストの結果
+    SelfTestFailed(u8),
+    /// ポートのテストの結果．(ポート番号, 結果)
+    PortTestFailed(u8, u8),
+    /// 何度送り直してもRESENDが返ってくる
+    TooManyResends,
+    /// ACKを待つ間に関係のないデータが届き続けた．最後に届いたもの
+    UnexpectedResponse(u8),
+    NotInitialized,
+    /// マウス用のポートがない
+    NoSecondPort,
+    /// 割り込みハンドラを登録できない
+    Irq(IrqError),
+    /// マウスのストリームはもう作られている
+    StreamAlreadyCreated,
+}
+
+/// コマンドの送り先
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Device {
+    Keyboard,
+    Mouse,
+}
+
+/// キーを押し続けたときに，リピートが始まるまでの時間
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RepeatDelay {
+    Ms250 = 0,
+    Ms500 = 1,
+    Ms750 = 2,
+    Ms1000 = 3,
+}
+
+/// キーリピートの設定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Typematic {
+    pub delay: RepeatDelay,
+    /// 0が最速(30回/秒)，31が最遅(2回/秒)
+    pub rate: u8,
+}
+
+impl Typematic {
+    fn as_byte(self) -> u8 {
+        ((self.delay as u8) << 5) | (self.rate & 0x1f)
+    }
+}
+
+impl Default for Typematic {
+    /// キーボードの電源を入れたときと同じ，500ms後に約10.9回/秒
+    fn default() -> Self {
+        Self {
+            delay: RepeatDelay::Ms500,
+            rate: 0x0b,
+        }
+    }
+}
+
+/// CPUが受け取るスキャンコードのセット
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    Set1,
+    Set2,
+}
+
+/// 初期化してわかったこと
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ControllerInfo {
+    /// 2つ目のポート(マウス)があるか
+    pub dual_port: bool,
+    /// コントローラがスキャンコードをセット1に変換しているか
+    pub translation: bool,
+    /// 変換した後のセット
+    pub scancode_set: ScancodeSet,
+}
+
+/// マウスを初期化してわかったこと
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseInfo {
+    /// ホイールがあれば4バイト，なければ3バイトのパケットが来る
+    pub has_wheel: bool,
+}
+
+impl MouseInfo {
+    pub fn packet_size(&self) -> usize {
+        if self.has_wheel {
+            4
+        } else {
+            3
+        }
+    }
+}
+
+/// i8042 PS/2コントローラ
+///
+/// 1つ目のポートにキーボードがつながっている前提で初期化する
+pub struct Controller {
+    data: Port<u8>,
+    status: PortReadOnly<u8>,
+    command: PortWriteOnly<u8>,
+    info: Option<ControllerInfo>,
+    mouse: Option<MouseInfo>,
+}
+
+pub static CONTROLLER: Mutex<Controller> = Mutex::new(Controller::new());
+
+impl Controller {
+    const fn new() -> Self {
+        Self {
+            data: Port::new(DATA_PORT),
+            status: PortReadOnly::new(STATUS_PORT),
+            command: PortWriteOnly::new(STATUS_PORT),
+            info: None,
+            mouse: None,
+        }
+    }
+
+    pub fn info(&self) -> Option<ControllerInfo> {
+        self.info
+    }
+
+    fn read_status(&mut self) -> u8 {
+        unsafe { self.status.read() }
+    }
+
+    fn wait_input_empty(&mut self) -> Result<(), Ps2Error> {
+        for _ in 0..TIMEOUT_SPINS {
+            if self.read_status() & STATUS_INPUT_FULL == 0 {
+                return Ok(());
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn read_data(&mut self) -> Result<u8, Ps2Error> {
+        for _ in 0..TIMEOUT_SPINS {
+            if self.read_status() & STATUS_OUTPUT_FULL != 0 {
+                return Ok(unsafe { self.data.read() });
+            }
+            core::hint::spin_loop();
+        }
+        Err(Ps2Error::Timeout)
+    }
+
+    fn write_data(&mut self, byte: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.data.write(byte) };
+        Ok(())
+    }
+
+    fn write_command(&mut self, command: u8) -> Result<(), Ps2Error> {
+        self.wait_input_empty()?;
+        unsafe { self.command.write(command) };
+        Ok(())
+    }
+
+    /// 読み残しを捨てる
+    fn flush(&mut self) {
+        while self.read_status() & STATUS_OUTPUT_FULL != 0 {
+            unsafe { self.data.read() };
+        }
+    }
+
+    fn read_config(&mut self) -> Result<u8, Ps2Error> {
+        self.write_command(READ_CONFIG)?;
+        self.read_data()
+    }
+
+    fn write_config(&mut self, config: u8) -> Result<(), Ps2Error> {
+        self.write_command(WRITE_CONFIG)?;
+        self.write_data(config)
+    }
+
+    /// デバイスにコマンドを送り，ACKを待つ
+    ///
+    /// RESENDが返ってきたら送り直す．
+    /// 初期化後は，待っている間に来たスキャンコードやマウスのパケットをそれぞれのキューに回す．
+    /// 回したデータが送り直しも含めて`MAX_FORWARDED_BYTES`を超えたら諦める
+    fn send_command(&mut self, device: Device, byte: u8) -> Result<(), Ps2Error> {
+        let mut forwarded = 0;
+        for _ in 0..=MAX_RESENDS {
+            if device == Device::Mouse {
+                self.write_command(WRITE_SECOND_PORT)?;
+            }
+            self.write_data(byte)?;
+            loop {
+                let status = self.read_status();
+                let response = self.read_data()?;
+                match response {
+                    ACK => return Ok(()),
+                    RESEND => break,
+                    _ => {
+                        self.forward(status, response);
+                        forwarded += 1;
+                        if forwarded > MAX_FORWARDED_BYTES {
+                            return Err(Ps2Error::UnexpectedResponse(response));
+                        }
+                    }
+                }
+            }
+        }
+        Err(Ps2Error::TooManyResends)
+    }
+
+    /// コマンドと引数をデバイスに送る
+    fn send_command_with(
+        &mut self,
+        device: Device,
+        command: u8,
+        argument: u8,
+    ) -> Result<(), Ps2Error> {
+        self.send_command(device, command)?;
+        self.send_command(device, argument)
+    }
+
+    /// 応答を待っている間に届いた，関係のないデータを渡す
+    ///
+    /// 初期化中のデバイスから届いたものは捨てる
+    fn forward(&self, status: u8, byte: u8) {
+        if status & STATUS_SECOND_PORT != 0 {
+            if self.mouse.is_some() {
+                crate::task::mouse::add_byte(byte);
+            }
+        } else if self.info.is_some() {
+            crate::task::keyboard::add_scancode(byte);
+        }
+    }
+
+    /// 変換しないときに，キーボードが送ってくるセットを調べる
+    ///
+    /// セット2以外なら，セット2に切り替える．
+    /// USBキーボードをPS/2に見せかけているファームウェアには問い合わせに答えないものがあるので，
+    /// 問い合わせに失敗しても電源を入れたときのセット2とみなす
+    fn detect_scancode_set(&mut self) -> Result<ScancodeSet, Ps2Error> {
+        let current = self
+            .send_command_with(Device::Keyboard, SCANCODE_SET, 0)
+            .and_then(|_| self.read_data());
+        match current {
+            Ok(1) => Ok(ScancodeSet::Set1),
+            Ok(2) => Ok(ScancodeSet::Set2),
+            Err(_) => {
+                // 遅れて届いた応答がスキャンコードとして読まれないように捨てる
+                self.flush();
+                Ok(ScancodeSet::Set2)
+            }
+            Ok(_) => {
+                self.send_command_with(Device::Keyboard, SCANCODE_SET, 2)?;
+                Ok(ScancodeSet::Set2)
+            }
+        }
+    }
+
+    /// `translation`が`None`なら，変換はファームウェアの設定のままにする
+    fn init(&mut self, translation: Option<bool>) -> Result<ControllerInfo, Ps2Error> {
+        self.info = None;
+
+        // 設定している間にデバイスからデータが来ないようにする
+        self.write_command(DISABLE_FIRST_PORT)?;
+        self.write_command(DISABLE_SECOND_PORT)?;
+        self.flush();
+
+        let mut config = self.read_config()?;
+        config &= !(CONFIG_FIRST_IRQ | CONFIG_SECOND_IRQ);
+        match translation {
+            Some(true) => config |= CONFIG_TRANSLATION,
+            Some(false) => config &= !CONFIG_TRANSLATION,
+            None => {}
+        }
+        self.write_config(config)?;
+
+        self.write_command(SELF_TEST)?;
+        match self.read_data()? {
+            SELF_TEST_PASSED => {}
+            result => return Err(Ps2Error::SelfTestFailed(result)),
+        }
+        // セルフテストで設定が戻ってしまうコントローラがある
+        self.write_config(config)?;
+
+        // 2つ目のポートを有効にしてクロックが動けば，2ポートのコントローラ
+        let dual_port = config & CONFIG_SECOND_CLOCK_DISABLED != 0 && {
+            self.write_command(ENABLE_SECOND_PORT)?;
+            let enabled = self.read_config()? & CONFIG_SECOND_CLOCK_DISABLED == 0;
+            self.write_command(DISABLE_SECOND_PORT)?;
+            enabled
+        };
+
+        self.write_command(TEST_FIRST_PORT)?;
+        match self.read_data()? {
+            PORT_TEST_PASSED => {}
+            result => return Err(Ps2Error::PortTestFailed(1, result)),
+        }
+        if dual_port {
+            self.write_command(TEST_SECOND_PORT)?;
+            match self.read_data()? {
+                PORT_TEST_PASSED => {}
+                result => return Err(Ps2Error::PortTestFailed(2, result)),
+            }
+        }
+
+        self.write_command(ENABLE_FIRST_PORT)?;
+        // キーが押されて応答に混ざらないように，スキャンを始める前に調べる
+        let translation = config & CONFIG_TRANSLATION != 0;
+        let scancode_set = if translation {
+            // キーボードが何を送っても，セット1に変換される
+            ScancodeSet::Set1
+        } else {
+            self.detect_scancode_set()?
+        };
+        self.send_command_with(
+            Device::Keyboard,
+            SET_TYPEMATIC,
+            Typematic::default().as_byte(),
+        )?;
+        self.send_command(Device::Keyboard, ENABLE_SCANNING)?;
+
+        let config = self.read_config()?;
+        let info = ControllerInfo {
+            dual_port,
+            translation,
+            scancode_set,
+        };
+        self.write_config((config | CONFIG_FIRST_IRQ) & !CONFIG_FIRST_CLOCK_DISABLED)?;
+        self.info = Some(info);
+        Ok(info)
+    }
+
+    fn init_mouse(&mut self) -> Result<MouseInfo, Ps2Error> {
+        let info = self.info.ok_or(Ps2Error::NotInitialized)?;
+        if !info.dual_port {
+            return Err(Ps2Error::NoSecondPort);
+        }
+        self.mouse = None;
+
+        self.write_command(ENABLE_SECOND_PORT)?;
+        self.send_command(Device::Mouse, SET_DEFAULTS)?;
+        // サンプルレートを200，100，80の順に設定すると，ホイールのあるマウスは4バイトのパケットに切り替わる
+        for &rate in &[200, 100, 80] {
+            self.send_command_with(Device::Mouse, SET_SAMPLE_RATE, rate)?;
+        }
+        self.send_command(Device::Mouse, GET_DEVICE_ID)?;
+        let has_wheel = self.read_data()? == INTELLIMOUSE_ID;
+        self.send_command_with(Device::Mouse, SET_SAMPLE_RATE, 100)?;
+        self.send_command(Device::Mouse, ENABLE_SCANNING)?;
+
+        let config = self.read_config()?;
+        self.write_config((config | CONFIG_SECOND_IRQ) & !CONFIG_SECOND_CLOCK_DISABLED)?;
+        let mouse = MouseInfo { has_wheel };
+        self.mouse = Some(mouse);
+        Ok(mouse)
+    }
+
+    fn ensure_initialized(&self) -> Result<(), Ps2Error> {
+        self.info.map(|_| ()).ok_or(Ps2Error::NotInitialized)
+    }
+}
+
+/// コントローラとキーボードを初期化し，キーボードの割り込みを有効にする
+///
+/// 割り込みを有効にする前に呼ぶ
+pub fn init() -> Result<ControllerInfo, Ps2Error> {
+    interrupts::without_interrupts(|| CONTROLLER.lock().init(None))
+}
+
+/// スキャンコードをセット1に変換するかを決めて，初期化し直す
+///
+/// 変換しないとキーボードのセットを調べるので，その処理を試すのに使う
+pub fn init_with_translation(translation: bool) -> Result<ControllerInfo, Ps2Error> {
+    interrupts::without_interrupts(|| CONTROLLER.lock().init(Some(translation)))
+}
+
+/// 2つ目のポートのマウスを初期化し，パケットを送らせる
+///
+/// 割り込みの設定は呼び出し元が行う
+pub fn init_mouse() -> Result<MouseInfo, Ps2Error> {
+    interrupts::without_interrupts(|| CONTROLLER.lock().init_mouse())
+}
+
+/// Caps/Num/Scroll LockのLEDを設定する．`LED_*`の組み合わせ
+pub fn set_leds(leds: u8) -> Result<(), Ps2Error> {
+    // ACKを割り込みハンドラに読まれないように，割り込みを止めて待つ
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        controller.ensure_initialized()?;
+        controller.send_command_with(Device::Keyboard, SET_LEDS, leds & 0x07)
+    })
+}
+
+/// キーリピートの速さを設定する
+pub fn set_typematic(typematic: Typematic) -> Result<(), Ps2Error> {
+    interrupts::without_interrupts(|| {
+        let mut controller = CONTROLLER.lock();
+        controller.ensure_initialized()?;
+        controller.send_command_with(Device::Keyboard, SET_TYPEMATIC, typematic.as_byte())
+    })
+}
+
+/// 初期化の結果．初期化していないか失敗していれば`None`
+pub fn info() -> Option<ControllerInfo> {
+    interrupts::without_interrupts(|| CONTROLLER.lock().info())
+}
+
+/// キーボードから届くスキャンコードのセット
+///
+/// 初期化していなければ，BIOSが普通に設定するセット1とみなす
+pub fn scancode_set() -> ScancodeSet {
+    info().map_or(ScancodeSet::Set1, |info| info.scancode_set)
+}
+
+/// キーボードとマウスの割り込みハンドラから呼ばれる
+///
+/// ロックを取らない．コマンドの応答待ちで先に読まれていれば`None`
+pub(crate) fn read_irq_data() -> Option<u8> {
+    let mut status: PortReadOnly<u8> = PortReadOnly::new(STATUS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        if status.read() & STATUS_OUTPUT_FULL == 0 {
+            return None;
+        }
+        Some(data.read())
+    }
+}
+
+/// CPUのリセット線を叩く
+pub fn pulse_reset_line() {
+    let mut status: PortReadOnly<u8> = PortReadOnly::new(STATUS_PORT);
+    let mut command: PortWriteOnly<u8> = PortWriteOnly::new(STATUS_PORT);
+    unsafe {
+        while status.read() & STATUS_INPUT_FULL != 0 {}
+        command.write(PULSE_RESET);
+    }
+}
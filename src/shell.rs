@@ -0,0 +1,199 @@
+pub mod commands;
+
+use alloc::{
+    collections::BTreeMap,
+    string::{String, ToString},
+    vec::Vec,
+};
+use core::fmt;
+use pc_keyboard::KeyCode;
+
+use crate::println;
+use crate::task::keyboard::hotkey::{self, Action, KeyCombo};
+use crate::task::{keyboard::line_editor::Readline, monitor::TaskMonitor};
+
+pub const PROMPT: &str = "> ";
+
+/// シェルを起動したときに割り当てるショートカット
+const DEFAULT_SHORTCUTS: &[(KeyCode, &str)] = &[
+    (KeyCode::F1, "help"),
+    (KeyCode::F2, "tasks"),
+    (KeyCode::F3, "meminfo"),
+];
+
+/// コマンドの本体．引数にはコマンド名を含まない
+pub type CommandFn = fn(&Context<'_>, &[String]) -> Result<(), CommandError>;
+
+#[derive(Clone, Copy)]
+pub struct Command {
+    pub name: &'static str,
+    /// `help`に表示する書式
+    pub usage: &'static str,
+    pub description: &'static str,
+    pub run: CommandFn,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandError {
+    /// 閉じていない`"`がある
+    UnterminatedQuote,
+    UnknownCommand(String),
+    /// 引数の数が合わない．正しい書式を持つ
+    Usage(&'static str),
+    InvalidArgument(String),
+}
+
+impl fmt::Display for CommandError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandError::UnterminatedQuote => write!(f, "unterminated quote"),
+            CommandError::UnknownCommand(name) => {
+                write!(f, "unknown command: {} (try `help`)", name)
+            }
+            CommandError::Usage(usage) => write!(f, "usage: {}", usage),
+            CommandError::InvalidArgument(argument) => write!(f, "invalid argument: {}", argument),
+        }
+    }
+}
+
+/// コマンドから参照できるもの
+pub struct Context<'a> {
+    pub registry: &'a CommandRegistry,
+    pub monitor: &'a TaskMonitor,
+}
+
+/// 名前からコマンドを引く表
+#[derive(Default)]
+pub struct CommandRegistry {
+    commands: BTreeMap<&'static str, Command>,
+}
+
+impl CommandRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 組み込みコマンドを登録した表を作る
+    pub fn with_builtins() -> Self {
+        let mut registry = Self::new();
+        for command in commands::BUILTINS {
+            registry.register(*command);
+        }
+        registry
+    }
+
+    /// 同じ名前のコマンドがあれば置き換えて，前のものを返す
+    pub fn register(&mut self, command: Command) -> Option<Command> {
+        self.commands.insert(command.name, command)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Command> {
+        self.commands.get(name)
+    }
+
+    /// 名前の順に返す
+    pub fn iter(&self) -> impl Iterator<Item = &Command> {
+        self.commands.values()
+    }
+}
+
+/// 行を空白で区切る
+///
+/// `"`で囲んだ部分は空白を含めて1つの引数になる
+pub fn parse(line: &str) -> Result<Vec<String>, CommandError> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    // 区切りの途中でも，`""`のように空の引数があり得る
+    let mut in_word = false;
+    let mut in_quote = false;
+
+    for character in line.chars() {
+        match character {
+            '"' => {
+                in_quote = !in_quote;
+                in_word = true;
+            }
+            c if c.is_whitespace() && !in_quote => {
+                if in_word {
+                    words.push(core::mem::take(&mut word));
+                    in_word = false;
+                }
+            }
+            c => {
+                word.push(c);
+                in_word = true;
+            }
+        }
+    }
+    if in_quote {
+        return Err(CommandError::UnterminatedQuote);
+    }
+    if in_word {
+        words.push(word);
+    }
+    Ok(words)
+}
+
+pub struct Shell {
+    registry: CommandRegistry,
+    monitor: TaskMonitor,
+}
+
+impl Shell {
+    pub fn new(monitor: TaskMonitor) -> Self {
+        Self {
+            registry: CommandRegistry::with_builtins(),
+            monitor,
+        }
+    }
+
+    pub fn registry(&self) -> &CommandRegistry {
+        &self.registry
+    }
+
+    /// コマンドを追加，置き換える
+    pub fn registry_mut(&mut self) -> &mut CommandRegistry {
+        &mut self.registry
+    }
+
+    /// 1行分のコマンドを実行する．空行は何もしない
+    pub fn execute(&self, line: &str) -> Result<(), CommandError> {
+        let words = parse(line)?;
+        let (name, args) = match words.split_first() {
+            Some(split) => split,
+            None => return Ok(()),
+        };
+        let command = self
+            .registry
+            .get(name)
+            .ok_or_else(|| CommandError::UnknownCommand(name.to_string()))?;
+        let context = Context {
+            registry: &self.registry,
+            monitor: &self.monitor,
+        };
+        (command.run)(&context, args)
+    }
+
+    /// キーボードから読んだ行を実行し続ける
+    pub async fn run(self) {
+        let mut readline = match Readline::new() {
+            Ok(readline) => readline,
+            Err(err) => {
+                println!("shell: cannot subscribe to the keyboard: {:?}", err);
+                return;
+            }
+        };
+        for &(code, line) in DEFAULT_SHORTCUTS {
+            let action = Action::Command(line.to_string());
+            if let Err(err) = hotkey::bind(KeyCombo::code(code), action) {
+                println!("shell: cannot bind {:?}: {:?}", code, err);
+            }
+        }
+        println!("\ntype `help` to list commands (F1: help)");
+        while let Some(line) = readline.read_line(PROMPT).await {
+            if let Err(err) = self.execute(&line) {
+                println!("{}", err);
+            }
+        }
+    }
+}
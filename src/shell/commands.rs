@@ -0,0 +1,202 @@
+use alloc::{
+    format,
+    string::{String, ToString},
+};
+use pc_keyboard::KeyCode;
+
+use super::{Command, CommandError, Context};
+use crate::task::keyboard::hotkey::{self, Action, KeyCombo};
+use crate::task::{keyboard::introduction, timer};
+use crate::{allocator, print, println, vga_buffer};
+
+/// `CommandRegistry::with_builtins`が登録するコマンド
+pub const BUILTINS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help [command]",
+        description: "list commands or show how to use one",
+        run: help,
+    },
+    Command {
+        name: "clear",
+        usage: "clear",
+        description: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        description: "show heap usage",
+        run: meminfo,
+    },
+    Command {
+        name: "tasks",
+        usage: "tasks",
+        description: "list running and recently finished tasks",
+        run: tasks,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime",
+        description: "show time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        description: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "bind",
+        usage: "bind <F1-F11> [command]",
+        description: "run a command with a function key, or remove the binding",
+        run: bind,
+    },
+    Command {
+        name: "profile",
+        usage: "profile [section]",
+        description: "show a section of the profile card",
+        run: profile,
+    },
+];
+
+fn help(context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            for command in context.registry.iter() {
+                println!("  {:<20}{}", command.usage, command.description);
+            }
+            Ok(())
+        }
+        [name] => {
+            let command = context
+                .registry
+                .get(name)
+                .ok_or_else(|| CommandError::UnknownCommand(name.clone()))?;
+            println!("usage: {}\n  {}", command.usage, command.description);
+            Ok(())
+        }
+        _ => Err(CommandError::Usage("help [command]")),
+    }
+}
+
+fn clear(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    no_args(args, "clear")?;
+    vga_buffer::clear_screen();
+    Ok(())
+}
+
+fn meminfo(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    no_args(args, "meminfo")?;
+    let stats = allocator::heap_stats();
+    println!(
+        "heap: {} / {} bytes used, {} free ({} cached in block lists)",
+        stats.used,
+        stats.size,
+        stats.free(),
+        stats.cached
+    );
+    Ok(())
+}
+
+fn tasks(context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    no_args(args, "tasks")?;
+    println!(
+        "{:>4} {:<8} {:<8} {:>8}  name",
+        "id", "state", "priority", "polls"
+    );
+    for info in context.monitor.tasks() {
+        println!(
+            "{:>4} {:<8} {:<8} {:>8}  {}",
+            info.id,
+            format!("{:?}", info.state),
+            format!("{:?}", info.priority),
+            info.poll_count,
+            info.name
+        );
+    }
+    Ok(())
+}
+
+fn uptime(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    no_args(args, "uptime")?;
+    let uptime = timer::uptime();
+    println!("up {}.{:03} s", uptime.as_secs(), uptime.subsec_millis());
+    Ok(())
+}
+
+fn reboot(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    no_args(args, "reboot")?;
+    println!("rebooting...");
+    crate::reboot();
+}
+
+fn profile(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    match args {
+        [] => {
+            print!("sections:");
+            for (name, _) in introduction::SECTIONS {
+                print!(" {}", name);
+            }
+            println!();
+            Ok(())
+        }
+        [section] => {
+            if introduction::show_section(section) {
+                println!();
+                Ok(())
+            } else {
+                Err(CommandError::InvalidArgument(section.to_string()))
+            }
+        }
+        _ => Err(CommandError::Usage("profile [section]")),
+    }
+}
+
+/// F12はレイアウトの切り替えに使っているので割り当てられない
+const FUNCTION_KEYS: [KeyCode; 11] = [
+    KeyCode::F1,
+    KeyCode::F2,
+    KeyCode::F3,
+    KeyCode::F4,
+    KeyCode::F5,
+    KeyCode::F6,
+    KeyCode::F7,
+    KeyCode::F8,
+    KeyCode::F9,
+    KeyCode::F10,
+    KeyCode::F11,
+];
+
+fn bind(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    let (key, command) = match args {
+        [key] => (key, None),
+        [key, command] => (key, Some(command)),
+        _ => return Err(CommandError::Usage("bind <F1-F11> [command]")),
+    };
+    let code = key
+        .strip_prefix('F')
+        .and_then(|number| number.parse::<usize>().ok())
+        .and_then(|number| FUNCTION_KEYS.get(number.checked_sub(1)?))
+        .ok_or_else(|| CommandError::InvalidArgument(key.clone()))?;
+    let combo = KeyCombo::code(*code);
+    match command {
+        Some(command) => {
+            hotkey::bind(combo, Action::Command(command.clone()))
+                .map_err(|_| CommandError::InvalidArgument(key.clone()))?;
+        }
+        None => {
+            hotkey::unbind(combo);
+        }
+    }
+    Ok(())
+}
+
+fn no_args(args: &[String], usage: &'static str) -> Result<(), CommandError> {
+    if args.is_empty() {
+        Ok(())
+    } else {
+        Err(CommandError::Usage(usage))
+    }
+}
@@ -0,0 +1,374 @@
+//! APを起こして，それぞれでExecutorを動かす
+//!
+//! BSPはこれまで通りタイマやキーボードの割り込みを受け取り，スレッドもBSPの上だけで動く
+//! APはhltから起こすIPIの他は割り込みを受け取らず，
+//! `executor::spawn_shared`したタスクだけを自分のExecutorで実行する
+
+pub mod acpi;
+pub mod lapic;
+
+use core::arch::global_asm;
+use core::sync::atomic::{fence, AtomicU32, AtomicU8, AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::{
+    registers::control::Cr3,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+use crate::task::executor::Executor;
+use crate::{gdt, interrupts};
+
+/// 扱うCPUの最大数
+pub const MAX_CPUS: usize = 8;
+
+/// APが最初に実行するコードを置く物理アドレス
+///
+/// 1MiB未満でページ境界にある必要がある
+/// `memory::LOW_MEMORY_END`より下なので，フレームアロケータが割り当てることはない
+/// アセンブリの中にも同じ値を書いているので，変えるときは両方変える
+const TRAMPOLINE_ADDR: u64 = 0x8000;
+
+/// ローカルAPICのレジスタとAPのスタックを置く領域
+///
+/// レベル4テーブルの138番目のエントリにあたる
+const SMP_REGION_START: u64 = 0x0000_4500_0000_0000;
+/// CPUごとのスタックの大きさ
+const AP_STACK_SIZE: u64 = 4096 * 4;
+/// CPUごとに，ガードページを挟んで3つのスタックを置く
+const AP_STACKS_SPAN: u64 = (AP_STACK_SIZE + 4096) * 3;
+
+/// CPUの番号からローカルAPIC IDを引く表（u32::MAXなら空き）
+///
+/// 0番はBSP
+static APIC_IDS: [AtomicU32; MAX_CPUS] = [const { AtomicU32::new(u32::MAX) }; MAX_CPUS];
+/// ローカルAPIC IDからCPUの番号を引く表
+///
+/// 各CPUが起動したときに1度だけ書く．書かれていなければ0（BSP）
+static CPU_INDICES: [AtomicU8; 256] = [const { AtomicU8::new(0) }; 256];
+/// hltしてIPIを待っているAPのビット
+static IDLE_CPUS: AtomicUsize = AtomicUsize::new(0);
+/// 起動して，`ap_main`までたどり着いたCPUの数（BSPを含む）
+static ONLINE: AtomicUsize = AtomicUsize::new(1);
+/// APのGDTに設定するスタック
+static AP_STACKS: Mutex<[Option<ApStacks>; MAX_CPUS]> = Mutex::new([None; MAX_CPUS]);
+
+#[derive(Debug, Clone, Copy)]
+struct ApStacks {
+    kernel: VirtAddr,
+    double_fault: VirtAddr,
+    privilege: VirtAddr,
+}
+
+#[derive(Debug)]
+pub enum SmpError {
+    Acpi(acpi::AcpiError),
+    MapFailed(MapToError<Size4KiB>),
+}
+
+impl From<acpi::AcpiError> for SmpError {
+    fn from(err: acpi::AcpiError) -> Self {
+        SmpError::Acpi(err)
+    }
+}
+
+impl From<MapToError<Size4KiB>> for SmpError {
+    fn from(err: MapToError<Size4KiB>) -> Self {
+        SmpError::MapFailed(err)
+    }
+}
+
+extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_end: u8;
+    static ap_trampoline_params: u8;
+}
+
+// APがリアルモードから64ビットモードに移るためのコード
+//
+// TRAMPOLINE_ADDRにコピーして実行するので，アドレスはすべてそこからの相対位置で書く
+// BSPのページテーブルを使うので，TRAMPOLINE_ADDRは恒等マップしておく必要がある
+// メモリオペランドには1つのシンボルしか書けないので，相対位置は先に定数にしておく
+global_asm!(
+    ".set TRAMPOLINE_BASE, 0x8000",
+    ".set GDTR_ADDR, TRAMPOLINE_BASE + (ap_trampoline_gdtr - ap_trampoline_start)",
+    ".set PARAMS_ADDR, TRAMPOLINE_BASE + (ap_trampoline_params - ap_trampoline_start)",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_end",
+    ".global ap_trampoline_params",
+    ".code16",
+    "ap_trampoline_start:",
+    "cli",
+    "cld",
+    "xor ax, ax",
+    "mov ds, ax",
+    "lgdt [GDTR_ADDR]",
+    // PAE
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, dword ptr [PARAMS_ADDR]",
+    "mov cr3, eax",
+    // EFER.LMEとEFER.NXE
+    "mov ecx, 0xc0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // 保護モードとページングを同時に有効にして，直接ロングモードに入る
+    // WPはBSPと合わせる
+    "mov eax, cr0",
+    "or eax, 0x80010001",
+    "mov cr0, eax",
+    // jmp far 0x08:3f (32ビットのオフセット)
+    ".byte 0x66, 0xea",
+    ".long TRAMPOLINE_BASE + (3f - ap_trampoline_start)",
+    ".word 0x08",
+    ".code64",
+    "3:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "xor ax, ax",
+    "mov fs, ax",
+    "mov gs, ax",
+    "mov rsp, [rip + ap_trampoline_params + 8]",
+    "mov rdi, [rip + ap_trampoline_params + 24]",
+    "mov rax, [rip + ap_trampoline_params + 16]",
+    "call rax",
+    "ud2",
+    ".align 16",
+    // 一時的なGDT: ヌル，64ビットコード，データ
+    "4:",
+    ".quad 0",
+    ".quad 0x00af9a000000ffff",
+    ".quad 0x00cf92000000ffff",
+    "ap_trampoline_gdtr:",
+    ".word ap_trampoline_gdtr - 4b - 1",
+    ".long TRAMPOLINE_BASE + (4b - ap_trampoline_start)",
+    ".align 8",
+    // BSPが書き込む: CR3, スタック, 飛び先, CPUの番号
+    "ap_trampoline_params:",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0",
+    "ap_trampoline_end:",
+);
+
+/// トランポリンに渡す引数（ap_trampoline_paramsと同じ並び）
+#[repr(C)]
+struct TrampolineParams {
+    cr3: u64,
+    stack_top: u64,
+    entry: u64,
+    cpu_index: u64,
+}
+
+/// MADTに載っているAPをすべて起こす
+///
+/// BSPで，ヒープを初期化して割り込みを有効にした後に1度だけ呼ぶ
+/// 起動できたCPUの数（BSPを含む）を返す
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, SmpError> {
+    let madt = acpi::find_madt(mapper.phys_offset())?;
+    lapic::map(
+        madt.local_apic_address,
+        VirtAddr::new(SMP_REGION_START),
+        mapper,
+        frame_allocator,
+    )?;
+    let bsp_id = lapic::id();
+    APIC_IDS[0].store(u32::from(bsp_id), Ordering::Release);
+
+    install_trampoline(mapper, frame_allocator)?;
+
+    let mut next_cpu = 1;
+    for &apic_id in madt.apic_ids.iter().filter(|&&id| id != bsp_id) {
+        if next_cpu == MAX_CPUS {
+            break;
+        }
+        let stacks = map_ap_stacks(next_cpu, mapper, frame_allocator)?;
+        AP_STACKS.lock()[next_cpu] = Some(stacks);
+        APIC_IDS[next_cpu].store(u32::from(apic_id), Ordering::Release);
+        if start_ap(apic_id, next_cpu, stacks.kernel) {
+            next_cpu += 1;
+        } else {
+            // 起きなかったCPUの番号は次のAPに使い回す
+            APIC_IDS[next_cpu].store(u32::MAX, Ordering::Release);
+        }
+    }
+
+    Ok(online_cpus())
+}
+
+/// トランポリンをコピーし，恒等マップする
+fn install_trampoline(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page: Page = Page::containing_address(VirtAddr::new(TRAMPOLINE_ADDR));
+    let frame = PhysFrame::containing_address(PhysAddr::new(TRAMPOLINE_ADDR));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+        Ok(flush) => flush.flush(),
+        // 2回目以降は恒等マップ済み
+        Err(MapToError::PageAlreadyMapped(mapped)) if mapped == frame => {}
+        Err(err) => return Err(err),
+    }
+
+    unsafe {
+        let start = core::ptr::addr_of!(ap_trampoline_start);
+        let len = core::ptr::addr_of!(ap_trampoline_end) as usize - start as usize;
+        core::ptr::copy_nonoverlapping(start, TRAMPOLINE_ADDR as *mut u8, len);
+    }
+    Ok(())
+}
+
+/// CPUごとのスタックを新しいフレームでマップする
+fn map_ap_stacks(
+    cpu: usize,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<ApStacks, MapToError<Size4KiB>> {
+    // 先頭の1ページはローカルAPICのレジスタ
+    let base = SMP_REGION_START + 4096 + AP_STACKS_SPAN * cpu as u64;
+    let mut tops = [VirtAddr::zero(); 3];
+    for (i, top) in tops.iter_mut().enumerate() {
+        // 各スタックの下にはマップしないガードページを置く
+        let bottom = base + (AP_STACK_SIZE + 4096) * i as u64 + 4096;
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        for offset in (0..AP_STACK_SIZE).step_by(4096) {
+            let page: Page = Page::containing_address(VirtAddr::new(bottom + offset));
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+        *top = VirtAddr::new(bottom + AP_STACK_SIZE);
+    }
+    Ok(ApStacks {
+        kernel: tops[0],
+        double_fault: tops[1],
+        privilege: tops[2],
+    })
+}
+
+/// INIT-SIPI-SIPIでAPを起こし，`ap_main`にたどり着くまで待つ
+fn start_ap(apic_id: u8, cpu: usize, stack_top: VirtAddr) -> bool {
+    let params_offset = core::ptr::addr_of!(ap_trampoline_params) as u64
+        - core::ptr::addr_of!(ap_trampoline_start) as u64;
+    let params = (TRAMPOLINE_ADDR + params_offset) as *mut TrampolineParams;
+    unsafe {
+        params.write_volatile(TrampolineParams {
+            cr3: Cr3::read().0.start_address().as_u64(),
+            stack_top: stack_top.as_u64(),
+            entry: ap_main as usize as u64,
+            cpu_index: cpu as u64,
+        });
+    }
+
+    let online = online_cpus();
+    let vector = (TRAMPOLINE_ADDR / 4096) as u8;
+    lapic::send_init(apic_id);
+    // 10ms以上待つ必要がある．タイマ割り込みは約55ms間隔
+    wait_ticks(1);
+    for _ in 0..2 {
+        lapic::send_startup(apic_id, vector);
+        if wait_until(|| online_cpus() > online, 4) {
+            return true;
+        }
+    }
+    false
+}
+
+/// タイマ割り込みを`ticks`回待つ
+fn wait_ticks(ticks: u64) {
+    wait_until(|| false, ticks);
+}
+
+/// `condition`が満たされるか，タイマ割り込みが`ticks`回起きるまで待つ
+fn wait_until(condition: impl Fn() -> bool, ticks: u64) -> bool {
+    let until = interrupts::timer_ticks() + ticks;
+    while interrupts::timer_ticks() <= until {
+        if condition() {
+            return true;
+        }
+        core::hint::spin_loop();
+    }
+    condition()
+}
+
+/// APが64ビットモードに入って最初に実行する関数
+extern "C" fn ap_main(cpu: u64) -> ! {
+    let cpu = cpu as usize;
+    CPU_INDICES[usize::from(lapic::id())].store(cpu as u8, Ordering::Release);
+    let stacks = AP_STACKS.lock()[cpu].expect("AP started without stacks");
+    gdt::init_ap(stacks.double_fault, stacks.privilege);
+    interrupts::init_idt();
+    lapic::enable();
+
+    ONLINE.fetch_add(1, Ordering::AcqRel);
+    Executor::new().run()
+}
+
+/// 起動しているCPUの数（BSPを含む）
+pub fn online_cpus() -> usize {
+    ONLINE.load(Ordering::Acquire)
+}
+
+/// 実行中のCPUの番号
+///
+/// 0がBSPで，APは起動した順に1から番号が付く
+/// `init`を呼ぶ前は常に0
+pub fn cpu_index() -> usize {
+    if !lapic::is_mapped() {
+        return 0;
+    }
+    usize::from(CPU_INDICES[usize::from(lapic::id())].load(Ordering::Acquire))
+}
+
+/// hltしてIPIを待っているAPの数
+pub fn idle_cpus() -> usize {
+    IDLE_CPUS.load(Ordering::Relaxed).count_ones() as usize
+}
+
+/// `idle`がtrueなら，他のCPUから`wake_idle_cpu`で起こされるまでhltする
+///
+/// APから割り込みを無効にして呼ぶ．戻るときも割り込みは無効のまま
+pub(crate) fn halt_while(idle: impl Fn() -> bool) {
+    let bit = 1 << cpu_index();
+    IDLE_CPUS.fetch_or(bit, Ordering::SeqCst);
+    // 印を付けてから確かめ直すので，その間に積まれたタスクのIPIも取りこぼさない
+    // 割り込みを無効にしている間に届いたIPIは，hltの直前に受け取られる
+    if idle() {
+        x86_64::instructions::interrupts::enable_and_hlt();
+        x86_64::instructions::interrupts::disable();
+    }
+    IDLE_CPUS.fetch_and(!bit, Ordering::SeqCst);
+}
+
+/// hltしているAPを1つ起こす
+///
+/// どのCPUからでも，割り込みハンドラからでも呼べる
+/// タスクを共有キューに積んだ後に呼ぶ
+pub(crate) fn wake_idle_cpu() {
+    // キューへの書き込みが，IDLE_CPUSを読むより先に見えるようにする
+    fence(Ordering::SeqCst);
+    let idle = IDLE_CPUS.load(Ordering::SeqCst);
+    if idle == 0 {
+        return;
+    }
+    let cpu = idle.trailing_zeros() as usize;
+    let bit = 1 << cpu;
+    // 同じAPに何度もIPIを送らないように，送る側で印を消す
+    if IDLE_CPUS.fetch_and(!bit, Ordering::SeqCst) & bit != 0 {
+        let apic_id = APIC_IDS[cpu].load(Ordering::Acquire);
+        lapic::send_fixed(apic_id as u8, lapic::WAKEUP_VECTOR);
+    }
+}
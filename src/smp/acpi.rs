@@ -0,0 +1,153 @@
+//! APを起こすのに必要な分だけ，ACPIのテーブルを読む
+
+use alloc::vec::Vec;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// RSDPが見つからなかった
+    RsdpNotFound,
+    /// チェックサムが合わないテーブルがあった
+    BadChecksum([u8; 4]),
+    /// MADTがなかった
+    MadtNotFound,
+}
+
+/// MADT (Multiple APIC Description Table) から読んだ情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Madt {
+    /// ローカルAPICのレジスタの物理アドレス
+    pub local_apic_address: u64,
+    /// 有効なプロセッサのローカルAPIC ID
+    pub apic_ids: Vec<u8>,
+}
+
+const SDT_HEADER_SIZE: usize = 36;
+
+/// 物理メモリのマッピングを使ってMADTを探す
+pub fn find_madt(physical_memory_offset: VirtAddr) -> Result<Madt, AcpiError> {
+    let memory = PhysicalMemory {
+        offset: physical_memory_offset,
+    };
+    let rsdp = find_rsdp(&memory).ok_or(AcpiError::RsdpNotFound)?;
+
+    // ACPI 2.0以降ならXSDT，そうでなければRSDTを使う
+    let revision = memory.read_u8(rsdp + 15);
+    let (root, entry_size) = if revision >= 2 {
+        (memory.read_u64(rsdp + 24), 8)
+    } else {
+        (u64::from(memory.read_u32(rsdp + 16)), 4)
+    };
+    let root_length = memory.checked_table(root)?;
+
+    let entries = (root_length - SDT_HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + (SDT_HEADER_SIZE + i * entry_size) as u64;
+        let table = if entry_size == 8 {
+            memory.read_u64(entry)
+        } else {
+            u64::from(memory.read_u32(entry))
+        };
+        if memory.signature(table) == *b"APIC" {
+            let length = memory.checked_table(table)?;
+            return Ok(parse_madt(&memory, table, length));
+        }
+    }
+    Err(AcpiError::MadtNotFound)
+}
+
+fn parse_madt(memory: &PhysicalMemory, table: u64, length: usize) -> Madt {
+    const LOCAL_APIC: u8 = 0;
+    const LOCAL_APIC_ADDRESS_OVERRIDE: u8 = 5;
+    /// このCPUは存在し，使える
+    ///
+    /// ONLINE_CAPABLE(1 << 1)だけのエントリは後から追加できるCPUで，今はないので起こさない
+    const ENABLED: u32 = 1 << 0;
+
+    let mut madt = Madt {
+        local_apic_address: u64::from(memory.read_u32(table + 36)),
+        apic_ids: Vec::new(),
+    };
+
+    // ヘッダ，ローカルAPICのアドレス，フラグの後にエントリが並ぶ
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= length {
+        let entry = table + offset as u64;
+        let entry_type = memory.read_u8(entry);
+        let entry_length = usize::from(memory.read_u8(entry + 1));
+        if entry_length < 2 {
+            break;
+        }
+        match entry_type {
+            LOCAL_APIC => {
+                let flags = memory.read_u32(entry + 4);
+                if flags & ENABLED != 0 {
+                    madt.apic_ids.push(memory.read_u8(entry + 3));
+                }
+            }
+            LOCAL_APIC_ADDRESS_OVERRIDE => {
+                madt.local_apic_address = memory.read_u64(entry + 4);
+            }
+            _ => {}
+        }
+        offset += entry_length;
+    }
+    madt
+}
+
+/// EBDAの先頭1KiBと，0xe0000..0x100000のBIOS領域からRSDPを探す
+fn find_rsdp(memory: &PhysicalMemory) -> Option<u64> {
+    let ebda = u64::from(memory.read_u16(0x40e)) << 4;
+    let ebda_range = (ebda..ebda + 1024).step_by(16);
+    let bios_range = (0xe0000..0x100000).step_by(16);
+
+    ebda_range
+        .chain(bios_range)
+        .find(|&addr| memory.bytes(addr, 8) == b"RSD PTR " && checksum(memory.bytes(addr, 20)))
+}
+
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) == 0
+}
+
+/// ブートローダがマップした物理メモリを読む
+struct PhysicalMemory {
+    offset: VirtAddr,
+}
+
+impl PhysicalMemory {
+    fn bytes(&self, phys: u64, len: usize) -> &'static [u8] {
+        unsafe { core::slice::from_raw_parts((self.offset + phys).as_ptr(), len) }
+    }
+
+    fn read_u8(&self, phys: u64) -> u8 {
+        self.bytes(phys, 1)[0]
+    }
+
+    fn read_u16(&self, phys: u64) -> u16 {
+        unsafe { core::ptr::read_unaligned((self.offset + phys).as_ptr()) }
+    }
+
+    fn read_u32(&self, phys: u64) -> u32 {
+        unsafe { core::ptr::read_unaligned((self.offset + phys).as_ptr()) }
+    }
+
+    fn read_u64(&self, phys: u64) -> u64 {
+        unsafe { core::ptr::read_unaligned((self.offset + phys).as_ptr()) }
+    }
+
+    fn signature(&self, table: u64) -> [u8; 4] {
+        let mut signature = [0; 4];
+        signature.copy_from_slice(self.bytes(table, 4));
+        signature
+    }
+
+    /// テーブルのチェックサムを確かめ，長さを返す
+    fn checked_table(&self, table: u64) -> Result<usize, AcpiError> {
+        let length = self.read_u32(table + 4) as usize;
+        if length < SDT_HEADER_SIZE || !checksum(self.bytes(table, length)) {
+            return Err(AcpiError::BadChecksum(self.signature(table)));
+        }
+        Ok(length)
+    }
+}
@@ -0,0 +1,121 @@
+//! ローカルAPIC
+//!
+//! IPIを送るのと，自分のIDを読むのに使う
+//! 割り込みはこれまで通り8259から受け取るので，BSPの設定はファームウェアのままにする
+//! APでは，hltから起こすIPIを受け取るためにソフトウェア的に有効にする
+
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags,
+        PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const REG_ID: u64 = 0x20;
+const REG_EOI: u64 = 0xb0;
+const REG_SPURIOUS: u64 = 0xf0;
+const REG_ICR_LOW: u64 = 0x300;
+const REG_ICR_HIGH: u64 = 0x310;
+
+const ICR_INIT: u32 = 0x0500;
+const ICR_STARTUP: u32 = 0x0600;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+
+const SPURIOUS_APIC_ENABLE: u32 = 1 << 8;
+
+/// hltしているAPを起こすIPIのベクタ
+pub const WAKEUP_VECTOR: u8 = 0xf0;
+/// ローカルAPICのスプリアス割り込みのベクタ
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// レジスタをマップした仮想アドレス（0ならまだマップしていない）
+static BASE: AtomicU64 = AtomicU64::new(0);
+
+/// レジスタの物理ページを`virt`にキャッシュ無効でマップする
+pub(super) fn map(
+    phys: u64,
+    virt: VirtAddr,
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let page: Page = Page::containing_address(virt);
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    BASE.store(virt.as_u64() + (phys & 0xfff), Ordering::Release);
+    Ok(())
+}
+
+fn read(register: u64) -> u32 {
+    let base = BASE.load(Ordering::Acquire);
+    debug_assert_ne!(base, 0, "local APIC is not mapped");
+    unsafe { core::ptr::read_volatile((base + register) as *const u32) }
+}
+
+fn write(register: u64, value: u32) {
+    let base = BASE.load(Ordering::Acquire);
+    debug_assert_ne!(base, 0, "local APIC is not mapped");
+    unsafe { core::ptr::write_volatile((base + register) as *mut u32, value) }
+}
+
+pub fn is_mapped() -> bool {
+    BASE.load(Ordering::Acquire) != 0
+}
+
+/// このCPUのローカルAPIC ID
+pub fn id() -> u8 {
+    (read(REG_ID) >> 24) as u8
+}
+
+/// このCPUのローカルAPICをソフトウェア的に有効にする
+///
+/// 有効にしないと，固定ベクタのIPIを受け取れない
+pub(super) fn enable() {
+    write(
+        REG_SPURIOUS,
+        SPURIOUS_APIC_ENABLE | u32::from(SPURIOUS_VECTOR),
+    );
+}
+
+/// ローカルAPICから受け取った割り込みの処理が終わったことを伝える
+///
+/// スプリアス割り込みのときは送らない
+pub fn end_of_interrupt() {
+    write(REG_EOI, 0);
+}
+
+/// INIT IPIを送る
+pub(super) fn send_init(apic_id: u8) {
+    send_ipi(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Startup IPIを送る
+///
+/// APは物理アドレス`vector * 0x1000`からリアルモードで実行を始める
+pub(super) fn send_startup(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// `vector`の割り込みを起こすIPIを送る
+pub(super) fn send_fixed(apic_id: u8, vector: u8) {
+    send_ipi(apic_id, ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+fn send_ipi(apic_id: u8, command: u32) {
+    // 割り込みハンドラからも送るので，上位と下位の間に割り込まれないようにする
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        // 上位を先に書く．下位に書いた時点で送られる
+        write(REG_ICR_HIGH, u32::from(apic_id) << 24);
+        write(REG_ICR_LOW, command);
+        while read(REG_ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+    });
+}
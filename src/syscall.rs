@@ -0,0 +1,288 @@
+use core::arch::global_asm;
+
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+use crate::process::{self, ExitStatus, Fault};
+use crate::{gdt, interrupts, memory, print, serial_print};
+
+/// システムコール番号
+///
+/// raxに入れて`syscall`を実行する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u64)]
+pub enum Syscall {
+    /// write(fd, buf, len) -> 書き込んだバイト数
+    Write = 0,
+    /// exit(code) -> 戻らない
+    Exit = 1,
+    /// sleep(ticks) -> 0
+    Sleep = 2,
+    /// getpid() -> pid
+    GetPid = 3,
+}
+
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+// エラーはLinuxと同じく負のerrnoで返す
+pub const EBADF: i64 = -9;
+pub const EFAULT: i64 = -14;
+pub const EINVAL: i64 = -22;
+pub const ENOSYS: i64 = -38;
+
+/// 引数はrdi, rsi, rdx, r10, r8の順に渡される
+type SyscallHandler = fn(u64, u64, u64, u64, u64) -> i64;
+
+/// システムコール番号から引くテーブル
+static SYSCALL_TABLE: [SyscallHandler; 4] = [sys_write, sys_exit, sys_sleep, sys_getpid];
+
+const SYSCALL_STACK_SIZE: usize = 4096 * 5;
+
+// System V ABIはcallの前にrspが16バイト境界にあることを求める
+#[repr(align(16))]
+struct SyscallStack([u8; SYSCALL_STACK_SIZE]);
+
+static mut SYSCALL_STACK: SyscallStack = SyscallStack([0; SYSCALL_STACK_SIZE]);
+
+// syscall_entryからしか触らない
+static mut SYSCALL_STACK_TOP: u64 = 0;
+static mut SYSCALL_USER_RSP: u64 = 0;
+// enter_user_modeを呼んだときのカーネルのrsp
+static mut USER_MODE_RETURN_RSP: u64 = 0;
+
+/// `syscall`命令を使えるようにする
+///
+/// gdt::initの後に呼ぶ必要がある
+pub fn init() {
+    let selectors = gdt::selectors();
+
+    unsafe {
+        SYSCALL_STACK_TOP = core::ptr::addr_of!(SYSCALL_STACK.0) as u64 + SYSCALL_STACK_SIZE as u64;
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    Star::write(
+        selectors.user_code_selector,
+        selectors.user_data_selector,
+        selectors.code_selector,
+        selectors.data_selector,
+    )
+    .expect("invalid segment layout for syscall");
+    LStar::write(VirtAddr::new(syscall_entry as usize as u64));
+    // カーネルに入った時点では割り込みを無効にしておく
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::TRAP_FLAG);
+}
+
+extern "C" {
+    fn syscall_entry();
+    fn enter_user_mode_asm(
+        entry: u64,
+        user_stack: u64,
+        code_selector: u64,
+        data_selector: u64,
+    ) -> RawExit;
+    fn return_from_user_mode_asm(kind: u64, value: u64) -> !;
+}
+
+/// ユーザモードから戻ってきた理由
+///
+/// raxとrdxの2つのレジスタで返される
+#[repr(C)]
+struct RawExit {
+    kind: u64,
+    value: u64,
+}
+
+const EXIT_KIND_EXITED: u64 = 0;
+const EXIT_KIND_PAGE_FAULT: u64 = 1;
+const EXIT_KIND_GENERAL_PROTECTION: u64 = 2;
+const EXIT_KIND_INVALID_OPCODE: u64 = 3;
+
+impl RawExit {
+    fn into_status(self) -> ExitStatus {
+        match self.kind {
+            EXIT_KIND_EXITED => ExitStatus::Exited(self.value as i64),
+            EXIT_KIND_PAGE_FAULT => {
+                ExitStatus::Killed(Fault::PageFault(VirtAddr::new_truncate(self.value)))
+            }
+            EXIT_KIND_GENERAL_PROTECTION => {
+                ExitStatus::Killed(Fault::GeneralProtection(self.value))
+            }
+            EXIT_KIND_INVALID_OPCODE => ExitStatus::Killed(Fault::InvalidOpcode),
+            kind => unreachable!("unknown user mode exit kind {}", kind),
+        }
+    }
+}
+
+// syscall命令はスタックを切り替えないので，ここで自分で切り替える
+// rcxにユーザのrip，r11にユーザのrflagsが入っている
+global_asm!(
+    ".global syscall_entry",
+    "syscall_entry:",
+    "mov [rip + {user_rsp}], rsp",
+    "mov rsp, [rip + {stack_top}]",
+    "push rcx",
+    "push r11",
+    "push rdi",
+    "push rsi",
+    "push rdx",
+    "push r10",
+    "push r8",
+    "push r9",
+    // syscall_dispatch(number, arg1, arg2, arg3, arg4, arg5)
+    "mov r9, r8",
+    "mov r8, r10",
+    "mov rcx, rdx",
+    "mov rdx, rsi",
+    "mov rsi, rdi",
+    "mov rdi, rax",
+    "call {dispatch}",
+    "pop r9",
+    "pop r8",
+    "pop r10",
+    "pop rdx",
+    "pop rsi",
+    "pop rdi",
+    "pop r11",
+    "pop rcx",
+    "mov rsp, [rip + {user_rsp}]",
+    "sysretq",
+    user_rsp = sym SYSCALL_USER_RSP,
+    stack_top = sym SYSCALL_STACK_TOP,
+    dispatch = sym syscall_dispatch,
+);
+
+// iretqでring3へ移る
+// 戻ってくるときはreturn_from_user_mode_asmが，保存したカーネルのコンテキストを復元する
+global_asm!(
+    ".global enter_user_mode_asm",
+    "enter_user_mode_asm:",
+    "pushfq",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rip + {return_rsp}], rsp",
+    "push rcx",
+    "push rsi",
+    "push 0x202",
+    "push rdx",
+    "push rdi",
+    "iretq",
+    "",
+    ".global return_from_user_mode_asm",
+    "return_from_user_mode_asm:",
+    "mov rax, rdi",
+    "mov rdx, rsi",
+    "mov rsp, [rip + {return_rsp}]",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "popfq",
+    "ret",
+    return_rsp = sym USER_MODE_RETURN_RSP,
+);
+
+/// ring3で`entry`から実行を始め，exitシステムコールか例外で戻ってくるまで待つ
+///
+/// unsafe
+/// 呼び出し元は，`entry`と`user_stack`がユーザからアクセス可能なページに
+/// マップされていることを保証しなくてはならない
+pub unsafe fn enter_user_mode(entry: VirtAddr, user_stack: VirtAddr) -> ExitStatus {
+    let selectors = gdt::selectors();
+    enter_user_mode_asm(
+        entry.as_u64(),
+        user_stack.as_u64(),
+        u64::from(selectors.user_code_selector.0),
+        u64::from(selectors.user_data_selector.0),
+    )
+    .into_status()
+}
+
+/// enter_user_modeを呼んだところへ戻る
+///
+/// ユーザモードから入ったシステムコールか例外ハンドラの中でしか呼んではいけない
+pub(crate) fn exit_user_mode(status: ExitStatus) -> ! {
+    let (kind, value) = match status {
+        ExitStatus::Exited(code) => (EXIT_KIND_EXITED, code as u64),
+        ExitStatus::Killed(Fault::PageFault(addr)) => (EXIT_KIND_PAGE_FAULT, addr.as_u64()),
+        ExitStatus::Killed(Fault::GeneralProtection(error_code)) => {
+            (EXIT_KIND_GENERAL_PROTECTION, error_code)
+        }
+        ExitStatus::Killed(Fault::InvalidOpcode) => (EXIT_KIND_INVALID_OPCODE, 0),
+    };
+    unsafe { return_from_user_mode_asm(kind, value) }
+}
+
+extern "C" fn syscall_dispatch(
+    number: u64,
+    arg1: u64,
+    arg2: u64,
+    arg3: u64,
+    arg4: u64,
+    arg5: u64,
+) -> i64 {
+    match SYSCALL_TABLE.get(number as usize) {
+        Some(handler) => handler(arg1, arg2, arg3, arg4, arg5),
+        None => ENOSYS,
+    }
+}
+
+/// ユーザから渡されたバッファがユーザ領域に収まり，ring3から読めるか確かめる
+///
+/// カーネルのメモリを読ませないように，またマップされていないページでring0の例外を起こさないようにする
+fn user_slice(ptr: u64, len: u64) -> Option<&'static [u8]> {
+    let end = ptr.checked_add(len)?;
+    if ptr < process::USER_REGION_START || end > process::USER_REGION_END {
+        return None;
+    }
+    if !memory::is_user_accessible(VirtAddr::new(ptr), len) {
+        return None;
+    }
+    Some(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) })
+}
+
+fn sys_write(fd: u64, ptr: u64, len: u64, _: u64, _: u64) -> i64 {
+    let bytes = match user_slice(ptr, len) {
+        Some(bytes) => bytes,
+        None => return EFAULT,
+    };
+    let s = match core::str::from_utf8(bytes) {
+        Ok(s) => s,
+        Err(_) => return EINVAL,
+    };
+
+    match fd {
+        STDOUT => print!("{}", s),
+        STDERR => {
+            serial_print!("{}", s);
+        }
+        _ => return EBADF,
+    }
+    len as i64
+}
+
+fn sys_exit(code: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    exit_user_mode(ExitStatus::Exited(code as i64))
+}
+
+fn sys_sleep(ticks: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    use x86_64::instructions::interrupts::{disable, enable_and_hlt};
+
+    let until = interrupts::timer_ticks().saturating_add(ticks);
+    while interrupts::timer_ticks() < until {
+        enable_and_hlt();
+        disable();
+    }
+    0
+}
+
+fn sys_getpid(_: u64, _: u64, _: u64, _: u64, _: u64) -> i64 {
+    process::current_pid().as_u64() as i64
+}
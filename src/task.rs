@@ -1,6 +1,16 @@
 pub mod executor;
+pub mod join;
 pub mod keyboard;
+pub mod monitor;
+pub mod mouse;
+pub mod select;
 pub mod simple_executor;
+pub mod sync;
+pub mod timer;
+
+pub use select::{select, select_all, Either};
+pub use simple_executor::block_on;
+pub use timer::{sleep, timeout};
 
 use alloc::boxed::Box;
 use core::sync::atomic::{AtomicU64, Ordering};
@@ -8,7 +18,7 @@ use core::task::{Context, Poll};
 use core::{future::Future, pin::Pin};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-struct TaskId(u64);
+pub(crate) struct TaskId(u64);
 
 impl TaskId {
     fn new() -> Self {
@@ -17,24 +27,107 @@ impl TaskId {
     }
 }
 
+/// タスクの優先度
+///
+/// Executorは1巡ごとに，優先度の高い順にそれぞれの予算の数だけタスクをポーリングする
+/// 低い優先度のタスクも毎巡ポーリングされるので，飢餓状態にはならない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Priority {
+    /// キーボード入力など，割り込みで起こされてすぐ応答したいもの
+    High,
+    #[default]
+    Normal,
+    /// アニメーションなど，遅れても構わないもの
+    Low,
+}
+
+impl Priority {
+    pub const ALL: [Priority; 3] = [Priority::High, Priority::Normal, Priority::Low];
+
+    /// 1巡でポーリングするタスクの数
+    pub fn poll_budget(self) -> usize {
+        match self {
+            Priority::High => 8,
+            Priority::Normal => 4,
+            Priority::Low => 1,
+        }
+    }
+
+    pub(crate) fn index(self) -> usize {
+        self as usize
+    }
+}
+
 pub struct Task {
     id: TaskId,
+    /// 診断表示用の名前
+    name: &'static str,
+    priority: Priority,
     // 各async fnは異なる型を持っている
     // それに対応するため dynによる動的ディスパッチを使う
     future: Pin<Box<dyn Future<Output = ()>>>,
 }
 
 impl Task {
-    pub fn new(future: impl Future<Output = ()> + 'static) -> Self {
+    pub fn new<F>(future: F) -> Self
+    where
+        F: Future<Output = ()> + 'static,
+    {
         // 返されたTaskが任意の時間だけ生き続けることができるため，
         // futureもその時間だけ有効である必要がある
         Self {
             id: TaskId::new(),
+            // 名前を付けなければ，futureの型名を使う
+            name: core::any::type_name::<F>(),
+            priority: Priority::default(),
             future: Box::pin(future),
         }
     }
 
+    pub fn with_priority(mut self, priority: Priority) -> Self {
+        self.priority = priority;
+        self
+    }
+
+    pub fn with_name(mut self, name: &'static str) -> Self {
+        self.name = name;
+        self
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    pub fn priority(&self) -> Priority {
+        self.priority
+    }
+
     fn poll(&mut self, context: &mut Context) -> Poll<()> {
         self.future.as_mut().poll(context)
     }
 }
+
+/// 一度だけPendingを返して，同じExecutorの他のタスクに順番を譲る
+///
+/// 自分自身をすぐwakeするので，キューの後ろに並び直してまたポーリングされる
+pub fn yield_now() -> YieldNow {
+    YieldNow { yielded: false }
+}
+
+/// `yield_now`が返すfuture
+pub struct YieldNow {
+    yielded: bool,
+}
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.yielded {
+            return Poll::Ready(());
+        }
+        self.yielded = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
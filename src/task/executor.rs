@@ -1,61 +1,194 @@
-use super::{Task, TaskId};
-use alloc::{collections::BTreeMap, sync::Arc, task::Wake};
+mod shared;
+
+pub use shared::{cpu_stats, live_shared_tasks, spawn_shared, CpuStats};
+
+use super::{
+    join::JoinHandle,
+    monitor::{Registry, TaskMonitor, TaskStats},
+    Priority, Task, TaskId,
+};
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    task::Wake,
+};
+use core::future::Future;
+use core::sync::atomic::{AtomicBool, Ordering};
 use core::task::{Context, Waker};
-use crossbeam_queue::ArrayQueue;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::smp;
 
+/// 1巡でポーリングする，`spawn_shared`したタスクの数
+const SHARED_POLL_BUDGET: usize = 4;
+
+/// CPUごとに1つ動かすExecutor
+///
+/// `spawn`したタスクはこのExecutorだけがポーリングする
+/// `spawn_shared`したタスクはCPUごとのキューに入り，手の空いたCPUのExecutorが盗んで実行する
 pub struct Executor {
     tasks: BTreeMap<TaskId, Task>,
-    task_queue: Arc<ArrayQueue<TaskId>>,
-    waker_cache: BTreeMap<TaskId, Waker>,
+    task_queue: Arc<RunQueue>,
+    waker_cache: BTreeMap<TaskId, Arc<TaskWaker>>,
+    spawn_queue: Arc<Mutex<VecDeque<Task>>>,
+    registry: Arc<Mutex<Registry>>,
 }
 
 impl Executor {
     pub fn new() -> Self {
         Self {
             tasks: BTreeMap::new(),
-            task_queue: Arc::new(ArrayQueue::new(100)),
+            task_queue: Arc::new(RunQueue::new()),
             waker_cache: BTreeMap::new(),
+            spawn_queue: Arc::new(Mutex::new(VecDeque::new())),
+            registry: Arc::new(Mutex::new(Registry::default())),
         }
     }
 
-    pub fn spawn(&mut self, task: Task) {
+    /// futureをタスクとして追加し，その結果を待つJoinHandleを返す
+    pub fn spawn<F>(&mut self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        self.spawn_with_priority(future, Priority::default())
+    }
+
+    /// 優先度を指定してタスクを追加する
+    pub fn spawn_with_priority<F>(&mut self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::with_join_handle(future);
+        self.spawn_task(task.with_priority(priority));
+        handle
+    }
+
+    /// 名前と優先度を指定してタスクを追加する
+    ///
+    /// 名前はmonitorで一覧を取ったときに表示される
+    pub fn spawn_named<F>(
+        &mut self,
+        future: F,
+        name: &'static str,
+        priority: Priority,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::with_join_handle(future);
+        self.spawn_task(task.with_name(name).with_priority(priority));
+        handle
+    }
+
+    fn spawn_task(&mut self, task: Task) {
         let task_id = task.id;
         if self.tasks.insert(task.id, task).is_some() {
             // 同じIDのタスクがすでにtasks内にあるのはバグ
             panic!("task with same ID already in tasks");
         }
-        self.task_queue.push(task_id).expect("queue full");
+        // 各タスクはキューに高々1つしか入らないので，タスクの数だけ確保しておけば
+        // 割り込みハンドラからのwakeでアロケートすることはない
+        let (name, priority) = {
+            let task = &self.tasks[&task_id];
+            (task.name, task.priority)
+        };
+        self.task_queue.reserve(priority, self.tasks.len());
+
+        let stats = Arc::new(TaskStats::new(task_id, name, priority));
+        self.registry.lock().insert(stats.clone());
+        let waker = Arc::new(TaskWaker {
+            task_id,
+            priority,
+            scheduled: AtomicBool::new(false),
+            task_queue: self.task_queue.clone(),
+            stats,
+        });
+        waker.wake_task();
+        self.waker_cache.insert(task_id, waker);
+    }
+
+    /// タスクの一覧を取るためのハンドルを返す
+    pub fn monitor(&self) -> TaskMonitor {
+        TaskMonitor::new(self.registry.clone())
+    }
+
+    /// 実行中のタスクからでもタスクを追加できるハンドルを返す
+    pub fn spawner(&self) -> Spawner {
+        Spawner {
+            spawn_queue: self.spawn_queue.clone(),
+        }
+    }
+
+    /// Spawner経由で追加されたタスクをtasksに移す
+    fn spawn_queued_tasks(&mut self) {
+        let spawned =
+            interrupts::without_interrupts(|| core::mem::take(&mut *self.spawn_queue.lock()));
+        for task in spawned {
+            self.spawn_task(task);
+        }
     }
 
+    /// 実行可能なタスクを1巡ポーリングする
+    ///
+    /// 優先度ごとに予算の数までしかポーリングしないので，
+    /// 何度もwakeするタスクがあっても他のタスクが待たされ続けることはない
+    /// 最後に，CPUの間で共有するタスクを予算の数までポーリングする
     fn run_ready_tasks(&mut self) {
+        super::timer::wake_expired();
+        self.spawn_queued_tasks();
+
+        for priority in Priority::ALL.iter().copied() {
+            for _ in 0..priority.poll_budget() {
+                match self.task_queue.pop(priority) {
+                    Some(task_id) => self.poll_task(task_id),
+                    None => break,
+                }
+            }
+        }
+
+        let cpu = smp::cpu_index();
+        for _ in 0..SHARED_POLL_BUDGET {
+            if !shared::run_once(cpu) {
+                break;
+            }
+        }
+    }
+
+    fn poll_task(&mut self, task_id: TaskId) {
         // 借用チェッカのエラーを回避するため，selfを分配する
         let Self {
             tasks,
-            task_queue,
             waker_cache,
+            registry,
+            ..
         } = self;
 
-        while let Ok(task_id) = task_queue.pop() {
-            let task = match tasks.get_mut(&task_id) {
-                Some(task) => task,
-                // 今回の実装では存在しないタスクに対してwakeupが発生することがある
-                // タスクはもう存在しない
-                None => continue,
-            };
-
-            let waker = waker_cache
-                .entry(task_id)
-                .or_insert_with(|| TaskWaker::new(task_id, task_queue.clone()));
-            let mut context = Context::from_waker(waker);
-
-            match task.poll(&mut context) {
-                core::task::Poll::Ready(()) => {
-                    // タスクが終了したので削除
-                    tasks.remove(&task_id);
-                    waker_cache.remove(&task_id);
-                }
-                core::task::Poll::Pending => {}
+        let (task, task_waker) = match (tasks.get_mut(&task_id), waker_cache.get(&task_id)) {
+            (Some(task), Some(task_waker)) => (task, task_waker),
+            // タスクはもう存在しない
+            _ => return,
+        };
+
+        // ポーリング中にwakeされたら，もう一度キューに入れる必要がある
+        task_waker.scheduled.store(false, Ordering::Release);
+        let waker = Waker::from(task_waker.clone());
+        let mut context = Context::from_waker(&waker);
+
+        let started_at = task_waker.stats.start_poll();
+        let poll = task.poll(&mut context);
+        task_waker.stats.finish_poll(started_at, poll.is_ready());
+
+        match poll {
+            core::task::Poll::Ready(()) => {
+                // 終了したタスクへのwakeはキューに入れないようにする
+                task_waker.scheduled.store(true, Ordering::Release);
+                // タスクが終了したので削除
+                tasks.remove(&task_id);
+                waker_cache.remove(&task_id);
+                registry.lock().finish(task_id);
             }
+            core::task::Poll::Pending => {}
         }
     }
 
@@ -66,35 +199,171 @@ impl Executor {
         }
     }
 
+    /// すべてのタスクが終了するまで実行する
+    ///
+    /// 他のCPUで実行中のものも含め，`spawn_shared`したタスクの終了も待つ
+    /// 割り込みを待つタスクが残っていると戻らないので，主にテストで使う
+    pub fn run_until_complete(&mut self) {
+        loop {
+            self.run_ready_tasks();
+            if self.tasks.is_empty() && self.spawn_queue_is_empty() && live_shared_tasks() == 0 {
+                break;
+            }
+            self.sleep_if_idle();
+        }
+    }
+
+    fn spawn_queue_is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.spawn_queue.lock().is_empty())
+    }
+
     fn sleep_if_idle(&self) {
-        use x86_64::instructions::interrupts::{self, enable_and_hlt};
+        use x86_64::instructions::interrupts::enable_and_hlt;
+
+        let cpu = smp::cpu_index();
+
         // is_emptyのチェックとhitの間に割り込みが起きる可能せいがある
         // なので，チェックの前に割り込みを無効化してその可能性を排除する
         interrupts::disable();
 
-        if self.task_queue.is_empty() {
-            enable_and_hlt();
+        if cpu != 0 {
+            // APはタイマ割り込みを受け取らないので，他のCPUがタスクを積んだときのIPIで起きる
+            // 割り込みは無効のまま戻る
+            smp::halt_while(|| {
+                self.task_queue.is_empty()
+                    && self.spawn_queue.lock().is_empty()
+                    && shared::all_empty()
+            });
+            return;
+        }
+
+        if self.task_queue.is_empty() && self.spawn_queue.lock().is_empty() && shared::is_empty(cpu)
+        {
+            if crate::thread::has_ready_threads() {
+                // 他のスレッドが動けるなら，止まらずに譲る
+                crate::thread::yield_now();
+                interrupts::enable();
+            } else {
+                enable_and_hlt();
+            }
         } else {
             interrupts::enable();
         }
     }
 }
 
+/// タスクを実行中のExecutorに追加するためのハンドル
+///
+/// クローンしてfutureの中に持ち込める
+/// アロケートするので，割り込みハンドラから使ってはいけない
+#[derive(Clone)]
+pub struct Spawner {
+    spawn_queue: Arc<Mutex<VecDeque<Task>>>,
+}
+
+impl Spawner {
+    /// futureをタスクとして追加し，その結果を待つJoinHandleを返す
+    ///
+    /// 実際にtasksへ入るのは，Executorが次にrun_ready_tasksを呼んだとき
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        self.spawn_with_priority(future, Priority::default())
+    }
+
+    /// 優先度を指定してタスクを追加する
+    pub fn spawn_with_priority<F>(&self, future: F, priority: Priority) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::with_join_handle(future);
+        self.push(task.with_priority(priority));
+        handle
+    }
+
+    /// 名前と優先度を指定してタスクを追加する
+    pub fn spawn_named<F>(
+        &self,
+        future: F,
+        name: &'static str,
+        priority: Priority,
+    ) -> JoinHandle<F::Output>
+    where
+        F: Future + 'static,
+    {
+        let (task, handle) = Task::with_join_handle(future);
+        self.push(task.with_name(name).with_priority(priority));
+        handle
+    }
+
+    fn push(&self, task: Task) {
+        interrupts::without_interrupts(|| self.spawn_queue.lock().push_back(task));
+    }
+}
+
+/// 実行可能なタスクのキュー
+///
+/// 優先度ごとにキューを持つ
+/// 割り込みハンドラからもpushされるので，ロックは必ず割り込みを無効にして取る
+struct RunQueue {
+    queues: Mutex<[VecDeque<TaskId>; 3]>,
+}
+
+impl RunQueue {
+    fn new() -> Self {
+        Self {
+            queues: Mutex::new([VecDeque::new(), VecDeque::new(), VecDeque::new()]),
+        }
+    }
+
+    fn push(&self, task_id: TaskId, priority: Priority) {
+        interrupts::without_interrupts(|| {
+            let mut queues = self.queues.lock();
+            let queue = &mut queues[priority.index()];
+            // reserveしてあるので，ここでアロケートは起きないはず
+            debug_assert!(queue.len() < queue.capacity());
+            queue.push_back(task_id);
+        });
+    }
+
+    fn pop(&self, priority: Priority) -> Option<TaskId> {
+        interrupts::without_interrupts(|| self.queues.lock()[priority.index()].pop_front())
+    }
+
+    fn is_empty(&self) -> bool {
+        interrupts::without_interrupts(|| self.queues.lock().iter().all(|queue| queue.is_empty()))
+    }
+
+    /// 少なくともcapacity個のタスクIDを入れられるようにする
+    ///
+    /// アロケートするので，割り込みハンドラから呼んではいけない
+    fn reserve(&self, priority: Priority, capacity: usize) {
+        interrupts::without_interrupts(|| {
+            let mut queues = self.queues.lock();
+            let queue = &mut queues[priority.index()];
+            let additional = capacity.saturating_sub(queue.len());
+            queue.reserve(additional);
+        });
+    }
+}
+
 struct TaskWaker {
     task_id: TaskId,
-    task_queue: Arc<ArrayQueue<TaskId>>,
+    priority: Priority,
+    // キューに入っているか，タスクが終了していればtrue
+    // 同じタスクが何度wakeされてもキューには1つしか入らない
+    scheduled: AtomicBool,
+    task_queue: Arc<RunQueue>,
+    stats: Arc<TaskStats>,
 }
 
 impl TaskWaker {
     fn wake_task(&self) {
-        self.task_queue.push(self.task_id).expect("task_queue full");
-    }
-
-    fn new(task_id: TaskId, task_queue: Arc<ArrayQueue<TaskId>>) -> Waker {
-        Waker::from(Arc::new(TaskWaker {
-            task_id,
-            task_queue,
-        }))
+        self.stats.record_wake();
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            self.task_queue.push(self.task_id, self.priority);
+        }
     }
 }
 
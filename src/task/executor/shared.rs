@@ -0,0 +1,142 @@
+//! CPUの間で盗み合うタスクのキュー
+//!
+//! `Executor::spawn`したタスクと違い，どのCPUのExecutorでポーリングされるか分からないので
+//! futureは`Send`でなければならない
+//! 各CPUのExecutorは自分のキューからタスクを取り，空なら他のCPUのキューから半分を盗む
+
+use alloc::{boxed::Box, collections::VecDeque, sync::Arc, task::Wake};
+use core::{
+    future::Future,
+    pin::Pin,
+    sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+    task::{Context, Waker},
+};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use crate::smp::{self, cpu_index, MAX_CPUS};
+
+type BoxFuture = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+struct SharedTask {
+    // ポーリング中のCPUがロックを持つ
+    future: Mutex<Option<BoxFuture>>,
+    // キューに入っているか，終了していればtrue
+    scheduled: AtomicBool,
+}
+
+impl Wake for SharedTask {
+    fn wake(self: Arc<Self>) {
+        if !self.scheduled.swap(true, Ordering::AcqRel) {
+            push(self);
+        }
+    }
+}
+
+/// CPUごとの実行可能キュー
+///
+/// BSPでは割り込みハンドラからwakeされることがあるので，ロックは割り込みを無効にして取る
+static QUEUES: [Mutex<VecDeque<Arc<SharedTask>>>; MAX_CPUS] =
+    [const { Mutex::new(VecDeque::new()) }; MAX_CPUS];
+/// CPUごとのポーリングした回数
+static POLLS: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// CPUごとの他のCPUから盗んだタスクの数
+static STOLEN: [AtomicU64; MAX_CPUS] = [const { AtomicU64::new(0) }; MAX_CPUS];
+/// 終了していないタスクの数
+static LIVE_TASKS: AtomicUsize = AtomicUsize::new(0);
+
+/// どのCPUで実行してもよいタスクを，呼び出したCPUのキューに追加する
+pub fn spawn_shared(future: impl Future<Output = ()> + Send + 'static) {
+    LIVE_TASKS.fetch_add(1, Ordering::Relaxed);
+    push(Arc::new(SharedTask {
+        future: Mutex::new(Some(Box::pin(future))),
+        scheduled: AtomicBool::new(true),
+    }));
+}
+
+fn push(task: Arc<SharedTask>) {
+    let cpu = cpu_index();
+    interrupts::without_interrupts(|| QUEUES[cpu].lock().push_back(task));
+    // hltしているAPがいれば，盗みに来てもらう
+    smp::wake_idle_cpu();
+}
+
+/// 自分のキューか，他のCPUから盗んだタスクを1つポーリングする
+///
+/// 何もポーリングしなければfalseを返す
+pub(super) fn run_once(cpu: usize) -> bool {
+    let task = match pop(cpu).or_else(|| steal(cpu)) {
+        Some(task) => task,
+        None => return false,
+    };
+
+    let mut future = task.future.lock();
+    let done = match future.as_mut() {
+        Some(inner) => {
+            // ポーリング中にwakeされたら，もう一度キューに入れる必要がある
+            task.scheduled.store(false, Ordering::Release);
+            let waker = Waker::from(task.clone());
+            let mut context = Context::from_waker(&waker);
+            inner.as_mut().poll(&mut context).is_ready()
+        }
+        // 他のCPUで終了した
+        None => false,
+    };
+    if done {
+        *future = None;
+        task.scheduled.store(true, Ordering::Release);
+        LIVE_TASKS.fetch_sub(1, Ordering::Relaxed);
+    }
+    POLLS[cpu].fetch_add(1, Ordering::Relaxed);
+    true
+}
+
+/// `cpu`のキューが空ならtrue
+pub(super) fn is_empty(cpu: usize) -> bool {
+    interrupts::without_interrupts(|| QUEUES[cpu].lock().is_empty())
+}
+
+/// どのCPUのキューにも，盗めるタスクがなければtrue
+pub(super) fn all_empty() -> bool {
+    (0..MAX_CPUS).all(is_empty)
+}
+
+fn pop(cpu: usize) -> Option<Arc<SharedTask>> {
+    interrupts::without_interrupts(|| QUEUES[cpu].lock().pop_front())
+}
+
+/// 他のCPUのキューの後ろ半分を自分のキューに移し，その先頭を返す
+fn steal(cpu: usize) -> Option<Arc<SharedTask>> {
+    for victim in (1..MAX_CPUS).map(|offset| (cpu + offset) % MAX_CPUS) {
+        let mut stolen = interrupts::without_interrupts(|| {
+            let mut queue = QUEUES[victim].lock();
+            let keep = queue.len() / 2;
+            queue.split_off(keep)
+        });
+        if let Some(task) = stolen.pop_front() {
+            STOLEN[cpu].fetch_add(stolen.len() as u64 + 1, Ordering::Relaxed);
+            interrupts::without_interrupts(|| QUEUES[cpu].lock().extend(stolen));
+            return Some(task);
+        }
+    }
+    None
+}
+
+/// `spawn_shared`したタスクのうち，終了していないものの数
+pub fn live_shared_tasks() -> usize {
+    LIVE_TASKS.load(Ordering::Relaxed)
+}
+
+/// CPUごとの統計
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CpuStats {
+    pub polls: u64,
+    pub stolen: u64,
+}
+
+pub fn cpu_stats(cpu: usize) -> CpuStats {
+    CpuStats {
+        polls: POLLS[cpu].load(Ordering::Relaxed),
+        stolen: STOLEN[cpu].load(Ordering::Relaxed),
+    }
+}
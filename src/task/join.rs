@@ -0,0 +1,155 @@
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+use super::Task;
+
+/// タスクの結果が得られなかった理由
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JoinError {
+    /// `JoinHandle::abort`で中断された
+    Cancelled,
+}
+
+enum Outcome<T> {
+    Running,
+    Finished(T),
+    Cancelled,
+    /// JoinHandleが結果を受け取った後
+    Taken,
+}
+
+struct JoinState<T> {
+    outcome: Outcome<T>,
+    // 結果を待っているタスクのWaker
+    joiner: Option<Waker>,
+    // abortしたときに，タスク自身を起こすためのWaker
+    task_waker: Option<Waker>,
+}
+
+/// spawnしたタスクの結果を待つfuture
+///
+/// ドロップしてもタスクは止まらない（デタッチされる）
+pub struct JoinHandle<T> {
+    state: Arc<Mutex<JoinState<T>>>,
+}
+
+impl<T> JoinHandle<T> {
+    /// タスクを中断する
+    ///
+    /// JoinHandleはすぐに`JoinError::Cancelled`を返すようになる
+    /// タスクは起こされ，Executorがfutureをポーリングせずに取り除く
+    /// すでに終了していれば何もしない
+    pub fn abort(&self) {
+        let (joiner, task_waker) = {
+            let mut state = self.state.lock();
+            if !matches!(state.outcome, Outcome::Running) {
+                return;
+            }
+            state.outcome = Outcome::Cancelled;
+            (state.joiner.take(), state.task_waker.take())
+        };
+        // ロックを持ったままwakeしない
+        if let Some(waker) = joiner {
+            waker.wake();
+        }
+        if let Some(waker) = task_waker {
+            waker.wake();
+        }
+    }
+
+    /// タスクが終了したか，中断されたかどうか
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state.lock().outcome, Outcome::Running)
+    }
+}
+
+impl<T> Future for JoinHandle<T> {
+    type Output = Result<T, JoinError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.state.lock();
+        match core::mem::replace(&mut state.outcome, Outcome::Taken) {
+            Outcome::Finished(output) => Poll::Ready(Ok(output)),
+            Outcome::Cancelled => Poll::Ready(Err(JoinError::Cancelled)),
+            Outcome::Running => {
+                state.outcome = Outcome::Running;
+                state.joiner = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            Outcome::Taken => panic!("JoinHandle polled after completion"),
+        }
+    }
+}
+
+/// 結果をJoinStateに書き込み，abortされていれば中断するfuture
+struct Joinable<F: Future> {
+    future: Pin<alloc::boxed::Box<F>>,
+    state: Arc<Mutex<JoinState<F::Output>>>,
+}
+
+impl<F: Future> Joinable<F> {
+    fn finish(&self, output: F::Output) -> Poll<()> {
+        let joiner = {
+            let mut state = self.state.lock();
+            state.task_waker = None;
+            // ポーリング中にabortされていたら，結果は捨てる
+            if !matches!(state.outcome, Outcome::Running) {
+                return Poll::Ready(());
+            }
+            state.outcome = Outcome::Finished(output);
+            state.joiner.take()
+        };
+        if let Some(waker) = joiner {
+            waker.wake();
+        }
+        Poll::Ready(())
+    }
+}
+
+impl<F: Future> Future for Joinable<F> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        // フィールドはすべてUnpinなので，Pinを外してよい
+        let this = self.get_mut();
+        {
+            let mut state = this.state.lock();
+            if !matches!(state.outcome, Outcome::Running) {
+                // abortされた．Readyを返せばExecutorがタスクごとfutureをドロップする
+                return Poll::Ready(());
+            }
+            state.task_waker = Some(cx.waker().clone());
+        }
+
+        match this.future.as_mut().poll(cx) {
+            Poll::Ready(output) => this.finish(output),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Task {
+    /// 任意の出力を持つfutureからタスクを作り，その結果を待つJoinHandleも返す
+    pub fn with_join_handle<F>(future: F) -> (Task, JoinHandle<F::Output>)
+    where
+        F: Future + 'static,
+    {
+        let state = Arc::new(Mutex::new(JoinState {
+            outcome: Outcome::Running,
+            joiner: None,
+            task_waker: None,
+        }));
+        let task = Task::new(Joinable {
+            future: alloc::boxed::Box::pin(future),
+            state: state.clone(),
+        })
+        // Joinableではなく，元のfutureの型名を名前にする
+        .with_name(core::any::type_name::<F>());
+        (task, JoinHandle { state })
+    }
+}
@@ -1,49 +1,303 @@
+pub mod event;
+pub mod hotkey;
 pub mod introduction;
+pub mod layout;
+pub mod line_editor;
+pub mod service;
 
+use alloc::sync::Arc;
 use conquer_once::spin::OnceCell;
 use core::{
     pin::Pin,
+    sync::atomic::{AtomicU64, Ordering},
     task::{Context, Poll},
+    time::Duration,
 };
 use crossbeam_queue::ArrayQueue;
+use futures_util::future;
 use futures_util::stream::{Stream, StreamExt};
 use futures_util::task::AtomicWaker;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use lazy_static::lazy_static;
+use pc_keyboard::DecodedKey;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use event::KeyEvent;
+use layout::Layout;
+use service::KeyboardService;
 
 use crate::{print, println};
 
+/// 同時に購読できるストリームの数
+pub const MAX_SUBSCRIBERS: usize = 8;
+/// 割り込みハンドラが入れるキューの，標準の長さ
+pub const DEFAULT_SCANCODE_QUEUE_SIZE: usize = 100;
+/// 購読者ごとのキューの，標準の長さ
+pub const DEFAULT_SUBSCRIBER_QUEUE_SIZE: usize = 100;
+/// 取りこぼしの警告を出す間隔の最小値
+const WARNING_INTERVAL: Duration = Duration::from_secs(1);
+
 static SCANCODE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
-static WAKER: AtomicWaker = AtomicWaker::new();
+
+// 割り込みハンドラからも触るので，アロケートせずに済む固定長の配列にする
+#[allow(clippy::declare_interior_mutable_const)]
+const NEW_WAKER: AtomicWaker = AtomicWaker::new();
+static WAKERS: [AtomicWaker; MAX_SUBSCRIBERS] = [NEW_WAKER; MAX_SUBSCRIBERS];
+
+// 割り込みハンドラで画面に書くと，WRITERを持っている処理に割り込んだときにデッドロックする
+// 取りこぼした数だけ数えておき，警告はタスクから出す
+static DROPPED_QUEUE_FULL: AtomicU64 = AtomicU64::new(0);
+static DROPPED_UNINITIALIZED: AtomicU64 = AtomicU64::new(0);
+static DROPPED_SUBSCRIBER_FULL: AtomicU64 = AtomicU64::new(0);
+static DROP_WAKER: AtomicWaker = AtomicWaker::new();
+
+/// 取りこぼしたキーボード入力の数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct DropStats {
+    /// スキャンコードキューがいっぱいだった
+    pub queue_full: u64,
+    /// スキャンコードキューが作られる前だった
+    pub uninitialized: u64,
+    /// 読まれずにいっぱいになった購読者の分
+    pub subscriber_full: u64,
+}
+
+impl DropStats {
+    pub fn total(&self) -> u64 {
+        self.queue_full + self.uninitialized + self.subscriber_full
+    }
+}
+
+pub fn drop_stats() -> DropStats {
+    DropStats {
+        queue_full: DROPPED_QUEUE_FULL.load(Ordering::Relaxed),
+        uninitialized: DROPPED_UNINITIALIZED.load(Ordering::Relaxed),
+        subscriber_full: DROPPED_SUBSCRIBER_FULL.load(Ordering::Relaxed),
+    }
+}
+
+fn count_drop(counter: &AtomicU64) {
+    counter.fetch_add(1, Ordering::Relaxed);
+    DROP_WAKER.wake();
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    /// 最初の購読者が作られたか，もう設定されている
+    AlreadyInitialized,
+    ZeroCapacity,
+    /// もう`MAX_SUBSCRIBERS`個のストリームがある
+    TooManySubscribers,
+}
+
+/// スキャンコードキューの長さを決める
+///
+/// 最初のストリームを作る前に呼ぶ．呼ばなければ`DEFAULT_SCANCODE_QUEUE_SIZE`になる
+pub fn init_scancode_queue(capacity: usize) -> Result<(), QueueError> {
+    if capacity == 0 {
+        return Err(QueueError::ZeroCapacity);
+    }
+    SCANCODE_QUEUE
+        .try_init_once(|| ArrayQueue::new(capacity))
+        .map_err(|_| QueueError::AlreadyInitialized)
+}
 
 /// キーボード割り込みハンドラから呼び出される
 ///
 /// 処理をブロックしたり，アロケートしてはいけない
 pub(crate) fn add_scancode(scancode: u8) {
     if let Ok(queue) = SCANCODE_QUEUE.try_get() {
-        if let Err(_) = queue.push(scancode) {
+        if queue.push(scancode).is_err() {
             //  スキャンコードキューがいっぱいでキーボード入力を取りこぼしている
-            println!("WARNING: scancode queue full; dropping keyboard input");
+            count_drop(&DROPPED_QUEUE_FULL);
         } else {
-            WAKER.wake();
+            // どの購読者が配るかわからないので，全員を起こす
+            for waker in WAKERS.iter() {
+                waker.wake();
+            }
         }
     } else {
         //  スキャンコードキューが初期化されていない
-        println!("WARNING: scancode queue uninitialized");
+        count_drop(&DROPPED_UNINITIALIZED);
+    }
+}
+
+/// スキャンコードを，キーボード割り込みから届いたものとして入れる
+///
+/// 人がキーを押さなくても入力を再現できるので，テストに使う．
+/// 今のセット(`ps2::scancode_set`)のスキャンコードを渡す
+pub fn inject_scancodes(scancodes: &[u8]) {
+    for &scancode in scancodes {
+        add_scancode(scancode);
+    }
+}
+
+/// 取りこぼしが増えたら警告を出し続ける
+///
+/// 警告は`WARNING_INTERVAL`に1回までにまとめる
+pub async fn report_dropped_input() {
+    let mut reported = drop_stats();
+    loop {
+        future::poll_fn(|cx| {
+            DROP_WAKER.register(cx.waker());
+            if drop_stats() == reported {
+                Poll::Pending
+            } else {
+                Poll::Ready(())
+            }
+        })
+        .await;
+
+        let stats = drop_stats();
+        println!(
+            "WARNING: dropped {} keyboard input(s) (queue full: {}, uninitialized: {}, subscriber full: {})",
+            stats.total() - reported.total(),
+            stats.queue_full - reported.queue_full,
+            stats.uninitialized - reported.uninitialized,
+            stats.subscriber_full - reported.subscriber_full,
+        );
+        reported = stats;
+        super::timer::sleep(WARNING_INTERVAL).await;
+    }
+}
+
+/// 購読者ごとの受け取り口
+enum Mailbox {
+    Scancodes(Arc<ArrayQueue<u8>>),
+    Events(Arc<ArrayQueue<KeyEvent>>),
+}
+
+/// 割り込みハンドラが入れたスキャンコードを，購読者に配る
+///
+/// デコーダは全員で1つを使うので，修飾キーの状態が購読者ごとにずれない
+struct Hub {
+    keyboard: KeyboardService,
+    mailboxes: [Option<Mailbox>; MAX_SUBSCRIBERS],
+    /// まだキーボードに送っていないLEDの設定
+    ///
+    /// コマンドの応答を待つ間HUBを持ち続けないように，ロックを離してから送る
+    pending_leds: Option<u8>,
+    /// まだ画面に表示していない，切り替えキーで変わったレイアウト
+    ///
+    /// 画面のロックを取るので，これもHUBのロックを離してから表示する
+    pending_layout: Option<Layout>,
+}
+
+lazy_static! {
+    static ref HUB: Mutex<Hub> = {
+        let _ = init_scancode_queue(DEFAULT_SCANCODE_QUEUE_SIZE);
+        const EMPTY: Option<Mailbox> = None;
+        let keyboard = KeyboardService::new();
+        let pending_leds = Some(keyboard.locks().leds());
+        Mutex::new(Hub {
+            keyboard,
+            mailboxes: [EMPTY; MAX_SUBSCRIBERS],
+            pending_leds,
+            pending_layout: None,
+        })
+    };
+}
+
+impl Hub {
+    /// 空いている番号に登録する
+    fn subscribe(&mut self, mailbox: Mailbox) -> Result<usize, QueueError> {
+        let slot = self
+            .mailboxes
+            .iter()
+            .position(Option::is_none)
+            .ok_or(QueueError::TooManySubscribers)?;
+        self.mailboxes[slot] = Some(mailbox);
+        Ok(slot)
+    }
+
+    fn unsubscribe(&mut self, slot: usize) {
+        self.mailboxes[slot] = None;
+        WAKERS[slot].take();
+    }
+
+    /// たまっているスキャンコードをデコードして配る
+    fn dispatch(&mut self) {
+        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+        while let Ok(scancode) = queue.pop() {
+            let locks = self.keyboard.locks();
+            let event = self.keyboard.process_key_event(scancode);
+            if self.keyboard.locks() != locks {
+                self.pending_leds = Some(self.keyboard.locks().leds());
+            }
+            if let Some(layout) = self.keyboard.take_layout_switch() {
+                self.pending_layout = Some(layout);
+            }
+            for (slot, mailbox) in self.mailboxes.iter().enumerate() {
+                let delivered = match (mailbox, event) {
+                    (Some(Mailbox::Scancodes(queue)), _) => Some(queue.push(scancode).is_ok()),
+                    (Some(Mailbox::Events(queue)), Some(event)) => Some(queue.push(event).is_ok()),
+                    _ => None,
+                };
+                match delivered {
+                    Some(true) => WAKERS[slot].wake(),
+                    // 読まれずにいっぱいになった購読者の分は捨てる
+                    Some(false) => count_drop(&DROPPED_SUBSCRIBER_FULL),
+                    None => {}
+                }
+            }
+        }
     }
 }
 
+fn with_hub<R>(f: impl FnOnce(&mut Hub) -> R) -> R {
+    interrupts::without_interrupts(|| f(&mut HUB.lock()))
+}
+
+/// 自分の受け取り口から1つ取り出す．なければ配ってから待つ
+fn poll_mailbox<T>(slot: usize, queue: &ArrayQueue<T>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+    if let Ok(item) = queue.pop() {
+        return Poll::Ready(Some(item));
+    }
+
+    // 配っている間に割り込みが入っても取りこぼさないように，先に登録する
+    WAKERS[slot].register(cx.waker());
+    let (leds, switched_layout) = with_hub(|hub| {
+        hub.dispatch();
+        (hub.pending_leds.take(), hub.pending_layout.take())
+    });
+    if let Some(leds) = leds {
+        // キーボードがなくてもスキャンコードは流せるので，失敗しても続ける
+        let _ = crate::ps2::set_leds(leds);
+    }
+    if let Some(switched) = switched_layout {
+        layout::show_indicator(switched);
+    }
+    match queue.pop() {
+        Ok(item) => {
+            WAKERS[slot].take();
+            Poll::Ready(Some(item))
+        }
+        Err(crossbeam_queue::PopError) => Poll::Pending,
+    }
+}
+
+/// 生のスキャンコードを受け取るストリーム
+///
+/// いくつでも作れるが，同時に存在できるのは他のストリームと合わせて`MAX_SUBSCRIBERS`個まで．
+/// それより多く作ろうとすると`QueueError::TooManySubscribers`になる
 pub struct ScancodeStream {
-    // モジュールの外部から構造体を構築できないようにするため
-    _private: (),
+    slot: usize,
+    queue: Arc<ArrayQueue<u8>>,
 }
 
 impl ScancodeStream {
-    pub fn new() -> Self {
-        SCANCODE_QUEUE
-            .try_init_once(|| ArrayQueue::new(100))
-            .expect("ScancodeStream::new should only be called once");
+    pub fn new() -> Result<Self, QueueError> {
+        Self::with_capacity(DEFAULT_SUBSCRIBER_QUEUE_SIZE)
+    }
 
-        Self { _private: () }
+    /// 読まずにためておける数を指定して作る
+    pub fn with_capacity(capacity: usize) -> Result<Self, QueueError> {
+        if capacity == 0 {
+            return Err(QueueError::ZeroCapacity);
+        }
+        let queue = Arc::new(ArrayQueue::new(capacity));
+        let slot = with_hub(|hub| hub.subscribe(Mailbox::Scancodes(queue.clone())))?;
+        Ok(Self { slot, queue })
     }
 }
 
@@ -51,41 +305,78 @@ impl Stream for ScancodeStream {
     type Item = u8;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let queue = SCANCODE_QUEUE.try_get().expect("not initialized");
+        poll_mailbox(self.slot, &self.queue, cx)
+    }
+}
 
-        // 成功しない場合，キューが空であるかもしれない
-        // 割り込みハンドラがチェックの直後に非同期的にキューを満たした可能性もある
-        if let Ok(scancode) = queue.pop() {
-            return Poll::Ready(Some(scancode));
-        }
+impl Drop for ScancodeStream {
+    fn drop(&mut self) {
+        with_hub(|hub| hub.unsubscribe(self.slot));
+    }
+}
 
-        WAKER.register(&cx.waker());
-        match queue.pop() {
-            Ok(scancode) => {
-                WAKER.take();
-                Poll::Ready(Some(scancode))
-            }
-            Err(crossbeam_queue::PopError) => Poll::Pending,
+/// デコード済みのキーイベントを受け取るストリーム
+///
+/// 押下と解放の両方が届く．購読する前のイベントは届かない
+pub struct KeyEventStream {
+    slot: usize,
+    queue: Arc<ArrayQueue<KeyEvent>>,
+}
+
+impl KeyEventStream {
+    pub fn new() -> Result<Self, QueueError> {
+        Self::with_capacity(DEFAULT_SUBSCRIBER_QUEUE_SIZE)
+    }
+
+    /// 読まずにためておける数を指定して作る
+    pub fn with_capacity(capacity: usize) -> Result<Self, QueueError> {
+        if capacity == 0 {
+            return Err(QueueError::ZeroCapacity);
         }
+        let queue = Arc::new(ArrayQueue::new(capacity));
+        let slot = with_hub(|hub| hub.subscribe(Mailbox::Events(queue.clone())))?;
+        Ok(Self { slot, queue })
+    }
+}
+
+impl Stream for KeyEventStream {
+    type Item = KeyEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        poll_mailbox(self.slot, &self.queue, cx)
+    }
+}
+
+impl Drop for KeyEventStream {
+    fn drop(&mut self) {
+        with_hub(|hub| hub.unsubscribe(self.slot));
     }
 }
 
+/// 今の購読者の数
+pub fn subscriber_count() -> usize {
+    with_hub(|hub| {
+        hub.mailboxes
+            .iter()
+            .filter(|mailbox| mailbox.is_some())
+            .count()
+    })
+}
+
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
-
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => print!("{}", character),
-                    DecodedKey::RawKey(key) => print!("{:?}", key),
-                }
-            }
+    let mut events = match KeyEventStream::new() {
+        Ok(events) => events,
+        Err(err) => {
+            println!("keyboard: cannot subscribe: {:?}", err);
+            return;
+        }
+    };
+
+    while let Some(event) = events.next().await {
+        match event.key {
+            Some(DecodedKey::Unicode(character)) => print!("{}", character),
+            Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+            None => {}
         }
     }
 }
@@ -0,0 +1,144 @@
+use core::ops::{BitOr, BitOrAssign};
+use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+/// 押されている修飾キー
+///
+/// 左右どちらのキーでも同じビットになる
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers(u8);
+
+impl Modifiers {
+    pub const NONE: Modifiers = Modifiers(0);
+    pub const SHIFT: Modifiers = Modifiers(1 << 0);
+    pub const CTRL: Modifiers = Modifiers(1 << 1);
+    pub const ALT: Modifiers = Modifiers(1 << 2);
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// `other`のビットがすべて立っていればtrue
+    pub const fn contains(self, other: Modifiers) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Modifiers) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Modifiers) {
+        self.0 &= !other.0;
+    }
+
+    /// 修飾キーなら，そのビットを返す
+    pub fn from_key_code(code: KeyCode) -> Option<Modifiers> {
+        match code {
+            KeyCode::LShift | KeyCode::RShift => Some(Modifiers::SHIFT),
+            KeyCode::LControl | KeyCode::RControl => Some(Modifiers::CTRL),
+            KeyCode::LAlt | KeyCode::RAltGr => Some(Modifiers::ALT),
+            _ => None,
+        }
+    }
+
+    /// キーの押下，解放に合わせて更新する
+    pub(crate) fn update(&mut self, code: KeyCode, state: KeyState) {
+        if let Some(modifier) = Modifiers::from_key_code(code) {
+            match state {
+                KeyState::Down => self.insert(modifier),
+                KeyState::Up => self.remove(modifier),
+                _ => {}
+            }
+        }
+    }
+}
+
+impl BitOr for Modifiers {
+    type Output = Modifiers;
+
+    fn bitor(self, rhs: Modifiers) -> Modifiers {
+        Modifiers(self.0 | rhs.0)
+    }
+}
+
+impl BitOrAssign for Modifiers {
+    fn bitor_assign(&mut self, rhs: Modifiers) {
+        self.insert(rhs);
+    }
+}
+
+/// Caps/Num/Scroll Lockの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockState {
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl LockState {
+    /// ロックキーなら状態を反転する．変わったらtrue
+    pub(crate) fn update(&mut self, code: KeyCode, state: KeyState) -> bool {
+        if state != KeyState::Down {
+            return false;
+        }
+        let lock = match code {
+            KeyCode::CapsLock => &mut self.caps_lock,
+            KeyCode::NumpadLock => &mut self.num_lock,
+            KeyCode::ScrollLock => &mut self.scroll_lock,
+            _ => return false,
+        };
+        *lock = !*lock;
+        true
+    }
+
+    /// PS/2キーボードのLEDの設定値
+    pub fn leds(self) -> u8 {
+        let mut leds = 0;
+        if self.caps_lock {
+            leds |= crate::ps2::LED_CAPS_LOCK;
+        }
+        if self.num_lock {
+            leds |= crate::ps2::LED_NUM_LOCK;
+        }
+        if self.scroll_lock {
+            leds |= crate::ps2::LED_SCROLL_LOCK;
+        }
+        leds
+    }
+}
+
+impl Default for LockState {
+    /// pc_keyboardのデコーダに合わせて，Num Lockだけ有効
+    fn default() -> Self {
+        Self {
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        }
+    }
+}
+
+/// デコード済みのキーイベント
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    /// 物理的なキー
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// 今のレイアウトで解釈したキー．押したときだけ入る
+    pub key: Option<DecodedKey>,
+    /// このイベントを処理した後の修飾キーの状態
+    pub modifiers: Modifiers,
+}
+
+impl KeyEvent {
+    pub fn is_press(&self) -> bool {
+        self.state == KeyState::Down
+    }
+
+    pub fn is_release(&self) -> bool {
+        self.state == KeyState::Up
+    }
+}
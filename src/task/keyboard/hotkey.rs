@@ -0,0 +1,149 @@
+use alloc::{string::String, vec::Vec};
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+use super::event::{KeyEvent, Modifiers};
+use super::service::SWITCH_LAYOUT_KEY;
+
+/// 組み合わせの中心になるキー
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    /// レイアウトで解釈した後の文字．Shiftは文字に含まれる
+    Char(char),
+    /// 物理的なキー．F1〜F12や矢印キーなど
+    Code(KeyCode),
+}
+
+/// キーと修飾キーの組み合わせ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyCombo {
+    pub key: Key,
+    pub modifiers: Modifiers,
+}
+
+impl KeyCombo {
+    pub const fn char(character: char) -> Self {
+        Self {
+            key: Key::Char(character),
+            modifiers: Modifiers::NONE,
+        }
+    }
+
+    pub const fn code(code: KeyCode) -> Self {
+        Self {
+            key: Key::Code(code),
+            modifiers: Modifiers::NONE,
+        }
+    }
+
+    pub const fn with(self, modifiers: Modifiers) -> Self {
+        Self {
+            key: self.key,
+            modifiers,
+        }
+    }
+
+    /// キーが押されたイベントがこの組み合わせならtrue
+    pub fn matches(&self, event: &KeyEvent) -> bool {
+        if !event.is_press() {
+            return false;
+        }
+        match self.key {
+            Key::Char(character) => {
+                // Shiftは文字のほうに反映されているので比べない
+                let mut modifiers = event.modifiers;
+                modifiers.remove(Modifiers::SHIFT);
+                event.key == Some(DecodedKey::Unicode(character)) && modifiers == self.modifiers
+            }
+            Key::Code(code) => event.code == code && event.modifiers == self.modifiers,
+        }
+    }
+}
+
+/// 組み合わせが押されたときにすること
+#[derive(Debug, Clone)]
+pub enum Action {
+    Call(fn()),
+    /// シェルに，この行を入力したものとして渡す
+    Command(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BindError {
+    /// KeyboardServiceが先に処理するので，割り当てても届かない
+    ReservedKey(KeyCode),
+}
+
+/// 組み合わせから動作を引く表
+///
+/// 同じ組み合わせは1つしか登録できない
+#[derive(Debug, Clone, Default)]
+pub struct HotkeyRegistry {
+    bindings: Vec<(KeyCombo, Action)>,
+}
+
+impl HotkeyRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 同じ組み合わせがあれば置き換えて，前の動作を返す
+    ///
+    /// レイアウトの切り替えキーは，修飾キーによらず割り当てられない
+    pub fn bind(&mut self, combo: KeyCombo, action: Action) -> Result<Option<Action>, BindError> {
+        if combo.key == Key::Code(SWITCH_LAYOUT_KEY) {
+            return Err(BindError::ReservedKey(SWITCH_LAYOUT_KEY));
+        }
+        match self.bindings.iter_mut().find(|(bound, _)| *bound == combo) {
+            Some((_, bound_action)) => Ok(Some(core::mem::replace(bound_action, action))),
+            None => {
+                self.bindings.push((combo, action));
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn unbind(&mut self, combo: KeyCombo) -> Option<Action> {
+        let index = self
+            .bindings
+            .iter()
+            .position(|(bound, _)| *bound == combo)?;
+        Some(self.bindings.remove(index).1)
+    }
+
+    /// イベントに合う動作を返す
+    pub fn lookup(&self, event: &KeyEvent) -> Option<&Action> {
+        self.bindings
+            .iter()
+            .find(|(combo, _)| combo.matches(event))
+            .map(|(_, action)| action)
+    }
+
+    /// 登録した順に返す
+    pub fn iter(&self) -> impl Iterator<Item = &(KeyCombo, Action)> {
+        self.bindings.iter()
+    }
+}
+
+lazy_static! {
+    /// どの画面でも効くショートカット．シェルの入力中にも使える
+    static ref GLOBAL: Mutex<HotkeyRegistry> = Mutex::new(HotkeyRegistry::new());
+}
+
+/// 全体のショートカットを登録する
+pub fn bind(combo: KeyCombo, action: Action) -> Result<Option<Action>, BindError> {
+    interrupts::without_interrupts(|| GLOBAL.lock().bind(combo, action))
+}
+
+pub fn unbind(combo: KeyCombo) -> Option<Action> {
+    interrupts::without_interrupts(|| GLOBAL.lock().unbind(combo))
+}
+
+/// イベントに合う全体のショートカットを返す
+///
+/// 動作の中から登録し直せるように，複製して返す
+pub fn lookup(event: &KeyEvent) -> Option<Action> {
+    interrupts::without_interrupts(|| GLOBAL.lock().lookup(event).cloned())
+}
@@ -1,52 +1,215 @@
 use alloc::format;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::time::Duration;
 use futures_util::StreamExt;
-use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
 
-use crate::print;
+use crate::task::mouse::{Cursor, MouseButtons, MouseStream};
+use crate::task::timer::{timeout, Elapsed};
+use crate::vga_buffer;
 use crate::vga_buffer::colored_letter::{color_print, ColoredString};
 use crate::vga_buffer::{Color, ColorCode};
+use crate::{print, println};
 
-use super::ScancodeStream;
+use super::event::KeyEvent;
+use super::hotkey::{self, Action, HotkeyRegistry, KeyCombo};
+use super::KeyEventStream;
+
+/// この時間キー入力がなければ，アイコンのカードを表示し直す
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
 
 pub async fn print_keypresses() {
-    let mut scancodes = ScancodeStream::new();
-    let mut keyboard = Keyboard::new(
-        ScancodeSet1::new(),
-        layouts::Us104Key,
-        HandleControl::Ignore,
-    );
+    let mut events = match KeyEventStream::new() {
+        Ok(events) => events,
+        Err(err) => {
+            println!("introduction: cannot subscribe to the keyboard: {:?}", err);
+            return;
+        }
+    };
 
-    while let Some(scancode) = scancodes.next().await {
-        if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-            if let Some(key) = keyboard.process_keyevent(key_event) {
-                match key {
-                    DecodedKey::Unicode(character) => introductions_by_char(character),
-                    DecodedKey::RawKey(key) => introductions_by_keycode(key),
+    // 最後に表示したのがアイコンのカードならtrue
+    let mut showing_icon = true;
+
+    loop {
+        let event = match timeout(IDLE_TIMEOUT, events.next()).await {
+            Ok(Some(event)) => event,
+            Ok(None) => break,
+            // しばらく入力がなければ，最初のカードに戻る
+            Err(Elapsed) => {
+                if !showing_icon {
+                    print!("\n");
+                    introduction_icon();
+                    showing_icon = true;
                 }
+                continue;
             }
+        };
+        showing_icon = false;
+        match card_action(&event) {
+            Some(Action::Call(action)) => action(),
+            // シェルが動いていないのでコマンドは実行できない．割り当てのないキーと同じく表示する
+            Some(Action::Command(_)) | None => match event.key {
+                Some(DecodedKey::Unicode(character)) => print!("{}", character),
+                Some(DecodedKey::RawKey(key)) => print!("{:?}", key),
+                None => {}
+            },
+        }
+    }
+}
+
+lazy_static! {
+    /// カードを表示しているときのキー割り当て．実行中に変えられる
+    pub static ref CARD_HOTKEYS: Mutex<HotkeyRegistry> = Mutex::new(default_card_hotkeys());
+}
+
+/// 凡例の文字で各項目を，Escでアイコンを，左右の矢印で前後の項目を表示する
+///
+/// レイアウトの切り替えキーは使わないので，登録は失敗しない
+fn default_card_hotkeys() -> HotkeyRegistry {
+    let mut registry = HotkeyRegistry::new();
+    for &(key, section) in LEGEND {
+        if let Some(&(_, show)) = SECTIONS.iter().find(|(name, _)| *name == section) {
+            let _ = registry.bind(KeyCombo::char(key), Action::Call(show));
         }
     }
+    let _ = registry.bind(
+        KeyCombo::code(KeyCode::Escape),
+        Action::Call(introduction_icon),
+    );
+    let _ = registry.bind(
+        KeyCombo::code(KeyCode::ArrowRight),
+        Action::Call(next_section),
+    );
+    let _ = registry.bind(
+        KeyCombo::code(KeyCode::ArrowLeft),
+        Action::Call(previous_section),
+    );
+    registry
 }
 
-fn introductions_by_char(char: char) {
-    match char {
-        'i' => introduction_icon(),
-        'n' => introduction_name(),
-        'a' => introduction_age(),
-        'l' => introduction_language(),
-        'u' => introduction_university(),
-        'm' => introduction_major(),
-        'g' => introduction_grade(),
-        'p' => introduction_programing_language(),
-        'q' => introduction_qualification(),
-        _ => print!("{}", char),
+/// 全体のショートカットを優先し，なければカードのキー割り当てから探す
+fn card_action(event: &KeyEvent) -> Option<Action> {
+    hotkey::lookup(event)
+        .or_else(|| interrupts::without_interrupts(|| CARD_HOTKEYS.lock().lookup(event).cloned()))
+}
+
+/// 自己紹介カードの項目．シェルの`profile`コマンドから表示する
+pub const SECTIONS: &[(&str, fn())] = &[
+    ("icon", introduction_icon),
+    ("name", introduction_name),
+    ("age", introduction_age),
+    ("language", introduction_language),
+    ("university", introduction_university),
+    ("major", introduction_major),
+    ("grade", introduction_grade),
+    ("programing", introduction_programing_language),
+    ("qualification", introduction_qualification),
+];
+
+/// 矢印キーで移動するときの，今の項目の位置
+static CURRENT_SECTION: AtomicUsize = AtomicUsize::new(0);
+
+/// 名前が`name`の項目を表示する．なければfalse
+pub fn show_section(name: &str) -> bool {
+    match SECTIONS.iter().position(|(section, _)| *section == name) {
+        Some(index) => {
+            show_section_at(index);
+            true
+        }
+        None => false,
     }
 }
 
-fn introductions_by_keycode(keycode: KeyCode) {
-    match keycode {
-        KeyCode::Escape => introduction_icon(),
-        _ => print!("{:?}", keycode),
+fn show_section_at(index: usize) {
+    CURRENT_SECTION.store(index, Ordering::Relaxed);
+    (SECTIONS[index].1)();
+}
+
+fn next_section() {
+    let current = CURRENT_SECTION.load(Ordering::Relaxed);
+    show_section_at((current + 1) % SECTIONS.len());
+}
+
+fn previous_section() {
+    let current = CURRENT_SECTION.load(Ordering::Relaxed);
+    show_section_at((current + SECTIONS.len() - 1) % SECTIONS.len());
+}
+
+/// アイコンのカードに書いてある`キー: 項目`の凡例と，対応する項目
+const LEGEND: &[(char, &str)] = &[
+    ('i', "icon"),
+    ('n', "name"),
+    ('a', "age"),
+    ('l', "language"),
+    ('u', "university"),
+    ('m', "major"),
+    ('q', "qualification"),
+    ('g', "grade"),
+    ('p', "programing"),
+];
+
+/// 画面の行`line`の`column`列目が凡例の上なら，その項目の名前を返す
+///
+/// 凡例は`n: name, a: age`のように書かれていて，次の凡例の手前までを1つとみなす
+pub fn section_at(line: &str, column: usize) -> Option<&'static str> {
+    let bytes = line.as_bytes();
+    let starts = (0..bytes.len().saturating_sub(2)).filter(|&i| {
+        bytes[i].is_ascii_alphabetic()
+            && bytes[i + 1] == b':'
+            && bytes[i + 2] == b' '
+            && (i == 0 || !bytes[i - 1].is_ascii_alphanumeric())
+    });
+    let mut found = None;
+    for start in starts {
+        if start > column {
+            break;
+        }
+        found = Some(start);
+    }
+    let start = found?;
+    // 凡例の後ろの区切りや空白の上は含めない
+    let end = line[start..]
+        .find(',')
+        .map_or(line.trim_end().len(), |offset| start + offset);
+    if column >= end {
+        return None;
+    }
+    let key = char::from(bytes[start]);
+    LEGEND
+        .iter()
+        .find(|(legend_key, _)| *legend_key == key)
+        .map(|(_, section)| *section)
+}
+
+/// マウスカーソルを動かし，カードの凡例をクリックしたらその項目を表示する
+pub async fn click_sections() {
+    let mut mice = match MouseStream::new() {
+        Ok(mice) => mice,
+        Err(err) => {
+            println!("introduction: cannot read the mouse: {:?}", err);
+            return;
+        }
+    };
+    let mut cursor = Cursor::new();
+    let mut buttons = MouseButtons::NONE;
+    vga_buffer::set_mouse_cursor(Some(cursor.position()));
+
+    while let Some(event) = mice.next().await {
+        cursor.apply(&event);
+        let (row, column) = cursor.position();
+        vga_buffer::set_mouse_cursor(Some((row, column)));
+
+        let clicked =
+            event.buttons.contains(MouseButtons::LEFT) && !buttons.contains(MouseButtons::LEFT);
+        buttons = event.buttons;
+        if clicked {
+            if let Some(section) = section_at(&vga_buffer::read_row(row), column) {
+                show_section(section);
+            }
+        }
     }
 }
 
@@ -0,0 +1,73 @@
+use alloc::format;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+use crate::vga_buffer::{self, Color, ColorCode, BUFFER_WIDTH};
+
+/// 使えるキーボードレイアウト
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[repr(u8)]
+pub enum Layout {
+    #[default]
+    Us104 = 0,
+    Jis109 = 1,
+    Uk105 = 2,
+    De105 = 3,
+}
+
+impl Layout {
+    /// 切り替えキーで回る順
+    pub const ALL: [Layout; 4] = [Layout::Us104, Layout::Jis109, Layout::Uk105, Layout::De105];
+
+    /// 画面に表示する短い名前
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "US",
+            Layout::Jis109 => "JIS",
+            Layout::Uk105 => "UK",
+            Layout::De105 => "DE",
+        }
+    }
+
+    /// 切り替えキーを押したときの次のレイアウト
+    pub fn next(self) -> Layout {
+        Layout::ALL[(self as usize + 1) % Layout::ALL.len()]
+    }
+
+    fn from_u8(value: u8) -> Layout {
+        Layout::ALL
+            .iter()
+            .copied()
+            .find(|layout| *layout as u8 == value)
+            .unwrap_or(Layout::Us104)
+    }
+}
+
+static ACTIVE_LAYOUT: AtomicU8 = AtomicU8::new(Layout::Us104 as u8);
+
+/// 今使っているレイアウト
+pub fn active_layout() -> Layout {
+    Layout::from_u8(ACTIVE_LAYOUT.load(Ordering::Relaxed))
+}
+
+/// レイアウトを切り替え，画面右上の表示を更新する
+///
+/// KeyboardServiceは次のスキャンコードから新しいレイアウトで解釈する
+pub fn set_layout(layout: Layout) {
+    set_active_layout(layout);
+    show_indicator(layout);
+}
+
+/// 表示は更新せずにレイアウトだけを切り替える
+///
+/// ロックを持っている間など，画面に書けないところから使う
+pub fn set_active_layout(layout: Layout) {
+    ACTIVE_LAYOUT.store(layout as u8, Ordering::Relaxed);
+}
+
+/// ステータス行の右端に今のレイアウトを表示する
+pub fn show_indicator(layout: Layout) {
+    // 名前の長さが違っても前の表示が残らないように，幅を揃える
+    let text = format!("[KB:{:<3}]", layout.name());
+    let color_code = ColorCode::new(Color::Black, Color::LightGray);
+    vga_buffer::write_status(BUFFER_WIDTH - text.len(), &text, color_code);
+}
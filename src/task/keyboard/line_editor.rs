@@ -0,0 +1,273 @@
+use alloc::{
+    collections::VecDeque,
+    string::{String, ToString},
+    vec::Vec,
+};
+use futures_util::stream::StreamExt;
+use pc_keyboard::{DecodedKey, KeyCode};
+
+use super::event::{KeyEvent, Modifiers};
+use super::hotkey::{self, Action};
+use super::{KeyEventStream, QueueError};
+use crate::{print, println, vga_buffer};
+
+/// 覚えておく履歴の数
+pub const DEFAULT_HISTORY_CAPACITY: usize = 16;
+
+const BACKSPACE: char = '\u{8}';
+const DELETE: char = '\u{7f}';
+/// Ctrl-U
+const KILL_LINE: char = '\u{15}';
+
+/// 入力した行の履歴
+///
+/// いっぱいになると，古いものから捨てる
+pub struct History {
+    entries: VecDeque<String>,
+    capacity: usize,
+}
+
+impl History {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            entries: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    /// 空行と，直前と同じ行は記録しない
+    pub fn push(&mut self, line: &str) {
+        if self.capacity == 0
+            || line.is_empty()
+            || self.entries.back().map(String::as_str) == Some(line)
+        {
+            return;
+        }
+        if self.entries.len() == self.capacity {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(line.to_string());
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// 古い順に返す
+    pub fn iter(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(String::as_str)
+    }
+
+    fn get(&self, index: usize) -> Option<&str> {
+        self.entries.get(index).map(String::as_str)
+    }
+}
+
+/// 1行分の編集状態
+///
+/// キーを1つずつ受け取り，Enterで確定した行を返す．画面には触らない
+pub struct LineEditor {
+    buffer: Vec<char>,
+    cursor: usize,
+    max_len: usize,
+    history: History,
+    /// 履歴を遡っているときの位置
+    history_index: Option<usize>,
+    /// 履歴を遡る前に入力していた行
+    draft: Vec<char>,
+}
+
+impl LineEditor {
+    /// `max_len`より長い行は入力できない
+    pub fn new(max_len: usize, history_capacity: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            cursor: 0,
+            max_len,
+            history: History::new(history_capacity),
+            history_index: None,
+            draft: Vec::new(),
+        }
+    }
+
+    /// 入力中の行
+    pub fn line(&self) -> String {
+        self.buffer.iter().collect()
+    }
+
+    /// カーソルの位置(文字数)
+    pub fn cursor(&self) -> usize {
+        self.cursor
+    }
+
+    pub fn history(&self) -> &History {
+        &self.history
+    }
+
+    /// キーを1つ処理する．Enterが押されたら確定した行を返す
+    pub fn handle_key(&mut self, key: DecodedKey) -> Option<String> {
+        match key {
+            DecodedKey::Unicode('\n') => return Some(self.submit()),
+            DecodedKey::Unicode(BACKSPACE) => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode(DELETE) => {
+                if self.cursor < self.buffer.len() {
+                    self.buffer.remove(self.cursor);
+                }
+            }
+            DecodedKey::Unicode(KILL_LINE) => {
+                self.buffer.drain(..self.cursor);
+                self.cursor = 0;
+            }
+            DecodedKey::Unicode(character) if !character.is_control() => {
+                if self.buffer.len() < self.max_len {
+                    self.buffer.insert(self.cursor, character);
+                    self.cursor += 1;
+                }
+            }
+            DecodedKey::RawKey(KeyCode::ArrowLeft) => self.cursor = self.cursor.saturating_sub(1),
+            DecodedKey::RawKey(KeyCode::ArrowRight) => {
+                self.cursor = (self.cursor + 1).min(self.buffer.len())
+            }
+            DecodedKey::RawKey(KeyCode::Home) => self.cursor = 0,
+            DecodedKey::RawKey(KeyCode::End) => self.cursor = self.buffer.len(),
+            DecodedKey::RawKey(KeyCode::ArrowUp) => self.history_prev(),
+            DecodedKey::RawKey(KeyCode::ArrowDown) => self.history_next(),
+            _ => {}
+        }
+        None
+    }
+
+    fn submit(&mut self) -> String {
+        let line = self.line();
+        self.history.push(&line);
+        self.buffer.clear();
+        self.cursor = 0;
+        self.history_index = None;
+        self.draft.clear();
+        line
+    }
+
+    fn history_prev(&mut self) {
+        let index = match self.history_index {
+            Some(0) => return,
+            Some(index) => index - 1,
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.buffer.clone();
+                self.history.len() - 1
+            }
+        };
+        self.history_index = Some(index);
+        let entry = self.history.get(index).unwrap_or_default();
+        self.set_buffer(entry.chars().collect());
+    }
+
+    fn history_next(&mut self) {
+        let index = match self.history_index {
+            Some(index) => index + 1,
+            None => return,
+        };
+        if index < self.history.len() {
+            self.history_index = Some(index);
+            let entry = self.history.get(index).unwrap_or_default();
+            self.set_buffer(entry.chars().collect());
+        } else {
+            self.history_index = None;
+            let draft = core::mem::take(&mut self.draft);
+            self.set_buffer(draft);
+        }
+    }
+
+    fn set_buffer(&mut self, mut buffer: Vec<char>) {
+        buffer.truncate(self.max_len);
+        self.cursor = buffer.len();
+        self.buffer = buffer;
+    }
+}
+
+/// キーボードから1行ずつ読む
+///
+/// 入力中の行は画面の一番下に表示する
+pub struct Readline {
+    events: KeyEventStream,
+    editor: LineEditor,
+}
+
+impl Readline {
+    pub fn new() -> Result<Self, QueueError> {
+        Ok(Self::with_events(KeyEventStream::new()?))
+    }
+
+    pub fn with_events(events: KeyEventStream) -> Self {
+        Self {
+            events,
+            editor: LineEditor::new(0, DEFAULT_HISTORY_CAPACITY),
+        }
+    }
+
+    pub fn history(&self) -> &History {
+        self.editor.history()
+    }
+
+    /// `prompt`を表示して1行読む．スキャンコードが尽きたら`None`
+    pub async fn read_line(&mut self, prompt: &str) -> Option<String> {
+        // プロンプトと入力が1行に収まるようにする
+        self.editor.max_len = (vga_buffer::BUFFER_WIDTH - 1).saturating_sub(prompt.chars().count());
+        redraw(prompt, &self.editor);
+
+        while let Some(event) = self.events.next().await {
+            match hotkey::lookup(&event) {
+                Some(Action::Call(action)) => {
+                    action();
+                    redraw(prompt, &self.editor);
+                    continue;
+                }
+                // 入力中の行は残したまま，割り当てられたコマンドを返す
+                Some(Action::Command(line)) => {
+                    vga_buffer::replace_last_line(prompt, prompt.chars().count());
+                    print!("{}", line);
+                    println!();
+                    return Some(line);
+                }
+                None => {}
+            }
+            let key = match editor_key(&event) {
+                Some(key) => key,
+                None => continue,
+            };
+            if let Some(line) = self.editor.handle_key(key) {
+                println!();
+                return Some(line);
+            }
+            redraw(prompt, &self.editor);
+        }
+        None
+    }
+}
+
+/// Ctrlと文字の同時押しは，対応する制御文字にして渡す
+fn editor_key(event: &KeyEvent) -> Option<DecodedKey> {
+    match event.key? {
+        DecodedKey::Unicode(character)
+            if event.modifiers.contains(Modifiers::CTRL) && character.is_ascii_alphabetic() =>
+        {
+            Some(DecodedKey::Unicode(char::from(character as u8 & 0x1f)))
+        }
+        key => Some(key),
+    }
+}
+
+fn redraw(prompt: &str, editor: &LineEditor) {
+    let mut text = String::from(prompt);
+    text.extend(editor.buffer.iter());
+    vga_buffer::replace_last_line(&text, prompt.chars().count() + editor.cursor());
+}
@@ -0,0 +1,260 @@
+use pc_keyboard::{
+    layouts, DecodedKey, HandleControl, KeyCode, KeyEvent as RawKeyEvent, KeyState, Keyboard,
+    ScancodeSet1, ScancodeSet2,
+};
+
+use super::event::{KeyEvent, LockState, Modifiers};
+use super::layout::{self, Layout};
+use crate::ps2::{self, ScancodeSet};
+
+/// このキーを押すと，次のレイアウトに切り替わる
+pub const SWITCH_LAYOUT_KEY: KeyCode = KeyCode::F12;
+
+/// デコーダが押されているかを覚えているキー．作り直したときに押し直す
+const DECODER_MODIFIER_KEYS: [KeyCode; 5] = [
+    KeyCode::LShift,
+    KeyCode::RShift,
+    KeyCode::LControl,
+    KeyCode::RControl,
+    KeyCode::RAltGr,
+];
+
+/// レイアウトごとのデコーダ
+///
+/// pc_keyboardのKeyboardはレイアウトを型引数に取るので，切り替えるときは作り直す
+enum LayoutDecoder<S: pc_keyboard::ScancodeSet> {
+    Us104(Keyboard<layouts::Us104Key, S>),
+    Jis109(Keyboard<layouts::Jis109Key, S>),
+    Uk105(Keyboard<layouts::Uk105Key, S>),
+    De105(Keyboard<layouts::De105Key, S>),
+}
+
+impl<S: pc_keyboard::ScancodeSet> LayoutDecoder<S> {
+    fn new(layout: Layout, scancode_set: S, handle_control: HandleControl) -> Self {
+        match layout {
+            Layout::Us104 => LayoutDecoder::Us104(Keyboard::new(
+                scancode_set,
+                layouts::Us104Key,
+                handle_control,
+            )),
+            Layout::Jis109 => LayoutDecoder::Jis109(Keyboard::new(
+                scancode_set,
+                layouts::Jis109Key,
+                handle_control,
+            )),
+            Layout::Uk105 => LayoutDecoder::Uk105(Keyboard::new(
+                scancode_set,
+                layouts::Uk105Key,
+                handle_control,
+            )),
+            Layout::De105 => LayoutDecoder::De105(Keyboard::new(
+                scancode_set,
+                layouts::De105Key,
+                handle_control,
+            )),
+        }
+    }
+}
+
+/// スキャンコードのセットごとのデコーダ
+enum Decoder {
+    Set1(LayoutDecoder<ScancodeSet1>),
+    Set2(LayoutDecoder<ScancodeSet2>),
+}
+
+/// どのセット，レイアウトのデコーダでも同じ処理をする
+macro_rules! with_decoder {
+    ($decoder:expr, $keyboard:ident => $body:expr) => {
+        match $decoder {
+            Decoder::Set1(decoder) => with_layout_decoder!(decoder, $keyboard => $body),
+            Decoder::Set2(decoder) => with_layout_decoder!(decoder, $keyboard => $body),
+        }
+    };
+}
+
+macro_rules! with_layout_decoder {
+    ($decoder:expr, $keyboard:ident => $body:expr) => {
+        match $decoder {
+            LayoutDecoder::Us104($keyboard) => $body,
+            LayoutDecoder::Jis109($keyboard) => $body,
+            LayoutDecoder::Uk105($keyboard) => $body,
+            LayoutDecoder::De105($keyboard) => $body,
+        }
+    };
+}
+
+impl Decoder {
+    fn new(layout: Layout, scancode_set: ScancodeSet, handle_control: HandleControl) -> Self {
+        match scancode_set {
+            ScancodeSet::Set1 => Decoder::Set1(LayoutDecoder::new(
+                layout,
+                ScancodeSet1::new(),
+                handle_control,
+            )),
+            ScancodeSet::Set2 => Decoder::Set2(LayoutDecoder::new(
+                layout,
+                ScancodeSet2::new(),
+                handle_control,
+            )),
+        }
+    }
+}
+
+/// スキャンコードを文字やキーに変換する
+///
+/// 使うレイアウトは`layout::set_layout`か，切り替えキーでいつでも変えられる
+/// 切り替えキーで変わったときは表示を更新しないので，`take_layout_switch`を見て呼び出し側で更新する
+pub struct KeyboardService {
+    decoder: Decoder,
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    handle_control: HandleControl,
+    /// デコーダを作り直しても消えないように，自分で持っておく
+    modifiers: Modifiers,
+    locks: LockState,
+    /// `DECODER_MODIFIER_KEYS`のうち押されているもの．i番目のビットがi番目のキー
+    held_keys: u8,
+    /// 切り替えキーでレイアウトを変えたが，まだ`take_layout_switch`で渡していない
+    layout_switched: bool,
+}
+
+impl KeyboardService {
+    /// 今のレイアウトでサービスを作り，画面にレイアウトを表示する
+    pub fn new() -> Self {
+        Self::with_handle_control(HandleControl::Ignore)
+    }
+
+    /// Ctrlとの同時押しの扱いを指定して作る
+    ///
+    /// `HandleControl::MapLettersToUnicode`にすると，Ctrl-Uなどが制御文字('\u{15}')になる
+    pub fn with_handle_control(handle_control: HandleControl) -> Self {
+        Self::with_scancode_set(ps2::scancode_set(), handle_control)
+    }
+
+    /// コントローラの設定によらず，決まったセットのスキャンコードを読むサービスを作る
+    pub fn with_scancode_set(scancode_set: ScancodeSet, handle_control: HandleControl) -> Self {
+        let layout = layout::active_layout();
+        layout::show_indicator(layout);
+        Self {
+            decoder: Decoder::new(layout, scancode_set, handle_control),
+            layout,
+            scancode_set,
+            handle_control,
+            modifiers: Modifiers::NONE,
+            locks: LockState::default(),
+            held_keys: 0,
+            layout_switched: false,
+        }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn scancode_set(&self) -> ScancodeSet {
+        self.scancode_set
+    }
+
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    pub fn locks(&self) -> LockState {
+        self.locks
+    }
+
+    /// 前に呼んでから切り替えキーでレイアウトが変わっていれば，今のレイアウトを返す
+    pub fn take_layout_switch(&mut self) -> Option<Layout> {
+        core::mem::take(&mut self.layout_switched).then_some(self.layout)
+    }
+
+    /// スキャンコードを1バイト処理し，キーが押されていればそれを返す
+    ///
+    /// 切り替えキーはここで処理するので返さない
+    pub fn process_scancode(&mut self, scancode: u8) -> Option<DecodedKey> {
+        self.process_key_event(scancode)?.key
+    }
+
+    /// スキャンコードを1バイト処理し，キーの押下か解放がそろえばイベントを返す
+    ///
+    /// 切り替えキーはここで処理するので返さない
+    pub fn process_key_event(&mut self, scancode: u8) -> Option<KeyEvent> {
+        self.sync_layout();
+
+        let event = with_decoder!(&mut self.decoder, keyboard => keyboard.add_byte(scancode))
+            .ok()
+            .flatten()?;
+        if self.handle_switch_key(&event) {
+            return None;
+        }
+        self.modifiers.update(event.code, event.state);
+        self.locks.update(event.code, event.state);
+        self.update_held_keys(event.code, event.state);
+        let (code, state) = (event.code, event.state);
+        let key = with_decoder!(&mut self.decoder, keyboard => keyboard.process_keyevent(event));
+        Some(KeyEvent {
+            code,
+            state,
+            key,
+            modifiers: self.modifiers,
+        })
+    }
+
+    fn handle_switch_key(&mut self, event: &RawKeyEvent) -> bool {
+        if event.code != SWITCH_LAYOUT_KEY {
+            return false;
+        }
+        if event.state == KeyState::Down {
+            layout::set_active_layout(self.layout.next());
+            self.sync_layout();
+            self.layout_switched = true;
+        }
+        true
+    }
+
+    fn update_held_keys(&mut self, code: KeyCode, state: KeyState) {
+        if let Some(index) = DECODER_MODIFIER_KEYS.iter().position(|&key| key == code) {
+            match state {
+                KeyState::Down => self.held_keys |= 1 << index,
+                KeyState::Up => self.held_keys &= !(1 << index),
+                _ => {}
+            }
+        }
+    }
+
+    /// 他の場所でレイアウトが変えられていれば，デコーダを作り直す
+    ///
+    /// 押されている修飾キーとロックの状態は，新しいデコーダに押し直して引き継ぐ
+    fn sync_layout(&mut self) {
+        let active = layout::active_layout();
+        if active != self.layout {
+            self.decoder = Decoder::new(active, self.scancode_set, self.handle_control);
+            self.layout = active;
+            self.replay_state();
+        }
+    }
+
+    /// 作り直したデコーダに，今の修飾キーとロックの状態を教える
+    fn replay_state(&mut self) {
+        let fresh = LockState::default();
+        let held_keys = self.held_keys;
+        let presses = DECODER_MODIFIER_KEYS
+            .iter()
+            .enumerate()
+            .filter(|(index, _)| held_keys & (1 << index) != 0)
+            .map(|(_, &code)| code)
+            // ロックキーは押すたびに反転するので，作ったときと違うものだけ押す
+            .chain((self.locks.caps_lock != fresh.caps_lock).then_some(KeyCode::CapsLock))
+            .chain((self.locks.num_lock != fresh.num_lock).then_some(KeyCode::NumpadLock));
+        for code in presses {
+            let event = RawKeyEvent::new(code, KeyState::Down);
+            with_decoder!(&mut self.decoder, keyboard => keyboard.process_keyevent(event));
+        }
+    }
+}
+
+impl Default for KeyboardService {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,185 @@
+use alloc::{
+    collections::{BTreeMap, VecDeque},
+    sync::Arc,
+    vec::Vec,
+};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use spin::Mutex;
+
+use super::{Priority, TaskId};
+use crate::interrupts;
+
+/// 終了したタスクの情報をいくつまで残しておくか
+const FINISHED_HISTORY: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum TaskState {
+    /// キューに入っていて，ポーリングを待っている
+    Ready,
+    /// ポーリング中
+    Running,
+    /// wakeされるのを待っている
+    Waiting,
+    Done,
+}
+
+impl TaskState {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            0 => TaskState::Ready,
+            1 => TaskState::Running,
+            2 => TaskState::Waiting,
+            _ => TaskState::Done,
+        }
+    }
+}
+
+/// 診断表示用のタスクの情報
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaskInfo {
+    pub id: u64,
+    pub name: &'static str,
+    pub priority: Priority,
+    pub state: TaskState,
+    pub poll_count: u64,
+    /// ポーリングにかかった時間の合計 (TSCのサイクル数)
+    pub total_poll_cycles: u64,
+    /// 最後にwakeされたときのタイマ割り込みの回数
+    pub last_wake_tick: u64,
+}
+
+/// タスクごとの統計
+///
+/// wakeは割り込みハンドラからも呼ばれるので，すべてアトミックに更新する
+pub(crate) struct TaskStats {
+    id: TaskId,
+    name: &'static str,
+    priority: Priority,
+    state: AtomicU8,
+    poll_count: AtomicU64,
+    poll_cycles: AtomicU64,
+    last_wake_tick: AtomicU64,
+}
+
+impl TaskStats {
+    pub(crate) fn new(id: TaskId, name: &'static str, priority: Priority) -> Self {
+        Self {
+            id,
+            name,
+            priority,
+            state: AtomicU8::new(TaskState::Waiting as u8),
+            poll_count: AtomicU64::new(0),
+            poll_cycles: AtomicU64::new(0),
+            last_wake_tick: AtomicU64::new(0),
+        }
+    }
+
+    /// TaskWakerから呼ばれる
+    pub(crate) fn record_wake(&self) {
+        self.last_wake_tick
+            .store(interrupts::timer_ticks(), Ordering::Relaxed);
+        self.state.store(TaskState::Ready as u8, Ordering::Relaxed);
+    }
+
+    /// ポーリングを始める直前に呼び，ポーリングを始めたときのTSCを返す
+    pub(crate) fn start_poll(&self) -> u64 {
+        self.state
+            .store(TaskState::Running as u8, Ordering::Relaxed);
+        read_tsc()
+    }
+
+    pub(crate) fn finish_poll(&self, started_at: u64, done: bool) {
+        self.poll_count.fetch_add(1, Ordering::Relaxed);
+        self.poll_cycles
+            .fetch_add(read_tsc().wrapping_sub(started_at), Ordering::Relaxed);
+        if done {
+            self.state.store(TaskState::Done as u8, Ordering::Relaxed);
+        } else {
+            // ポーリング中にwakeされていれば，Readyのままにしておく
+            let _ = self.state.compare_exchange(
+                TaskState::Running as u8,
+                TaskState::Waiting as u8,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            );
+        }
+    }
+
+    fn info(&self) -> TaskInfo {
+        TaskInfo {
+            id: self.id.0,
+            name: self.name,
+            priority: self.priority,
+            state: TaskState::from_u8(self.state.load(Ordering::Relaxed)),
+            poll_count: self.poll_count.load(Ordering::Relaxed),
+            total_poll_cycles: self.poll_cycles.load(Ordering::Relaxed),
+            last_wake_tick: self.last_wake_tick.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Executorが持つタスクの一覧
+///
+/// 割り込みハンドラからは触らない
+#[derive(Default)]
+pub(crate) struct Registry {
+    tasks: BTreeMap<TaskId, Arc<TaskStats>>,
+    finished: VecDeque<TaskInfo>,
+}
+
+impl Registry {
+    pub(crate) fn insert(&mut self, stats: Arc<TaskStats>) {
+        self.tasks.insert(stats.id, stats);
+    }
+
+    pub(crate) fn finish(&mut self, task_id: TaskId) {
+        if let Some(stats) = self.tasks.remove(&task_id) {
+            if self.finished.len() == FINISHED_HISTORY {
+                self.finished.pop_front();
+            }
+            self.finished.push_back(stats.info());
+        }
+    }
+}
+
+/// Executorの中のタスクを外から覗くためのハンドル
+///
+/// クローンしてタスクの中に持ち込める
+#[derive(Clone)]
+pub struct TaskMonitor {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl TaskMonitor {
+    pub(crate) fn new(registry: Arc<Mutex<Registry>>) -> Self {
+        Self { registry }
+    }
+
+    /// 実行中のタスクと，最近終了したタスクの情報をIDの順に返す
+    pub fn tasks(&self) -> Vec<TaskInfo> {
+        let registry = self.registry.lock();
+        let mut tasks: Vec<TaskInfo> = registry
+            .finished
+            .iter()
+            .cloned()
+            .chain(registry.tasks.values().map(|stats| stats.info()))
+            .collect();
+        tasks.sort_by_key(|info| info.id);
+        tasks
+    }
+
+    /// 終了していないタスクの数
+    pub fn live_task_count(&self) -> usize {
+        self.registry.lock().tasks.len()
+    }
+}
+
+/// タイムスタンプカウンタを読む
+fn read_tsc() -> u64 {
+    let (low, high): (u32, u32);
+    unsafe {
+        core::arch::asm!("rdtsc", out("eax") low, out("edx") high, options(nomem, nostack, preserves_flags));
+    }
+    (u64::from(high) << 32) | u64::from(low)
+}
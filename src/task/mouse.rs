@@ -0,0 +1,261 @@
+use conquer_once::spin::OnceCell;
+use core::ops::BitOr;
+use core::{
+    pin::Pin,
+    sync::atomic::{AtomicUsize, Ordering},
+    task::{Context, Poll},
+};
+use crossbeam_queue::ArrayQueue;
+use futures_util::stream::Stream;
+use futures_util::task::AtomicWaker;
+
+use crate::interrupts::{self, InterruptIndex};
+use crate::ps2::{self, MouseInfo, Ps2Error};
+use crate::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+/// 割り込みハンドラが入れるキューの長さ
+const BYTE_QUEUE_SIZE: usize = 256;
+
+static BYTE_QUEUE: OnceCell<ArrayQueue<u8>> = OnceCell::uninit();
+static WAKER: AtomicWaker = AtomicWaker::new();
+/// 今のマウスのパケットの長さ．初期化するまでは0
+static PACKET_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// マウスを初期化し，IRQ12でパケットを受け取り始める
+///
+/// 1度だけ呼ぶ．2度目は`Ps2Error::Irq`になる
+pub fn init() -> Result<MouseInfo, Ps2Error> {
+    let _ = BYTE_QUEUE.try_init_once(|| ArrayQueue::new(BYTE_QUEUE_SIZE));
+    // 報告を始める前にハンドラを登録しておかないと，読まれないデータでキーボードも止まる
+    interrupts::register_irq(InterruptIndex::Mouse.as_irq(), mouse_irq_handler)
+        .map_err(Ps2Error::Irq)?;
+    match ps2::init_mouse() {
+        Ok(info) => {
+            PACKET_SIZE.store(info.packet_size(), Ordering::Relaxed);
+            Ok(info)
+        }
+        Err(err) => {
+            let _ = interrupts::unregister_irq(InterruptIndex::Mouse.as_irq());
+            Err(err)
+        }
+    }
+}
+
+fn mouse_irq_handler() {
+    if let Some(byte) = ps2::read_irq_data() {
+        add_byte(byte);
+    }
+}
+
+/// マウス割り込みハンドラから呼び出される
+///
+/// 処理をブロックしたり，アロケートしてはいけない
+pub(crate) fn add_byte(byte: u8) {
+    if let Ok(queue) = BYTE_QUEUE.try_get() {
+        // いっぱいなら捨てる．パケットの区切りはPacketParserが合わせ直す
+        if queue.push(byte).is_ok() {
+            WAKER.wake();
+        }
+    }
+}
+
+/// 押されているボタン
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons(u8);
+
+impl MouseButtons {
+    pub const NONE: MouseButtons = MouseButtons(0);
+    pub const LEFT: MouseButtons = MouseButtons(1 << 0);
+    pub const RIGHT: MouseButtons = MouseButtons(1 << 1);
+    pub const MIDDLE: MouseButtons = MouseButtons(1 << 2);
+
+    pub const fn contains(self, other: MouseButtons) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+}
+
+impl BitOr for MouseButtons {
+    type Output = MouseButtons;
+
+    fn bitor(self, rhs: MouseButtons) -> MouseButtons {
+        MouseButtons(self.0 | rhs.0)
+    }
+}
+
+/// 1パケット分のマウスの動き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// 右が正
+    pub dx: i16,
+    /// 上が正
+    pub dy: i16,
+    /// 手前に回すと正．ホイールがなければ0
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+/// 1バイト目: 常に1になるビット
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// マウスから届いたバイト列をパケットに組み立てる
+pub struct PacketParser {
+    packet_size: usize,
+    bytes: [u8; 4],
+    len: usize,
+}
+
+impl PacketParser {
+    /// `packet_size`は3か4
+    pub fn new(packet_size: usize) -> Self {
+        assert!(packet_size == 3 || packet_size == 4);
+        Self {
+            packet_size,
+            bytes: [0; 4],
+            len: 0,
+        }
+    }
+
+    /// 1バイト入れ，パケットがそろえばイベントを返す
+    pub fn push(&mut self, byte: u8) -> Option<MouseEvent> {
+        // 取りこぼしで区切りがずれたら，1バイト目らしいバイトまで読み飛ばす
+        if self.len == 0 && byte & ALWAYS_ONE == 0 {
+            return None;
+        }
+        self.bytes[self.len] = byte;
+        self.len += 1;
+        if self.len < self.packet_size {
+            return None;
+        }
+        self.len = 0;
+        Some(self.decode())
+    }
+
+    fn decode(&self) -> MouseEvent {
+        let [flags, x, y, extra] = self.bytes;
+        let delta = |value: u8, negative: bool, overflow: bool| {
+            // あふれたときの値は当てにならないので捨てる
+            if overflow {
+                0
+            } else if negative {
+                i16::from(value) - 0x100
+            } else {
+                i16::from(value)
+            }
+        };
+        let wheel = if self.packet_size == 4 {
+            // 下位4ビットが符号付きの移動量
+            ((extra << 4) as i8) >> 4
+        } else {
+            0
+        };
+        MouseEvent {
+            dx: delta(x, flags & X_SIGN != 0, flags & X_OVERFLOW != 0),
+            dy: delta(y, flags & Y_SIGN != 0, flags & Y_OVERFLOW != 0),
+            wheel,
+            buttons: MouseButtons(flags & 0x07),
+        }
+    }
+}
+
+/// マウスのイベントを受け取るストリーム
+pub struct MouseStream {
+    parser: PacketParser,
+}
+
+impl MouseStream {
+    /// `init`が成功してから，1度だけ作れる
+    pub fn new() -> Result<Self, Ps2Error> {
+        static CREATED: OnceCell<()> = OnceCell::uninit();
+        let packet_size = PACKET_SIZE.load(Ordering::Relaxed);
+        if packet_size == 0 {
+            return Err(Ps2Error::NotInitialized);
+        }
+        CREATED
+            .try_init_once(|| ())
+            .map_err(|_| Ps2Error::StreamAlreadyCreated)?;
+
+        Ok(Self {
+            parser: PacketParser::new(packet_size),
+        })
+    }
+}
+
+impl Stream for MouseStream {
+    type Item = MouseEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<MouseEvent>> {
+        let queue = BYTE_QUEUE.try_get().expect("not initialized");
+
+        loop {
+            let byte = match queue.pop() {
+                Ok(byte) => byte,
+                Err(_) => {
+                    WAKER.register(cx.waker());
+                    match queue.pop() {
+                        Ok(byte) => {
+                            WAKER.take();
+                            byte
+                        }
+                        Err(_) => return Poll::Pending,
+                    }
+                }
+            };
+            if let Some(event) = self.parser.push(byte) {
+                return Poll::Ready(Some(event));
+            }
+        }
+    }
+}
+
+/// 1マスを動かすのに必要なマウスの移動量
+const COUNTS_PER_COLUMN: i32 = 8;
+const COUNTS_PER_ROW: i32 = 16;
+
+/// テキストモードの画面上のマウスカーソルの位置
+///
+/// マスより細かい移動量もためておく
+pub struct Cursor {
+    x: i32,
+    y: i32,
+}
+
+impl Cursor {
+    /// 画面の中央から始める
+    pub fn new() -> Self {
+        Self {
+            x: (BUFFER_WIDTH as i32 / 2) * COUNTS_PER_COLUMN,
+            y: (BUFFER_HEIGHT as i32 / 2) * COUNTS_PER_ROW,
+        }
+    }
+
+    /// 移動させる．画面の外には出ない
+    pub fn apply(&mut self, event: &MouseEvent) {
+        let max_x = BUFFER_WIDTH as i32 * COUNTS_PER_COLUMN - 1;
+        let max_y = BUFFER_HEIGHT as i32 * COUNTS_PER_ROW - 1;
+        self.x = (self.x + i32::from(event.dx)).clamp(0, max_x);
+        // 画面の行は下に向かって増える
+        self.y = (self.y - i32::from(event.dy)).clamp(0, max_y);
+    }
+
+    /// (行, 列)
+    pub fn position(&self) -> (usize, usize) {
+        (
+            (self.y / COUNTS_PER_ROW) as usize,
+            (self.x / COUNTS_PER_COLUMN) as usize,
+        )
+    }
+}
+
+impl Default for Cursor {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,76 @@
+//! 複数のfutureのうち，最初に完了したものの結果を返すコンビネータ
+
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+/// 2つのうちどちらが完了したか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+/// `a`と`b`を同時に待ち，先に完了した方の結果を返す
+///
+/// もう一方はドロップされる
+/// 両方とも完了できるときは`a`を優先する
+pub fn select<A: Future, B: Future>(a: A, b: B) -> Select<A, B> {
+    Select {
+        a: Box::pin(a),
+        b: Box::pin(b),
+    }
+}
+
+/// `select`が返すfuture
+pub struct Select<A: Future, B: Future> {
+    a: Pin<Box<A>>,
+    b: Pin<Box<B>>,
+}
+
+impl<A: Future, B: Future> Future for Select<A, B> {
+    type Output = Either<A::Output, B::Output>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        if let Poll::Ready(output) = this.a.as_mut().poll(cx) {
+            return Poll::Ready(Either::Left(output));
+        }
+        if let Poll::Ready(output) = this.b.as_mut().poll(cx) {
+            return Poll::Ready(Either::Right(output));
+        }
+        Poll::Pending
+    }
+}
+
+/// 同じ型のfutureをすべて同時に待ち，最初に完了したものの結果とその添字を返す
+///
+/// 空のVecを渡すとパニックする
+pub fn select_all<F: Future>(futures: Vec<F>) -> SelectAll<F> {
+    assert!(!futures.is_empty(), "select_all needs at least one future");
+    SelectAll {
+        futures: futures.into_iter().map(Box::pin).collect(),
+    }
+}
+
+/// `select_all`が返すfuture
+pub struct SelectAll<F: Future> {
+    futures: Vec<Pin<Box<F>>>,
+}
+
+impl<F: Future> Future for SelectAll<F> {
+    type Output = (F::Output, usize);
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        for (index, future) in this.futures.iter_mut().enumerate() {
+            if let Poll::Ready(output) = future.as_mut().poll(cx) {
+                return Poll::Ready((output, index));
+            }
+        }
+        Poll::Pending
+    }
+}
@@ -1,47 +1,57 @@
-use super::Task;
-use alloc::collections::VecDeque;
-use core::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use alloc::{sync::Arc, task::Wake};
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::task::{Context, Poll, Waker};
+use x86_64::instructions::interrupts;
 
-pub struct SimpleExecutor {
-    task_queue: VecDeque<Task>,
-}
+/// futureが完了するまで実行し，その結果を返す
+///
+/// wakeされるまではhltで止まっているので，割り込みでwakeされるfutureも待てる
+/// Executorを作らずにasyncなコードを動かしたいテストなどで使う
+/// 割り込みを受け取らないAPで呼ぶと戻らないことがある
+///
+/// 待っている間は割り込みを有効にし，戻るときに呼ばれたときの状態に戻す
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let were_enabled = interrupts::are_enabled();
+    let mut future = pin!(future);
+    let flag = Arc::new(WakeFlag {
+        woken: AtomicBool::new(false),
+    });
+    let waker = Waker::from(flag.clone());
+    let mut context = Context::from_waker(&waker);
 
-impl SimpleExecutor {
-    pub fn new() -> Self {
-        Self {
-            task_queue: VecDeque::new(),
+    loop {
+        flag.woken.store(false, Ordering::Release);
+        if let Poll::Ready(output) = future.as_mut().poll(&mut context) {
+            if !were_enabled {
+                interrupts::disable();
+            }
+            return output;
         }
-    }
 
-    pub fn spawn(&mut self, task: Task) {
-        self.task_queue.push_back(task);
-    }
-
-    pub fn run(&mut self) {
-        while let Some(mut task) = self.task_queue.pop_front() {
-            let waker = dummy_waker();
-            let mut context = Context::from_waker(&waker);
-            match task.poll(&mut context) {
-                // taskの終了
-                Poll::Ready(_) => {}
-                Poll::Pending => self.task_queue.push_back(task),
-            }
+        super::timer::wake_expired();
+        // チェックとhltの間にwakeされるとそのまま止まってしまうので，割り込みを無効にしてからチェックする
+        interrupts::disable();
+        if flag.woken.load(Ordering::Acquire) {
+            interrupts::enable();
+        } else {
+            interrupts::enable_and_hlt();
         }
     }
 }
 
-fn dummy_row_waker() -> RawWaker {
-    // *const は無名関数ポインタ
-    // 型の情報がない
-    fn no_op(_: *const ()) {}
-    fn clone(_: *const ()) -> RawWaker {
-        dummy_row_waker()
-    }
-
-    let vtable = &RawWakerVTable::new(clone, no_op, no_op, no_op);
-    RawWaker::new(0 as *const (), vtable)
+/// wakeされたかどうかを記録するだけのWaker
+struct WakeFlag {
+    woken: AtomicBool,
 }
 
-fn dummy_waker() -> Waker {
-    unsafe { Waker::from_raw(dummy_row_waker()) }
+impl Wake for WakeFlag {
+    fn wake(self: Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
+
+    fn wake_by_ref(self: &Arc<Self>) {
+        self.woken.store(true, Ordering::Release);
+    }
 }
@@ -0,0 +1,77 @@
+//! タスク間で使う非同期の同期プリミティブ
+//!
+//! `spin::Mutex`と違い，待っている間はExecutorに制御を返す
+//! 内部でアロケートするので，割り込みハンドラから使ってはいけない
+
+pub mod mpsc;
+pub mod mutex;
+pub mod notify;
+pub mod oneshot;
+
+pub use mutex::{Mutex, MutexGuard};
+pub use notify::Notify;
+
+use alloc::{collections::BTreeMap, vec::Vec};
+use core::task::Waker;
+
+/// 待っているタスクのWakerの一覧
+///
+/// 各futureは登録したときのキーを持っておき，ドロップするときに自分で取り除く
+/// キーの小さい順，つまり登録した順に起こす
+struct WaitList {
+    waiters: BTreeMap<u64, Waker>,
+    next_key: u64,
+}
+
+impl WaitList {
+    const fn new() -> Self {
+        Self {
+            waiters: BTreeMap::new(),
+            next_key: 0,
+        }
+    }
+
+    /// keyがNoneなら新しく登録し，そうでなければWakerを差し替える
+    fn register(&mut self, key: &mut Option<u64>, waker: &Waker) {
+        match key {
+            Some(key) => match self.waiters.get_mut(key) {
+                Some(registered) if registered.will_wake(waker) => {}
+                Some(registered) => *registered = waker.clone(),
+                None => {
+                    self.waiters.insert(*key, waker.clone());
+                }
+            },
+            None => {
+                let new_key = self.next_key;
+                self.next_key += 1;
+                self.waiters.insert(new_key, waker.clone());
+                *key = Some(new_key);
+            }
+        }
+    }
+
+    /// 取り除いたらtrueを返す
+    fn remove(&mut self, key: u64) -> bool {
+        self.waiters.remove(&key).is_some()
+    }
+
+    fn contains(&self, key: u64) -> bool {
+        self.waiters.contains_key(&key)
+    }
+
+    /// 先頭のWakerを返す
+    ///
+    /// 一覧からは取り除かないので，起こされたfutureがドロップされても次に回せる
+    fn first(&self) -> Option<Waker> {
+        self.waiters.values().next().cloned()
+    }
+
+    /// 先頭のキーとWakerを一覧から取り除いて返す
+    fn pop_first(&mut self) -> Option<(u64, Waker)> {
+        self.waiters.pop_first()
+    }
+
+    fn take_all(&mut self) -> Vec<Waker> {
+        core::mem::take(&mut self.waiters).into_values().collect()
+    }
+}
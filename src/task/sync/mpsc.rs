@@ -0,0 +1,255 @@
+//! 容量に上限のある複数送信者・単一受信者のチャネル
+
+use alloc::{collections::VecDeque, sync::Arc};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+use super::WaitList;
+
+/// 受信者がドロップされていて送れなかった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SendError<T>(pub T);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrySendError<T> {
+    /// キューがいっぱいだった
+    Full(T),
+    /// 受信者がドロップされていた
+    Closed(T),
+}
+
+struct Channel<T> {
+    queue: VecDeque<T>,
+    capacity: usize,
+    senders: usize,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+    send_waiters: WaitList,
+}
+
+/// 容量`capacity`のチャネルを作る
+///
+/// キューがいっぱいのとき，sendは空きができるまで待つ
+pub fn channel<T>(capacity: usize) -> (Sender<T>, Receiver<T>) {
+    assert!(capacity > 0, "channel capacity must be positive");
+    let channel = Arc::new(Mutex::new(Channel {
+        queue: VecDeque::with_capacity(capacity),
+        capacity,
+        senders: 1,
+        receiver_alive: true,
+        receiver: None,
+        send_waiters: WaitList::new(),
+    }));
+    (
+        Sender {
+            channel: channel.clone(),
+        },
+        Receiver { channel },
+    )
+}
+
+pub struct Sender<T> {
+    channel: Arc<Mutex<Channel<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// 値を送る
+    ///
+    /// キューに空きができるまで待つ
+    pub fn send(&self, value: T) -> SendFuture<'_, T> {
+        SendFuture {
+            sender: self,
+            value: Some(value),
+            key: None,
+        }
+    }
+
+    /// 待たずに値を送る
+    pub fn try_send(&self, value: T) -> Result<(), TrySendError<T>> {
+        let receiver = {
+            let mut channel = self.channel.lock();
+            if !channel.receiver_alive {
+                return Err(TrySendError::Closed(value));
+            }
+            if channel.queue.len() >= channel.capacity {
+                return Err(TrySendError::Full(value));
+            }
+            channel.queue.push_back(value);
+            channel.receiver.take()
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// 受信者がドロップされたかどうか
+    pub fn is_closed(&self) -> bool {
+        !self.channel.lock().receiver_alive
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.channel.lock().senders += 1;
+        Self {
+            channel: self.channel.clone(),
+        }
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let receiver = {
+            let mut channel = self.channel.lock();
+            channel.senders -= 1;
+            if channel.senders == 0 {
+                // recvがNoneを返せるように起こす
+                channel.receiver.take()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+    }
+}
+
+/// `Sender::send`が返すfuture
+pub struct SendFuture<'a, T> {
+    sender: &'a Sender<T>,
+    value: Option<T>,
+    key: Option<u64>,
+}
+
+// valueをピン留めして使うことはない
+impl<T> Unpin for SendFuture<'_, T> {}
+
+impl<T> Future for SendFuture<'_, T> {
+    type Output = Result<(), SendError<T>>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let value = this
+            .value
+            .take()
+            .expect("SendFuture polled after completion");
+        let receiver = {
+            let mut channel = this.sender.channel.lock();
+            if !channel.receiver_alive {
+                if let Some(key) = this.key.take() {
+                    channel.send_waiters.remove(key);
+                }
+                return Poll::Ready(Err(SendError(value)));
+            }
+            if channel.queue.len() >= channel.capacity {
+                channel.send_waiters.register(&mut this.key, cx.waker());
+                this.value = Some(value);
+                return Poll::Pending;
+            }
+
+            if let Some(key) = this.key.take() {
+                channel.send_waiters.remove(key);
+            }
+            channel.queue.push_back(value);
+            channel.receiver.take()
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<T> Drop for SendFuture<'_, T> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        // 空きができて起こされたのに送らなかった場合は，次の送信者を起こす
+        let waker = {
+            let mut channel = self.sender.channel.lock();
+            channel.send_waiters.remove(key);
+            if channel.queue.len() < channel.capacity {
+                channel.send_waiters.first()
+            } else {
+                None
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+pub struct Receiver<T> {
+    channel: Arc<Mutex<Channel<T>>>,
+}
+
+impl<T> Receiver<T> {
+    /// 値を受け取る
+    ///
+    /// すべての送信者がドロップされ，キューが空になるとNoneを返す
+    pub fn recv(&mut self) -> RecvFuture<'_, T> {
+        RecvFuture { receiver: self }
+    }
+
+    /// 待たずに値を受け取る
+    pub fn try_recv(&mut self) -> Option<T> {
+        let (value, sender) = {
+            let mut channel = self.channel.lock();
+            let value = channel.queue.pop_front()?;
+            (value, channel.send_waiters.first())
+        };
+        if let Some(waker) = sender {
+            waker.wake();
+        }
+        Some(value)
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        let senders = {
+            let mut channel = self.channel.lock();
+            channel.receiver_alive = false;
+            channel.send_waiters.take_all()
+        };
+        for waker in senders {
+            waker.wake();
+        }
+    }
+}
+
+/// `Receiver::recv`が返すfuture
+pub struct RecvFuture<'a, T> {
+    receiver: &'a mut Receiver<T>,
+}
+
+impl<T> Future for RecvFuture<'_, T> {
+    type Output = Option<T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let this = self.get_mut();
+        let mut channel = this.receiver.channel.lock();
+        if let Some(value) = channel.queue.pop_front() {
+            let sender = channel.send_waiters.first();
+            drop(channel);
+            if let Some(waker) = sender {
+                waker.wake();
+            }
+            return Poll::Ready(Some(value));
+        }
+        if channel.senders == 0 {
+            return Poll::Ready(None);
+        }
+        channel.receiver = Some(cx.waker().clone());
+        Poll::Pending
+    }
+}
@@ -0,0 +1,159 @@
+use core::{
+    cell::UnsafeCell,
+    future::Future,
+    ops::{Deref, DerefMut},
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use super::WaitList;
+
+/// 非同期のMutex
+///
+/// ロックを持ったまま.awaitしても，他のタスクは動き続けられる
+pub struct Mutex<T: ?Sized> {
+    state: spin::Mutex<State>,
+    value: UnsafeCell<T>,
+}
+
+struct State {
+    locked: bool,
+    waiters: WaitList,
+}
+
+// valueにはロックを取ったMutexGuardからしか触らない
+unsafe impl<T: ?Sized + Send> Send for Mutex<T> {}
+unsafe impl<T: ?Sized + Send> Sync for Mutex<T> {}
+
+impl<T> Mutex<T> {
+    pub const fn new(value: T) -> Self {
+        Self {
+            state: spin::Mutex::new(State {
+                locked: false,
+                waiters: WaitList::new(),
+            }),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: ?Sized> Mutex<T> {
+    /// ロックが取れるまで待つfutureを返す
+    pub fn lock(&self) -> LockFuture<'_, T> {
+        LockFuture {
+            mutex: self,
+            key: None,
+        }
+    }
+
+    /// ロックが取れなければすぐにNoneを返す
+    pub fn try_lock(&self) -> Option<MutexGuard<'_, T>> {
+        let mut state = self.state.lock();
+        if state.locked {
+            None
+        } else {
+            state.locked = true;
+            Some(MutexGuard { mutex: self })
+        }
+    }
+
+    /// 可変参照を持っていれば，ロックせずに中身に触れる
+    pub fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+
+    fn unlock(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            state.locked = false;
+            state.waiters.first()
+        };
+        // ロックを持ったままwakeしない
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+impl<T: Default> Default for Mutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// `Mutex::lock`が返すfuture
+pub struct LockFuture<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+    // 待ち行列に登録していればそのキー
+    key: Option<u64>,
+}
+
+impl<'a, T: ?Sized> Future for LockFuture<'a, T> {
+    type Output = MutexGuard<'a, T>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.get_mut();
+        let mut state = this.mutex.state.lock();
+        if state.locked {
+            state.waiters.register(&mut this.key, cx.waker());
+            return Poll::Pending;
+        }
+
+        state.locked = true;
+        if let Some(key) = this.key.take() {
+            state.waiters.remove(key);
+        }
+        Poll::Ready(MutexGuard { mutex: this.mutex })
+    }
+}
+
+impl<T: ?Sized> Drop for LockFuture<'_, T> {
+    fn drop(&mut self) {
+        let key = match self.key {
+            Some(key) => key,
+            None => return,
+        };
+        // 起こされたのにロックを取らずに諦めた場合は，次のタスクを起こす
+        let waker = {
+            let mut state = self.mutex.state.lock();
+            state.waiters.remove(key);
+            if state.locked {
+                None
+            } else {
+                state.waiters.first()
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+/// ドロップするとロックを解放する
+pub struct MutexGuard<'a, T: ?Sized> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T: ?Sized> Deref for MutexGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for MutexGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.value.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for MutexGuard<'_, T> {
+    fn drop(&mut self) {
+        self.mutex.unlock();
+    }
+}
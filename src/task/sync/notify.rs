@@ -0,0 +1,128 @@
+use alloc::collections::BTreeSet;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use spin::Mutex;
+
+use super::WaitList;
+
+/// 値を持たない通知
+///
+/// 待っているタスクがいないときの`notify_one`は1回分だけ覚えておき，
+/// 次の`notified`がすぐに完了する
+pub struct Notify {
+    state: Mutex<State>,
+}
+
+struct State {
+    permit: bool,
+    waiters: WaitList,
+    // `notify_one`で起こされ，まだ通知を受け取っていない待ち手のキー
+    handed_off: BTreeSet<u64>,
+}
+
+impl Notify {
+    pub const fn new() -> Self {
+        Self {
+            state: Mutex::new(State {
+                permit: false,
+                waiters: WaitList::new(),
+                handed_off: BTreeSet::new(),
+            }),
+        }
+    }
+
+    /// 通知されるまで待つfutureを返す
+    pub fn notified(&self) -> Notified<'_> {
+        Notified {
+            notify: self,
+            key: None,
+        }
+    }
+
+    /// 一番長く待っているタスクを1つ起こす
+    pub fn notify_one(&self) {
+        let waker = {
+            let mut state = self.state.lock();
+            match state.waiters.pop_first() {
+                Some((key, waker)) => {
+                    state.handed_off.insert(key);
+                    Some(waker)
+                }
+                None => {
+                    state.permit = true;
+                    None
+                }
+            }
+        };
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+
+    /// いま待っているタスクをすべて起こす
+    ///
+    /// 待っているタスクがいなくても，通知は覚えておかない
+    pub fn notify_waiters(&self) {
+        let wakers = self.state.lock().waiters.take_all();
+        for waker in wakers {
+            waker.wake();
+        }
+    }
+}
+
+impl Default for Notify {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// `Notify::notified`が返すfuture
+pub struct Notified<'a> {
+    notify: &'a Notify,
+    // 待っている間のキー
+    // 通知されると待ち行列から取り除かれる
+    key: Option<u64>,
+}
+
+impl Future for Notified<'_> {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let mut state = this.notify.state.lock();
+        match this.key {
+            Some(key) if !state.waiters.contains(key) => {
+                state.handed_off.remove(&key);
+                this.key = None;
+                return Poll::Ready(());
+            }
+            Some(_) => {}
+            None if state.permit => {
+                state.permit = false;
+                return Poll::Ready(());
+            }
+            None => {}
+        }
+        state.waiters.register(&mut this.key, cx.waker());
+        Poll::Pending
+    }
+}
+
+impl Drop for Notified<'_> {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            let handed_off = {
+                let mut state = self.notify.state.lock();
+                !state.waiters.remove(key) && state.handed_off.remove(&key)
+            };
+            // `notify_one`の通知を受け取らずに諦めたので，他のタスクに回す
+            // `notify_waiters`の通知は覚えておかないものなので，回さない
+            if handed_off {
+                self.notify.notify_one();
+            }
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! 値を1つだけ送るチャネル
+
+use alloc::sync::Arc;
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+};
+use spin::Mutex;
+
+/// 送信者が値を送らずにドロップされた
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecvError;
+
+struct Inner<T> {
+    value: Option<T>,
+    sender_alive: bool,
+    receiver_alive: bool,
+    receiver: Option<Waker>,
+}
+
+pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
+    let inner = Arc::new(Mutex::new(Inner {
+        value: None,
+        sender_alive: true,
+        receiver_alive: true,
+        receiver: None,
+    }));
+    (
+        Sender {
+            inner: inner.clone(),
+        },
+        Receiver { inner },
+    )
+}
+
+pub struct Sender<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Sender<T> {
+    /// 値を送る
+    ///
+    /// 受信者がすでにドロップされていれば，値をそのまま返す
+    pub fn send(self, value: T) -> Result<(), T> {
+        let receiver = {
+            let mut inner = self.inner.lock();
+            if !inner.receiver_alive {
+                return Err(value);
+            }
+            inner.value = Some(value);
+            inner.receiver.take()
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+        Ok(())
+    }
+
+    /// 受信者がドロップされたかどうか
+    pub fn is_closed(&self) -> bool {
+        !self.inner.lock().receiver_alive
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        let receiver = {
+            let mut inner = self.inner.lock();
+            inner.sender_alive = false;
+            inner.receiver.take()
+        };
+        if let Some(waker) = receiver {
+            waker.wake();
+        }
+    }
+}
+
+/// 送られた値を待つfuture
+pub struct Receiver<T> {
+    inner: Arc<Mutex<Inner<T>>>,
+}
+
+impl<T> Future for Receiver<T> {
+    type Output = Result<T, RecvError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.inner.lock();
+        if let Some(value) = inner.value.take() {
+            Poll::Ready(Ok(value))
+        } else if !inner.sender_alive {
+            Poll::Ready(Err(RecvError))
+        } else {
+            inner.receiver = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.inner.lock().receiver_alive = false;
+    }
+}
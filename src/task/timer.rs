@@ -0,0 +1,181 @@
+//! タイマ割り込みの回数を使ったsleepとtimeout
+//!
+//! 期限の過ぎたタイマはExecutorが1巡ごとに起こす
+//! タスクがなければExecutorはhltで止まっているが，タイマ割り込みで目を覚ますので遅れは1tick以内
+
+use alloc::{boxed::Box, collections::BTreeMap, vec::Vec};
+use core::{
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+use spin::Mutex;
+
+use crate::interrupts;
+
+/// PITの入力クロック
+const PIT_FREQUENCY_HZ: u64 = 1_193_182;
+/// PITの分周比（初期値のまま）
+const PIT_DIVISOR: u64 = 65536;
+
+/// 期限の近い順に並んだタイマ
+///
+/// キーは (期限のtick, 登録順)
+/// タスクからしか触らないので，割り込みを無効にせずにロックしてよい
+static TIMERS: Mutex<Timers> = Mutex::new(Timers {
+    wakers: BTreeMap::new(),
+    next_id: 0,
+});
+
+struct Timers {
+    wakers: BTreeMap<(u64, u64), Waker>,
+    next_id: u64,
+}
+
+/// `duration`の間に起きるタイマ割り込みの回数（切り上げ）
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let nanos = duration.as_nanos();
+    let divisor = u128::from(PIT_DIVISOR) * 1_000_000_000;
+    let ticks = (nanos * u128::from(PIT_FREQUENCY_HZ)).div_ceil(divisor);
+    ticks.min(u128::from(u64::MAX)) as u64
+}
+
+/// ticks回のタイマ割り込みにかかる時間
+pub fn ticks_to_duration(ticks: u64) -> Duration {
+    let nanos =
+        u128::from(ticks) * u128::from(PIT_DIVISOR) * 1_000_000_000 / u128::from(PIT_FREQUENCY_HZ);
+    Duration::from_nanos(nanos.min(u128::from(u64::MAX)) as u64)
+}
+
+/// 起動してからの時間
+pub fn uptime() -> Duration {
+    ticks_to_duration(interrupts::timer_ticks())
+}
+
+/// `duration`だけ待つ
+pub fn sleep(duration: Duration) -> Sleep {
+    sleep_until(interrupts::timer_ticks().saturating_add(duration_to_ticks(duration)))
+}
+
+/// `timer_ticks()`が`deadline`に達するまで待つ
+pub fn sleep_until(deadline: u64) -> Sleep {
+    Sleep {
+        deadline,
+        key: None,
+    }
+}
+
+/// 期限の過ぎたタイマのタスクを起こす
+///
+/// Executorから呼ばれる
+/// wakerのドロップでメモリを解放することがあるので，割り込みハンドラから呼んではいけない
+pub(crate) fn wake_expired() {
+    let now = interrupts::timer_ticks();
+    let expired: Vec<Waker> = {
+        let mut timers = TIMERS.lock();
+        let pending = timers.wakers.split_off(&(now + 1, 0));
+        core::mem::replace(&mut timers.wakers, pending)
+            .into_values()
+            .collect()
+    };
+    for waker in expired {
+        waker.wake();
+    }
+}
+
+/// 次に期限が来るタイマのtick
+pub fn next_deadline() -> Option<u64> {
+    TIMERS
+        .lock()
+        .wakers
+        .keys()
+        .next()
+        .map(|&(deadline, _)| deadline)
+}
+
+/// `sleep`が返すfuture
+pub struct Sleep {
+    deadline: u64,
+    // TIMERSに登録しているときのキー
+    key: Option<(u64, u64)>,
+}
+
+impl Sleep {
+    pub fn deadline(&self) -> u64 {
+        self.deadline
+    }
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = self.get_mut();
+        let mut timers = TIMERS.lock();
+        if interrupts::timer_ticks() >= this.deadline {
+            if let Some(key) = this.key.take() {
+                timers.wakers.remove(&key);
+            }
+            return Poll::Ready(());
+        }
+
+        let key = match this.key {
+            Some(key) => key,
+            None => {
+                let key = (this.deadline, timers.next_id);
+                timers.next_id += 1;
+                this.key = Some(key);
+                key
+            }
+        };
+        // 期限が来て取り除かれていても，もう一度登録する
+        timers.wakers.insert(key, cx.waker().clone());
+        Poll::Pending
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if let Some(key) = self.key {
+            TIMERS.lock().wakers.remove(&key);
+        }
+    }
+}
+
+/// timeoutで時間切れになった
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Elapsed;
+
+/// `future`が`duration`以内に完了しなければ`Err(Elapsed)`を返す
+///
+/// 時間切れになると`future`はドロップされる
+pub fn timeout<F: Future>(duration: Duration, future: F) -> Timeout<F> {
+    Timeout {
+        future: Box::pin(future),
+        sleep: sleep(duration),
+    }
+}
+
+/// `timeout`が返すfuture
+pub struct Timeout<F: Future> {
+    future: Pin<Box<F>>,
+    sleep: Sleep,
+}
+
+impl<F: Future> Future for Timeout<F> {
+    type Output = Result<F::Output, Elapsed>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        // フィールドはすべてUnpinなので，Pinを外してよい
+        let this = self.get_mut();
+        // 同時に完了していれば，結果の方を優先する
+        if let Poll::Ready(output) = this.future.as_mut().poll(cx) {
+            return Poll::Ready(Ok(output));
+        }
+        match Pin::new(&mut this.sleep).poll(cx) {
+            Poll::Ready(()) => Poll::Ready(Err(Elapsed)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
@@ -0,0 +1,398 @@
+//! タイマ割り込みで切り替わるカーネルスレッド
+//!
+//! スケジューラはラウンドロビンで，実行可能なスレッドを順番に`TIME_SLICE_TICKS`ずつ動かす
+//! スタックは`init`でマップしておいたものを使い回す
+//! 最初に`spawn`したときに，それを呼んだ文脈（ブート時のスタック）がメインスレッドになる
+//! async のExecutorはメインスレッドで動かせばよい
+
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::arch::global_asm;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::{
+    instructions::interrupts,
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
+    },
+    VirtAddr,
+};
+
+use crate::process::{self, Pid};
+
+/// スレッドごとのカーネルスタックの大きさ
+pub const STACK_SIZE: usize = 4096 * 4;
+
+/// 同時に存在できるスレッドの数（メインスレッドを除く）
+pub const MAX_THREADS: usize = 16;
+
+/// スレッドのスタックを置く領域
+///
+/// レベル4テーブルの140番目のエントリにあたる
+const STACK_REGION_START: u64 = 0x0000_4600_0000_0000;
+/// 各スタックの下にはマップしないガードページを置く
+const STACK_SPAN: u64 = STACK_SIZE as u64 + 4096;
+
+/// 1つのスレッドを続けて動かすタイマ割り込みの回数
+pub const TIME_SLICE_TICKS: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    /// メインスレッドのID
+    pub const MAIN: ThreadId = ThreadId(0);
+
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(self) -> u64 {
+        self.0
+    }
+}
+
+struct Thread {
+    id: ThreadId,
+    name: &'static str,
+    // 切り替えで保存したスタックポインタ
+    rsp: u64,
+    // メインスレッドはブートローダのスタックを使うのでNone
+    _stack: Option<Stack>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadError {
+    /// `init`でスタックを用意していない
+    NotInitialized,
+    /// `MAX_THREADS`個のスレッドがすでに存在する
+    TooManyThreads,
+}
+
+/// `init`でスタックをマップしたかどうか
+static STACKS_MAPPED: AtomicBool = AtomicBool::new(false);
+/// スタックごとの使用中かどうか
+static STACK_IN_USE: [AtomicBool; MAX_THREADS] = [const { AtomicBool::new(false) }; MAX_THREADS];
+
+/// スレッドが使っているスタックの番号
+///
+/// ドロップすると，スタックは次のスレッドに使い回される
+struct Stack(usize);
+
+impl Stack {
+    fn acquire() -> Result<Self, ThreadError> {
+        if !STACKS_MAPPED.load(Ordering::Acquire) {
+            return Err(ThreadError::NotInitialized);
+        }
+        STACK_IN_USE
+            .iter()
+            .position(|in_use| {
+                in_use
+                    .compare_exchange(false, true, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .map(Stack)
+            .ok_or(ThreadError::TooManyThreads)
+    }
+
+    fn top(&self) -> u64 {
+        stack_bottom(self.0) + STACK_SIZE as u64
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        STACK_IN_USE[self.0].store(false, Ordering::Release);
+    }
+}
+
+fn stack_bottom(index: usize) -> u64 {
+    STACK_REGION_START + STACK_SPAN * index as u64 + 4096
+}
+
+/// `MAX_THREADS`個のスレッドのスタックを新しいフレームでマップする
+///
+/// `spawn`する前に1度だけ呼ぶ
+pub fn init(
+    mapper: &mut OffsetPageTable,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<(), MapToError<Size4KiB>> {
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    for index in 0..MAX_THREADS {
+        let bottom = stack_bottom(index);
+        for offset in (0..STACK_SIZE as u64).step_by(4096) {
+            let page: Page = Page::containing_address(VirtAddr::new(bottom + offset));
+            let frame = frame_allocator
+                .allocate_frame()
+                .ok_or(MapToError::FrameAllocationFailed)?;
+            unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+        }
+    }
+    STACKS_MAPPED.store(true, Ordering::Release);
+    Ok(())
+}
+
+struct Scheduler {
+    current: Option<Box<Thread>>,
+    ready: VecDeque<Box<Thread>>,
+    // 終了したが，まだスタックを解放していないスレッド
+    finished: Vec<Box<Thread>>,
+    // 終了していないスレッドの数
+    alive: usize,
+}
+
+/// 割り込みハンドラからも触るので，ロックは必ず割り込みを無効にして取る
+static SCHEDULER: Mutex<Scheduler> = Mutex::new(Scheduler {
+    current: None,
+    ready: VecDeque::new(),
+    finished: Vec::new(),
+    alive: 0,
+});
+
+/// メインスレッドが登録されるまではプリエンプトしない
+static ENABLED: AtomicBool = AtomicBool::new(false);
+/// 割り込みハンドラを抜けるときに切り替える必要があるか
+static NEED_RESCHED: AtomicBool = AtomicBool::new(false);
+static TICKS_IN_SLICE: AtomicU64 = AtomicU64::new(0);
+
+extern "C" {
+    fn switch_context(old_rsp: *mut u64, new_rsp: u64);
+    fn thread_trampoline();
+}
+
+// callee-savedレジスタをスタックに積んでから，スタックを切り替える
+// caller-savedレジスタは呼び出し元が保存している
+global_asm!(
+    ".global switch_context",
+    "switch_context:",
+    "push rbx",
+    "push rbp",
+    "push r12",
+    "push r13",
+    "push r14",
+    "push r15",
+    "mov [rdi], rsp",
+    "mov rsp, rsi",
+    "pop r15",
+    "pop r14",
+    "pop r13",
+    "pop r12",
+    "pop rbp",
+    "pop rbx",
+    "ret",
+    "",
+    // 新しいスレッドはswitch_contextのretでここに来る
+    // r12にスレッドの本体を指すポインタが入っている
+    ".global thread_trampoline",
+    "thread_trampoline:",
+    "mov rdi, r12",
+    "call {entry}",
+    "ud2",
+    entry = sym thread_entry,
+);
+
+type Entry = Box<dyn FnOnce() + Send + 'static>;
+
+extern "C" fn thread_entry(entry: *mut Entry) -> ! {
+    // 切り替えは割り込みを無効にして行われる
+    interrupts::enable();
+    let entry = unsafe { Box::from_raw(entry) };
+    entry();
+    exit()
+}
+
+/// 新しいスレッドを作り，実行可能キューの最後に入れる
+///
+/// 初めて呼ばれたときに，呼び出し元をメインスレッドとして登録する
+pub fn spawn<F>(name: &'static str, f: F) -> Result<ThreadId, ThreadError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    reap();
+
+    let stack = Stack::acquire()?;
+    let entry: *mut Entry = Box::into_raw(Box::new(Box::new(f)));
+    let rsp = unsafe { init_stack(stack.top(), entry) };
+    let id = ThreadId::new();
+    let thread = Box::new(Thread {
+        id,
+        name,
+        rsp,
+        _stack: Some(stack),
+    });
+    let main = Box::new(Thread {
+        id: ThreadId::MAIN,
+        name: "main",
+        rsp: 0,
+        _stack: None,
+    });
+
+    interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        if scheduler.current.is_none() {
+            scheduler.current = Some(main);
+            scheduler.alive = 1;
+        }
+        scheduler.alive += 1;
+        // 切り替えのときにアロケートしないように，全スレッド分の容量を確保しておく
+        let alive = scheduler.alive;
+        scheduler.ready.reserve(alive);
+        scheduler.finished.reserve(alive);
+        scheduler.ready.push_back(thread);
+    });
+    ENABLED.store(true, Ordering::Release);
+    Ok(id)
+}
+
+/// switch_contextから戻ったときにthread_trampolineへ飛ぶように，スタックを用意する
+///
+/// 初期のスタックポインタを返す
+unsafe fn init_stack(top: u64, entry: *mut Entry) -> u64 {
+    let frame = [
+        0,                                 // r15
+        0,                                 // r14
+        0,                                 // r13
+        entry as u64,                      // r12
+        0,                                 // rbp
+        0,                                 // rbx
+        thread_trampoline as usize as u64, // 戻り先
+    ];
+    let rsp = top - (frame.len() * 8) as u64;
+    core::ptr::copy_nonoverlapping(frame.as_ptr(), rsp as *mut u64, frame.len());
+    rsp
+}
+
+/// 実行中のスレッドのID
+///
+/// スレッドを1つも作っていなければメインスレッド
+pub fn current_id() -> ThreadId {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .current
+            .as_ref()
+            .map_or(ThreadId::MAIN, |thread| thread.id)
+    })
+}
+
+/// 実行中のスレッドの名前
+pub fn current_name() -> &'static str {
+    interrupts::without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .current
+            .as_ref()
+            .map_or("main", |thread| thread.name)
+    })
+}
+
+/// スレッドがまだ終了していないかどうか
+pub fn is_alive(id: ThreadId) -> bool {
+    interrupts::without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        scheduler.current.iter().any(|thread| thread.id == id)
+            || scheduler.ready.iter().any(|thread| thread.id == id)
+    })
+}
+
+/// 自分以外に実行可能なスレッドがあるかどうか
+///
+/// APから呼ばれたら常にfalse
+pub fn has_ready_threads() -> bool {
+    on_bsp() && interrupts::without_interrupts(|| !SCHEDULER.lock().ready.is_empty())
+}
+
+/// 他のスレッドに実行を譲る
+///
+/// APから呼ばれたら何もしない
+pub fn yield_now() {
+    if on_bsp() {
+        interrupts::without_interrupts(|| schedule(false));
+    }
+}
+
+/// スケジューラは1つで，`current`はBSPで動いているスレッドを指す
+///
+/// APから切り替えると，BSPのスレッドのスタックを奪ってしまう
+fn on_bsp() -> bool {
+    crate::smp::cpu_index() == 0
+}
+
+/// 実行中のスレッドを終了する
+///
+/// メインスレッドから呼んではいけない
+pub fn exit() -> ! {
+    interrupts::disable();
+    {
+        let mut scheduler = SCHEDULER.lock();
+        let current = scheduler.current.as_ref().expect("no thread is running");
+        assert_ne!(current.id, ThreadId::MAIN, "main thread cannot exit");
+        scheduler.alive -= 1;
+    }
+    schedule(true);
+    unreachable!("finished thread was scheduled again");
+}
+
+/// タイマ割り込みハンドラから呼ばれる
+///
+/// 切り替えが必要かを記録するだけで，実際の切り替えはEOIを送った後に`preempt`で行う
+pub(crate) fn timer_tick() {
+    if ENABLED.load(Ordering::Acquire)
+        && TICKS_IN_SLICE.fetch_add(1, Ordering::Relaxed) + 1 >= TIME_SLICE_TICKS
+    {
+        NEED_RESCHED.store(true, Ordering::Relaxed);
+    }
+}
+
+/// IRQの処理を終えたときに呼ばれ，タイムスライスを使い切っていれば切り替える
+///
+/// 割り込みハンドラの中なので，割り込みは無効になっている
+pub(crate) fn preempt() {
+    // ユーザプロセスの実行中は，TSSのスタックやシステムコールのスタックを
+    // 他のスレッドと共有しているので切り替えない
+    if process::current_pid() != Pid::KERNEL {
+        return;
+    }
+    if NEED_RESCHED.swap(false, Ordering::Relaxed) {
+        schedule(false);
+    }
+}
+
+/// 次のスレッドに切り替える
+///
+/// 割り込みを無効にして呼ぶ必要がある
+/// アロケートも解放もしないので，割り込みハンドラから呼んでもよい
+fn schedule(current_finished: bool) {
+    let (old_rsp, new_rsp) = {
+        let mut scheduler = SCHEDULER.lock();
+        let next = match scheduler.ready.pop_front() {
+            Some(next) => next,
+            // 他に動けるスレッドがなければそのまま続ける
+            None => return,
+        };
+        let mut current = scheduler.current.take().expect("no thread is running");
+        // Boxの中身は動かないので，ロックを外した後もポインタは有効
+        let old_rsp: *mut u64 = &mut current.rsp;
+        if current_finished {
+            scheduler.finished.push(current);
+        } else {
+            scheduler.ready.push_back(current);
+        }
+        let new_rsp = next.rsp;
+        scheduler.current = Some(next);
+        (old_rsp, new_rsp)
+    };
+    TICKS_IN_SLICE.store(0, Ordering::Relaxed);
+
+    // ロックを持ったまま切り替えない
+    unsafe { switch_context(old_rsp, new_rsp) };
+}
+
+/// 終了したスレッドを解放し，スタックを使い回せるようにする
+///
+/// 解放はヒープのロックを取るので，割り込みハンドラの中ではなくここで行う
+fn reap() {
+    let finished =
+        interrupts::without_interrupts(|| core::mem::take(&mut SCHEDULER.lock().finished));
+    drop(finished);
+}
@@ -1,5 +1,6 @@
 pub mod colored_letter;
 
+use alloc::string::String;
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -67,6 +68,11 @@ impl ColorCode {
         // はじめの4ビットが背景色、次の4ビットが前景色ってこと？
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
+
+    /// 前景色と背景色を入れ替える
+    fn inverted(self) -> ColorCode {
+        ColorCode(self.0 << 4 | self.0 >> 4)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -77,8 +83,11 @@ struct ScrernChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
+
+/// 一番上の行はステータス表示に使い，スクロールしない
+const STATUS_ROW: usize = 0;
 
 #[repr(transparent)]
 struct Buffer {
@@ -90,6 +99,8 @@ pub struct Writer {
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    /// マウスカーソルのある(行, 列)．そのマスは色を反転して表示する
+    mouse_cursor: Option<(usize, usize)>,
 }
 
 impl Writer {
@@ -118,10 +129,14 @@ impl Writer {
 
                 let color_code = self.color_code;
 
-                self.buffer.chars[row][col].write(ScrernChar {
-                    ascii_character: byte,
-                    color_code,
-                });
+                self.write_cell(
+                    row,
+                    col,
+                    ScrernChar {
+                        ascii_character: byte,
+                        color_code,
+                    },
+                );
 
                 self.column_position += 1;
             }
@@ -129,10 +144,10 @@ impl Writer {
     }
 
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
+        for row in STATUS_ROW + 2..BUFFER_HEIGHT {
             for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                let character = self.read_cell(row, col);
+                self.write_cell(row - 1, col, character);
             }
         }
 
@@ -140,13 +155,85 @@ impl Writer {
         self.column_position = 0;
     }
 
+    /// 一番下の行を`s`で書き直し，ハードウェアカーソルを`cursor`の列に置く
+    fn replace_last_line(&mut self, s: &str, cursor: usize) {
+        self.clear_row(BUFFER_HEIGHT - 1);
+        self.column_position = 0;
+        // 1文字を1マスにするため，ASCII以外は■にする
+        for character in s.chars().take(BUFFER_WIDTH) {
+            match character {
+                ' '..='~' => self.write_byte(character as u8),
+                _ => self.write_byte(0xfe),
+            }
+        }
+        set_hardware_cursor(BUFFER_HEIGHT - 1, cursor.min(BUFFER_WIDTH - 1));
+    }
+
+    /// ステータス行以外を消す
+    fn clear_screen(&mut self) {
+        for row in STATUS_ROW + 1..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.column_position = 0;
+    }
+
+    /// ステータス行の`column`から`s`を書く
+    ///
+    /// はみ出した分は捨てる
+    fn write_status(&mut self, column: usize, s: &str, color_code: ColorCode) {
+        for (col, byte) in (column..BUFFER_WIDTH).zip(s.bytes()) {
+            let ascii_character = match byte {
+                0x20..=0x7e => byte,
+                _ => 0xfe,
+            };
+            self.write_cell(
+                STATUS_ROW,
+                col,
+                ScrernChar {
+                    ascii_character,
+                    color_code,
+                },
+            );
+        }
+    }
+
     fn clear_row(&mut self, row: usize) {
         let blank = ScrernChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
         for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+            self.write_cell(row, col, blank);
+        }
+    }
+
+    /// マウスカーソルによる反転を除いた，本来の文字を読む
+    fn read_cell(&self, row: usize, col: usize) -> ScrernChar {
+        let mut character = self.buffer.chars[row][col].read();
+        if self.mouse_cursor == Some((row, col)) {
+            character.color_code = character.color_code.inverted();
+        }
+        character
+    }
+
+    /// マスに書く．マウスカーソルのあるマスなら反転して書く
+    fn write_cell(&mut self, row: usize, col: usize, mut character: ScrernChar) {
+        if self.mouse_cursor == Some((row, col)) {
+            character.color_code = character.color_code.inverted();
+        }
+        self.buffer.chars[row][col].write(character);
+    }
+
+    fn set_mouse_cursor(&mut self, position: Option<(usize, usize)>) {
+        if let Some((row, col)) = self.mouse_cursor {
+            let character = self.read_cell(row, col);
+            self.mouse_cursor = None;
+            self.write_cell(row, col, character);
+        }
+        if let Some((row, col)) = position {
+            let character = self.read_cell(row, col);
+            self.mouse_cursor = Some((row, col));
+            self.write_cell(row, col, character);
         }
     }
 }
@@ -164,6 +251,79 @@ lazy_static! {
         column_position: 0,
         color_code: ColorCode::new(Color::Yellow, Color::Black),
         buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+        mouse_cursor: None,
+    });
+}
+
+/// 入力中の行を書き直す
+///
+/// 行エディタが，カーソルの移動や削除のたびに呼ぶ
+pub fn replace_last_line(s: &str, cursor: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().replace_last_line(s, cursor);
+    });
+}
+
+/// 画面を消す．ステータス行は残す
+pub fn clear_screen() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().clear_screen();
+    });
+}
+
+/// 点滅するハードウェアカーソルを動かす
+fn set_hardware_cursor(row: usize, col: usize) {
+    use x86_64::instructions::port::Port;
+
+    let position = (row * BUFFER_WIDTH + col) as u16;
+    let mut index: Port<u8> = Port::new(0x3d4);
+    let mut data: Port<u8> = Port::new(0x3d5);
+    unsafe {
+        index.write(0x0f);
+        data.write(position as u8);
+        index.write(0x0e);
+        data.write((position >> 8) as u8);
+    }
+}
+
+/// マウスカーソルを(行, 列)に動かす．`None`なら消す
+///
+/// 画面からはみ出す位置は端に寄せる
+pub fn set_mouse_cursor(position: Option<(usize, usize)>) {
+    use x86_64::instructions::interrupts;
+
+    let position =
+        position.map(|(row, col)| (row.min(BUFFER_HEIGHT - 1), col.min(BUFFER_WIDTH - 1)));
+    interrupts::without_interrupts(|| {
+        WRITER.lock().set_mouse_cursor(position);
+    });
+}
+
+/// 画面の`row`行目の文字を読む．ASCII以外は空白にする
+pub fn read_row(row: usize) -> String {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        (0..BUFFER_WIDTH)
+            .map(|col| match writer.read_cell(row, col).ascii_character {
+                byte @ 0x20..=0x7e => char::from(byte),
+                _ => ' ',
+            })
+            .collect()
+    })
+}
+
+/// 画面の一番上の行に，スクロールしない文字列を書く
+pub fn write_status(column: usize, s: &str, color_code: ColorCode) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITER.lock().write_status(column, s, color_code);
     });
 }
 
@@ -195,3 +355,42 @@ fn test_println_output() {
         }
     });
 }
+
+#[test_case]
+fn test_status_row_does_not_scroll() {
+    use x86_64::instructions::interrupts;
+
+    let color_code = ColorCode::new(Color::White, Color::Blue);
+    write_status(BUFFER_WIDTH - 6, "status", color_code);
+    for _ in 0..BUFFER_HEIGHT {
+        println!("scroll");
+    }
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        for (i, c) in "status".chars().enumerate() {
+            let screen_char = writer.buffer.chars[STATUS_ROW][BUFFER_WIDTH - 6 + i].read();
+            assert_eq!(char::from(screen_char.ascii_character), c);
+            assert_eq!(screen_char.color_code, color_code);
+        }
+    });
+}
+
+#[test_case]
+fn test_mouse_cursor_survives_scrolling() {
+    use x86_64::instructions::interrupts;
+
+    println!("\nclick");
+    set_mouse_cursor(Some((BUFFER_HEIGHT - 2, 0)));
+    assert_eq!(&read_row(BUFFER_HEIGHT - 2)[..5], "click");
+    println!();
+    // 画面に書いた色はそのままで，カーソルのマスだけ反転している
+    interrupts::without_interrupts(|| {
+        let writer = WRITER.lock();
+        let under_cursor = writer.buffer.chars[BUFFER_HEIGHT - 2][0].read();
+        let scrolled = writer.buffer.chars[BUFFER_HEIGHT - 3][0].read();
+        assert_eq!(char::from(scrolled.ascii_character), 'c');
+        assert_eq!(scrolled.color_code, writer.color_code);
+        assert_eq!(under_cursor.color_code, scrolled.color_code.inverted());
+    });
+    set_mouse_cursor(None);
+}
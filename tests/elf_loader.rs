@@ -0,0 +1,107 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use bootloader::{entry_point, BootInfo};
+use core::convert::TryInto;
+use core::panic::PanicInfo;
+use spin::Mutex;
+use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+use wos_os_n71::process::elf::{self, ElfError, ElfFile, LoadError, PF_W, PF_X, PT_LOAD};
+use wos_os_n71::process::ExitStatus;
+use x86_64::structures::paging::OffsetPageTable;
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// tests/programs/hello.s をビルドしたもの
+static HELLO: &[u8] = include_bytes!("programs/hello.elf");
+
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+#[test_case]
+fn parse_program_headers() {
+    let elf = ElfFile::parse(HELLO).expect("failed to parse");
+    assert_eq!(elf.entry(), VirtAddr::new(0x4000_0000_0000));
+
+    let loads: Vec<_> = elf
+        .program_headers()
+        .filter(|h| h.p_type == PT_LOAD)
+        .collect();
+    assert_eq!(loads.len(), 2);
+    // .textは実行可能で書き込み不可，.data/.bssは書き込み可能で実行不可
+    assert_eq!(loads[0].flags & (PF_W | PF_X), PF_X);
+    assert_eq!(loads[1].flags & (PF_W | PF_X), PF_W);
+    assert!(loads[1].memsz > loads[1].filesz);
+}
+
+#[test_case]
+fn reject_invalid_images() {
+    assert_eq!(ElfFile::parse(&HELLO[..16]).err(), Some(ElfError::TooShort));
+
+    let mut image = Vec::from(HELLO);
+    image[0] = 0;
+    assert_eq!(ElfFile::parse(&image).err(), Some(ElfError::BadMagic));
+
+    let mut image = Vec::from(HELLO);
+    // e_machine
+    image[18] = 0x28;
+    assert_eq!(ElfFile::parse(&image).err(), Some(ElfError::WrongMachine));
+
+    // セグメントの中身が切り取られている
+    let image = &HELLO[..0x1010];
+    assert_eq!(ElfFile::parse(image).err(), Some(ElfError::BadSegment));
+
+    // 最初のプログラムヘッダのp_vaddrをカーネルの領域に書き換える
+    let mut image = Vec::from(HELLO);
+    let phoff = u64::from_le_bytes(image[32..40].try_into().unwrap()) as usize;
+    image[phoff + 16..phoff + 24].copy_from_slice(&0xffff_ffff_ffff_f000u64.to_le_bytes());
+    assert_eq!(ElfFile::parse(&image).err(), Some(ElfError::BadSegment));
+}
+
+#[test_case]
+fn run_loaded_program() {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory not initialized");
+    let mut process = elf::spawn(mapper, frame_allocator, HELLO, &["hello", "card"])
+        .expect("failed to load program");
+    drop(memory);
+
+    // argc * 100 + strlen(argv[1])
+    assert_eq!(process.run(), ExitStatus::Exited(204));
+}
+
+#[test_case]
+fn arguments_must_fit_on_the_stack() {
+    let long_arg = alloc::string::String::from_utf8(alloc::vec![b'a'; 64 * 1024]).unwrap();
+
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory not initialized");
+    let result = elf::spawn(mapper, frame_allocator, HELLO, &["hello", &long_arg]);
+    assert!(matches!(result, Err(LoadError::ArgumentsTooLong)));
+}
@@ -0,0 +1,356 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Poll;
+use wos_os_n71::task::executor::{Executor, Spawner};
+use wos_os_n71::task::join::JoinError;
+use wos_os_n71::task::monitor::TaskState;
+use wos_os_n71::task::{yield_now, Priority};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+async fn child(finished: Arc<AtomicUsize>) {
+    finished.fetch_add(1, Ordering::Relaxed);
+}
+
+async fn parent(spawner: Spawner, finished: Arc<AtomicUsize>, children: usize) {
+    for _ in 0..children {
+        spawner.spawn(child(finished.clone()));
+    }
+    finished.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn task_can_spawn_tasks() {
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    executor.spawn(parent(spawner, finished.clone(), 10));
+    executor.run_until_complete();
+
+    assert_eq!(finished.load(Ordering::Relaxed), 11);
+}
+
+#[test_case]
+fn spawned_tasks_can_spawn_again() {
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    let grandparent = {
+        let spawner = spawner.clone();
+        let finished = finished.clone();
+        async move {
+            for _ in 0..3 {
+                spawner.spawn(parent(spawner.clone(), finished.clone(), 2));
+            }
+        }
+    };
+    executor.spawn(grandparent);
+    executor.run_until_complete();
+
+    // 親が3つ，子が6つ
+    assert_eq!(finished.load(Ordering::Relaxed), 9);
+}
+
+#[test_case]
+fn join_handle_returns_output() {
+    let result = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    let r = result.clone();
+    executor.spawn(async move {
+        let handle = spawner.spawn(async { 40 + 2 });
+        r.store(handle.await.unwrap(), Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(result.load(Ordering::Relaxed), 42);
+}
+
+#[test_case]
+fn abort_cancels_pending_task() {
+    let cancelled = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    let c = cancelled.clone();
+    executor.spawn(async move {
+        // 永遠に終わらないタスク
+        let handle = spawner.spawn(futures_util::future::pending::<()>());
+        handle.abort();
+        if handle.await == Err(JoinError::Cancelled) {
+            c.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    // 中断されたタスクがtasksから取り除かれないと，ここから戻らない
+    executor.run_until_complete();
+
+    assert_eq!(cancelled.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn abort_completes_handle_and_drops_future() {
+    struct DropCounter(Arc<AtomicUsize>);
+
+    impl Drop for DropCounter {
+        fn drop(&mut self) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let checked = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    let guard = DropCounter(dropped.clone());
+    let handle = executor.spawn(async move {
+        let _guard = guard;
+        futures_util::future::pending::<()>().await;
+    });
+    let c = checked.clone();
+    executor.spawn(async move {
+        // 中断するタスクが一度ポーリングされてから
+        yield_now().await;
+        handle.abort();
+        // タスクがもう一度ポーリングされるのを待たずに結果が決まる
+        assert!(handle.is_finished());
+        assert_eq!(handle.await, Err(JoinError::Cancelled));
+        c.fetch_add(1, Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(checked.load(Ordering::Relaxed), 1);
+    assert_eq!(dropped.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn abort_after_completion_keeps_result() {
+    let result = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let handle = executor.spawn(async { 7 });
+    executor.run_until_complete();
+
+    assert!(handle.is_finished());
+    handle.abort();
+
+    let r = result.clone();
+    executor.spawn(async move {
+        r.store(handle.await.unwrap(), Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(result.load(Ordering::Relaxed), 7);
+}
+
+#[test_case]
+fn many_tasks_and_wakeups_do_not_overflow() {
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    for _ in 0..200 {
+        let finished = finished.clone();
+        executor.spawn(async move {
+            for _ in 0..5 {
+                // 何度wakeしてもキューには1つしか入らない
+                futures_util::future::poll_fn(|cx| {
+                    for _ in 0..10 {
+                        cx.waker().wake_by_ref();
+                    }
+                    Poll::Ready(())
+                })
+                .await;
+                yield_now().await;
+            }
+            finished.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    executor.run_until_complete();
+
+    assert_eq!(finished.load(Ordering::Relaxed), 200);
+}
+
+#[test_case]
+fn spawner_accepts_hundreds_of_tasks() {
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    let spawner = executor.spawner();
+
+    let f = finished.clone();
+    executor.spawn(async move {
+        for _ in 0..200 {
+            spawner.spawn(child(f.clone()));
+        }
+    });
+    executor.run_until_complete();
+
+    assert_eq!(finished.load(Ordering::Relaxed), 200);
+}
+
+#[test_case]
+fn every_priority_is_polled_every_round() {
+    let others = Arc::new(AtomicUsize::new(0));
+    let max_gap = Arc::new(AtomicUsize::new(0));
+    let done = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    // どの優先度も予算より多くのタスクがwakeし続ける
+    for (priority, count) in [(Priority::High, 12), (Priority::Normal, 6)] {
+        for _ in 0..count {
+            let (others, done) = (others.clone(), done.clone());
+            executor.spawn_with_priority(
+                async move {
+                    while done.load(Ordering::Relaxed) == 0 {
+                        others.fetch_add(1, Ordering::Relaxed);
+                        yield_now().await;
+                    }
+                },
+                priority,
+            );
+        }
+    }
+    let (o, gap, d) = (others.clone(), max_gap.clone(), done.clone());
+    executor.spawn_with_priority(
+        async move {
+            let mut last = 0;
+            for _ in 0..20 {
+                let now = o.load(Ordering::Relaxed);
+                gap.fetch_max(now - last, Ordering::Relaxed);
+                last = now;
+                yield_now().await;
+            }
+            d.store(1, Ordering::Relaxed);
+        },
+        Priority::Low,
+    );
+    executor.run_until_complete();
+
+    // HighとNormalが1巡の予算を使い切る前に，Lowの番が来る
+    let round = Priority::High.poll_budget() + Priority::Normal.poll_budget();
+    let max_gap = max_gap.load(Ordering::Relaxed);
+    assert!(max_gap > 0);
+    assert!(max_gap <= round);
+}
+
+#[test_case]
+fn low_priority_task_still_makes_progress() {
+    let order = Arc::new(AtomicUsize::new(0));
+    let low_finished_at = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    for _ in 0..4 {
+        let order = order.clone();
+        executor.spawn_with_priority(
+            async move {
+                for _ in 0..50 {
+                    yield_now().await;
+                }
+                order.fetch_add(1, Ordering::Relaxed);
+            },
+            Priority::High,
+        );
+    }
+    let (o, finished_at) = (order.clone(), low_finished_at.clone());
+    executor.spawn_with_priority(
+        async move {
+            yield_now().await;
+            finished_at.store(o.fetch_add(1, Ordering::Relaxed) + 1, Ordering::Relaxed);
+        },
+        Priority::Low,
+    );
+    executor.run_until_complete();
+
+    // 高優先度のタスクがすべて終わる前に，低優先度のタスクが終わっている
+    assert_eq!(low_finished_at.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn monitor_lists_task_names_and_states() {
+    let mut executor = Executor::new();
+    let monitor = executor.monitor();
+
+    let waiting = executor.spawn_named(
+        futures_util::future::pending::<()>(),
+        "waiting",
+        Priority::Low,
+    );
+    executor.spawn_named(
+        async {
+            for _ in 0..3 {
+                yield_now().await;
+            }
+        },
+        "yielding",
+        Priority::Normal,
+    );
+    let m = monitor.clone();
+    executor.spawn_named(
+        async move {
+            // 自分自身はポーリング中に見える
+            let me = m.tasks().into_iter().find(|info| info.name == "inspector");
+            assert_eq!(me.map(|info| info.state), Some(TaskState::Running));
+            waiting.abort();
+        },
+        "inspector",
+        Priority::High,
+    );
+    executor.run_until_complete();
+
+    let tasks = monitor.tasks();
+    assert_eq!(monitor.live_task_count(), 0);
+    let yielding = tasks
+        .iter()
+        .find(|info| info.name == "yielding")
+        .expect("finished task is not listed");
+    assert_eq!(yielding.state, TaskState::Done);
+    assert_eq!(yielding.priority, Priority::Normal);
+    // 3回Pendingを返して，4回目で終わる
+    assert_eq!(yielding.poll_count, 4);
+    assert!(yielding.total_poll_cycles > 0);
+    assert!(tasks.iter().any(|info| info.name == "waiting"));
+}
+
+#[test_case]
+fn unnamed_task_is_named_after_its_future() {
+    let mut executor = Executor::new();
+    let monitor = executor.monitor();
+
+    executor.spawn(child(Arc::new(AtomicUsize::new(0))));
+    executor.run_until_complete();
+
+    let tasks = monitor.tasks();
+    assert_eq!(tasks.len(), 1);
+    assert!(tasks[0].name.contains("child"));
+}
@@ -0,0 +1,511 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use pc_keyboard::{DecodedKey, HandleControl, KeyCode, KeyState};
+use wos_os_n71::ps2::ScancodeSet;
+use wos_os_n71::task::keyboard::event::{KeyEvent, Modifiers};
+use wos_os_n71::task::keyboard::hotkey::{self, Action, HotkeyRegistry, KeyCombo};
+use wos_os_n71::task::keyboard::layout::{self, Layout};
+use wos_os_n71::task::keyboard::line_editor::LineEditor;
+use wos_os_n71::task::keyboard::service::KeyboardService;
+use wos_os_n71::task::keyboard::{self, KeyEventStream, ScancodeStream};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+// スキャンコードセット1
+const Z_PRESS: u8 = 0x2c;
+const Z_RELEASE: u8 = 0xac;
+const BRACKET_PRESS: u8 = 0x1a;
+const TWO_PRESS: u8 = 0x03;
+const LEFT_SHIFT_PRESS: u8 = 0x2a;
+const LEFT_SHIFT_RELEASE: u8 = 0xaa;
+const F12_PRESS: u8 = 0x58;
+const F12_RELEASE: u8 = 0xd8;
+
+/// スキャンコードを順に入れ，出てきた最後のキーを返す
+fn feed(service: &mut KeyboardService, scancodes: &[u8]) -> Option<DecodedKey> {
+    scancodes.iter().fold(None, |last, &scancode| {
+        service.process_scancode(scancode).or(last)
+    })
+}
+
+#[test_case]
+fn layout_can_be_switched_through_api() {
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::new();
+    assert_eq!(
+        feed(&mut service, &[Z_PRESS, Z_RELEASE]),
+        Some(DecodedKey::Unicode('z'))
+    );
+
+    // QWERTZ配列ではZとYが入れ替わる
+    layout::set_layout(Layout::De105);
+    assert_eq!(
+        feed(&mut service, &[Z_PRESS, Z_RELEASE]),
+        Some(DecodedKey::Unicode('y'))
+    );
+    assert_eq!(service.layout(), Layout::De105);
+}
+
+#[test_case]
+fn jis_and_uk_layouts_decode_symbols() {
+    let mut service = KeyboardService::new();
+
+    layout::set_layout(Layout::Jis109);
+    assert_eq!(
+        feed(&mut service, &[BRACKET_PRESS]),
+        Some(DecodedKey::Unicode('@'))
+    );
+
+    layout::set_layout(Layout::Uk105);
+    assert_eq!(
+        feed(
+            &mut service,
+            &[LEFT_SHIFT_PRESS, TWO_PRESS, LEFT_SHIFT_RELEASE]
+        ),
+        Some(DecodedKey::Unicode('"'))
+    );
+}
+
+#[test_case]
+fn hotkey_cycles_layouts() {
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::new();
+
+    // 切り替えキーそのものは入力として返さない
+    assert_eq!(feed(&mut service, &[F12_PRESS, F12_RELEASE]), None);
+    assert_eq!(layout::active_layout(), Layout::Jis109);
+    // 表示の更新は呼び出し側に任せる
+    assert_eq!(service.take_layout_switch(), Some(Layout::Jis109));
+    assert_eq!(service.take_layout_switch(), None);
+
+    for _ in 0..3 {
+        feed(&mut service, &[F12_PRESS, F12_RELEASE]);
+    }
+    assert_eq!(layout::active_layout(), Layout::Us104);
+}
+
+#[test_case]
+fn layout_names_are_short() {
+    for layout in Layout::ALL.iter() {
+        assert!(layout.name().len() <= 3);
+    }
+}
+
+/// 文字列を1文字ずつ入れる
+fn type_str(editor: &mut LineEditor, s: &str) {
+    for character in s.chars() {
+        assert_eq!(editor.handle_key(DecodedKey::Unicode(character)), None);
+    }
+}
+
+fn enter(editor: &mut LineEditor) -> Option<String> {
+    editor.handle_key(DecodedKey::Unicode('\n'))
+}
+
+#[test_case]
+fn line_editor_returns_typed_line() {
+    let mut editor = LineEditor::new(70, 4);
+    type_str(&mut editor, "help");
+    assert_eq!(enter(&mut editor).as_deref(), Some("help"));
+    assert_eq!(editor.line(), "");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn line_editor_edits_at_cursor() {
+    let mut editor = LineEditor::new(70, 4);
+    type_str(&mut editor, "helo");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowLeft));
+    type_str(&mut editor, "l");
+    assert_eq!(editor.line(), "hello");
+    assert_eq!(editor.cursor(), 4);
+
+    editor.handle_key(DecodedKey::RawKey(KeyCode::Home));
+    editor.handle_key(DecodedKey::Unicode('\u{7f}'));
+    assert_eq!(editor.line(), "ello");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::End));
+    editor.handle_key(DecodedKey::Unicode('\u{8}'));
+    assert_eq!(editor.line(), "ell");
+    assert_eq!(editor.cursor(), 3);
+}
+
+#[test_case]
+fn line_editor_ctrl_u_kills_before_cursor() {
+    let mut editor = LineEditor::new(70, 4);
+    type_str(&mut editor, "foo bar");
+    for _ in 0..3 {
+        editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowLeft));
+    }
+    editor.handle_key(DecodedKey::Unicode('\u{15}'));
+    assert_eq!(editor.line(), "bar");
+    assert_eq!(editor.cursor(), 0);
+}
+
+#[test_case]
+fn line_editor_respects_max_len() {
+    let mut editor = LineEditor::new(3, 4);
+    type_str(&mut editor, "abcd");
+    assert_eq!(enter(&mut editor).as_deref(), Some("abc"));
+}
+
+#[test_case]
+fn line_editor_walks_history() {
+    let mut editor = LineEditor::new(70, 2);
+    for line in &["one", "two", "three"] {
+        type_str(&mut editor, line);
+        enter(&mut editor);
+    }
+    // 容量が2なので"one"は捨てられている
+    assert_eq!(
+        editor.history().iter().collect::<Vec<_>>(),
+        ["two", "three"]
+    );
+
+    type_str(&mut editor, "dra");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp));
+    assert_eq!(editor.line(), "three");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp));
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowUp));
+    assert_eq!(editor.line(), "two");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowDown));
+    assert_eq!(editor.line(), "three");
+    editor.handle_key(DecodedKey::RawKey(KeyCode::ArrowDown));
+    assert_eq!(editor.line(), "dra");
+    assert_eq!(editor.cursor(), 3);
+}
+
+#[test_case]
+fn line_editor_skips_empty_and_repeated_lines() {
+    let mut editor = LineEditor::new(70, 4);
+    enter(&mut editor);
+    type_str(&mut editor, "ls");
+    enter(&mut editor);
+    type_str(&mut editor, "ls");
+    enter(&mut editor);
+    assert_eq!(editor.history().len(), 1);
+}
+
+#[test_case]
+fn ctrl_letters_map_to_control_characters() {
+    const LEFT_CTRL_PRESS: u8 = 0x1d;
+    const U_PRESS: u8 = 0x16;
+    let mut service = KeyboardService::with_handle_control(HandleControl::MapLettersToUnicode);
+    assert_eq!(
+        feed(&mut service, &[LEFT_CTRL_PRESS, U_PRESS]),
+        Some(DecodedKey::Unicode('\u{15}'))
+    );
+}
+
+// スキャンコードセット2
+const SET2_A_PRESS: u8 = 0x1c;
+const SET2_LEFT_SHIFT_PRESS: u8 = 0x12;
+const SET2_RELEASE_PREFIX: u8 = 0xf0;
+const SET2_EXTENDED_PREFIX: u8 = 0xe0;
+const SET2_ARROW_LEFT: u8 = 0x6b;
+
+#[test_case]
+fn scancode_set_2_is_decoded() {
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::with_scancode_set(ScancodeSet::Set2, HandleControl::Ignore);
+    assert_eq!(service.scancode_set(), ScancodeSet::Set2);
+
+    assert_eq!(
+        feed(&mut service, &[SET2_A_PRESS]),
+        Some(DecodedKey::Unicode('a'))
+    );
+    // 解放はプレフィックスの後に同じコードが来る
+    assert_eq!(
+        feed(&mut service, &[SET2_RELEASE_PREFIX, SET2_A_PRESS]),
+        None
+    );
+    assert_eq!(
+        feed(&mut service, &[SET2_LEFT_SHIFT_PRESS, SET2_A_PRESS]),
+        Some(DecodedKey::Unicode('A'))
+    );
+
+    let arrow = [SET2_EXTENDED_PREFIX, SET2_ARROW_LEFT];
+    let event = arrow
+        .iter()
+        .filter_map(|&scancode| service.process_key_event(scancode))
+        .last()
+        .unwrap();
+    assert_eq!(event.code, KeyCode::ArrowLeft);
+    assert!(event.is_press());
+}
+
+#[test_case]
+fn scancode_set_survives_layout_switch() {
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::with_scancode_set(ScancodeSet::Set2, HandleControl::Ignore);
+    layout::set_layout(Layout::Jis109);
+    assert_eq!(
+        feed(&mut service, &[SET2_A_PRESS]),
+        Some(DecodedKey::Unicode('a'))
+    );
+    assert_eq!(service.scancode_set(), ScancodeSet::Set2);
+    layout::set_layout(Layout::Us104);
+}
+
+#[test_case]
+fn key_events_carry_state_and_modifiers() {
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::new();
+
+    let shift = service.process_key_event(LEFT_SHIFT_PRESS).unwrap();
+    assert_eq!(shift.code, KeyCode::LShift);
+    assert!(shift.is_press());
+    assert_eq!(shift.key, None);
+    assert_eq!(shift.modifiers, Modifiers::SHIFT);
+
+    let z = service.process_key_event(Z_PRESS).unwrap();
+    assert_eq!(z.code, KeyCode::Z);
+    assert_eq!(z.key, Some(DecodedKey::Unicode('Z')));
+    assert!(z.modifiers.contains(Modifiers::SHIFT));
+
+    let release = service.process_key_event(Z_RELEASE).unwrap();
+    assert!(release.is_release());
+    assert_eq!(release.key, None);
+
+    service.process_key_event(LEFT_SHIFT_RELEASE);
+    assert!(service.modifiers().is_empty());
+}
+
+#[test_case]
+fn modifiers_survive_layout_switch() {
+    const LEFT_CTRL_PRESS: u8 = 0x1d;
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::with_handle_control(HandleControl::MapLettersToUnicode);
+    service.process_key_event(LEFT_CTRL_PRESS);
+    layout::set_layout(Layout::Uk105);
+    let z = service.process_key_event(Z_PRESS).unwrap();
+    assert!(z.modifiers.contains(Modifiers::CTRL));
+    // 新しいデコーダもCtrlが押されているとわかっている
+    assert_eq!(z.key, Some(DecodedKey::Unicode('\u{1a}')));
+    layout::set_layout(Layout::Us104);
+}
+
+#[test_case]
+fn caps_lock_survives_layout_switch() {
+    const CAPS_LOCK_PRESS: u8 = 0x3a;
+    const CAPS_LOCK_RELEASE: u8 = 0xba;
+    layout::set_layout(Layout::Us104);
+    let mut service = KeyboardService::new();
+    feed(&mut service, &[CAPS_LOCK_PRESS, CAPS_LOCK_RELEASE]);
+    layout::set_layout(Layout::Uk105);
+    assert_eq!(
+        feed(&mut service, &[Z_PRESS, Z_RELEASE]),
+        Some(DecodedKey::Unicode('Z'))
+    );
+    assert!(service.locks().caps_lock);
+    layout::set_layout(Layout::Us104);
+}
+
+#[test_case]
+fn modifier_bits_combine() {
+    let mut modifiers = Modifiers::SHIFT | Modifiers::ALT;
+    assert!(modifiers.contains(Modifiers::SHIFT));
+    assert!(!modifiers.contains(Modifiers::SHIFT | Modifiers::CTRL));
+    modifiers.remove(Modifiers::SHIFT);
+    assert_eq!(modifiers, Modifiers::ALT);
+}
+
+#[test_case]
+fn streams_can_subscribe_many_times() {
+    let before = keyboard::subscriber_count();
+    let first = KeyEventStream::new().unwrap();
+    let second = KeyEventStream::new().unwrap();
+    let raw = ScancodeStream::new().unwrap();
+    assert_eq!(keyboard::subscriber_count(), before + 3);
+    drop((first, second, raw));
+    assert_eq!(keyboard::subscriber_count(), before);
+
+    // 解放した番号は使い回される
+    let streams: Vec<KeyEventStream> = (0..keyboard::MAX_SUBSCRIBERS - before)
+        .map(|_| KeyEventStream::new().unwrap())
+        .collect();
+    assert_eq!(keyboard::subscriber_count(), keyboard::MAX_SUBSCRIBERS);
+    // 満員なら，パニックせずにエラーを返す
+    assert_eq!(
+        ScancodeStream::new().err(),
+        Some(keyboard::QueueError::TooManySubscribers)
+    );
+    drop(streams);
+}
+
+#[test_case]
+fn streams_reject_zero_capacity() {
+    use keyboard::QueueError;
+
+    let before = keyboard::subscriber_count();
+    assert_eq!(
+        ScancodeStream::with_capacity(0).err(),
+        Some(QueueError::ZeroCapacity)
+    );
+    assert_eq!(
+        KeyEventStream::with_capacity(0).err(),
+        Some(QueueError::ZeroCapacity)
+    );
+    assert_eq!(keyboard::subscriber_count(), before);
+}
+
+#[test_case]
+fn scancode_queue_is_configured_once() {
+    use keyboard::QueueError;
+
+    assert_eq!(
+        keyboard::init_scancode_queue(0),
+        Err(QueueError::ZeroCapacity)
+    );
+    // 最初のストリームを作った時点で標準の長さになっている
+    let _stream = ScancodeStream::with_capacity(1).unwrap();
+    assert_eq!(
+        keyboard::init_scancode_queue(16),
+        Err(QueueError::AlreadyInitialized)
+    );
+}
+
+fn press(code: KeyCode, key: Option<DecodedKey>, modifiers: Modifiers) -> KeyEvent {
+    KeyEvent {
+        code,
+        state: KeyState::Down,
+        key,
+        modifiers,
+    }
+}
+
+#[test_case]
+fn hotkeys_match_chars_and_codes() {
+    let mut registry = HotkeyRegistry::new();
+    registry
+        .bind(
+            KeyCombo::char('n'),
+            Action::Command(String::from("profile name")),
+        )
+        .unwrap();
+    registry
+        .bind(
+            KeyCombo::code(KeyCode::F5).with(Modifiers::CTRL),
+            Action::Command(String::from("uptime")),
+        )
+        .unwrap();
+
+    let n = press(KeyCode::N, Some(DecodedKey::Unicode('n')), Modifiers::NONE);
+    assert!(matches!(registry.lookup(&n), Some(Action::Command(line)) if line == "profile name"));
+
+    // 修飾キーが違えば別の組み合わせ
+    let f5 = press(
+        KeyCode::F5,
+        Some(DecodedKey::RawKey(KeyCode::F5)),
+        Modifiers::NONE,
+    );
+    assert!(registry.lookup(&f5).is_none());
+    let ctrl_f5 = KeyEvent {
+        modifiers: Modifiers::CTRL,
+        ..f5
+    };
+    assert!(registry.lookup(&ctrl_f5).is_some());
+
+    // 離したときは反応しない
+    let release = KeyEvent {
+        state: KeyState::Up,
+        ..ctrl_f5
+    };
+    assert!(registry.lookup(&release).is_none());
+}
+
+#[test_case]
+fn char_hotkeys_ignore_shift() {
+    let combo = KeyCombo::char('N');
+    let shifted = press(KeyCode::N, Some(DecodedKey::Unicode('N')), Modifiers::SHIFT);
+    assert!(combo.matches(&shifted));
+    let ctrl = press(
+        KeyCode::N,
+        Some(DecodedKey::Unicode('N')),
+        Modifiers::SHIFT | Modifiers::CTRL,
+    );
+    assert!(!combo.matches(&ctrl));
+}
+
+#[test_case]
+fn hotkeys_can_be_rebound_and_removed() {
+    let mut registry = HotkeyRegistry::new();
+    let combo = KeyCombo::code(KeyCode::ArrowUp);
+    assert!(registry
+        .bind(combo, Action::Command(String::from("tasks")))
+        .unwrap()
+        .is_none());
+    assert!(matches!(
+        registry.bind(combo, Action::Command(String::from("uptime"))),
+        Ok(Some(Action::Command(line))) if line == "tasks"
+    ));
+    assert_eq!(registry.iter().count(), 1);
+    assert!(registry.unbind(combo).is_some());
+    assert!(registry.unbind(combo).is_none());
+}
+
+#[test_case]
+fn global_hotkeys_are_configurable_at_runtime() {
+    let combo = KeyCombo::code(KeyCode::F9);
+    let event = press(
+        KeyCode::F9,
+        Some(DecodedKey::RawKey(KeyCode::F9)),
+        Modifiers::NONE,
+    );
+    assert!(hotkey::lookup(&event).is_none());
+    hotkey::bind(combo, Action::Command(String::from("clear"))).unwrap();
+    assert!(hotkey::lookup(&event).is_some());
+    hotkey::unbind(combo);
+    assert!(hotkey::lookup(&event).is_none());
+}
+
+#[test_case]
+fn layout_switch_key_cannot_be_bound() {
+    use hotkey::BindError;
+
+    let mut registry = HotkeyRegistry::new();
+    for combo in [
+        KeyCombo::code(KeyCode::F12),
+        KeyCombo::code(KeyCode::F12).with(Modifiers::CTRL),
+    ] {
+        assert!(matches!(
+            registry.bind(combo, Action::Command(String::from("help"))),
+            Err(BindError::ReservedKey(KeyCode::F12))
+        ));
+    }
+    assert_eq!(registry.iter().count(), 0);
+    assert!(hotkey::bind(
+        KeyCombo::code(KeyCode::F12),
+        Action::Command(String::from("help"))
+    )
+    .is_err());
+}
@@ -0,0 +1,184 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::pin::Pin;
+use core::task::{Context, Poll};
+use futures_util::stream::StreamExt;
+use futures_util::task::noop_waker_ref;
+use wos_os_n71::ps2::{self, ScancodeSet};
+use wos_os_n71::task::keyboard::hotkey::{self, Action, KeyCombo};
+use wos_os_n71::task::keyboard::{self, introduction, ScancodeStream};
+use wos_os_n71::vga_buffer::{self, BUFFER_HEIGHT};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+/// 押したときのスキャンコード．(セット1, セット2)
+#[derive(Clone, Copy)]
+struct Key(u8, u8);
+
+const ESCAPE: Key = Key(0x01, 0x76);
+const A: Key = Key(0x1e, 0x1c);
+const H: Key = Key(0x23, 0x33);
+const I: Key = Key(0x17, 0x43);
+const N: Key = Key(0x31, 0x31);
+const Z: Key = Key(0x2c, 0x1a);
+/// セット1では，押したときのコードにこのビットを立てると離したときのコードになる
+const SET1_RELEASE: u8 = 0x80;
+/// セット2では，押したときのコードの前にこれを付けると離したときのコードになる
+const SET2_RELEASE: u8 = 0xf0;
+
+/// 今のセットで，キーを押して離したときのスキャンコード
+fn press_and_release(key: Key) -> Vec<u8> {
+    match ps2::scancode_set() {
+        ScancodeSet::Set1 => vec![key.0, key.0 | SET1_RELEASE],
+        ScancodeSet::Set2 => vec![key.1, SET2_RELEASE, key.1],
+    }
+}
+
+/// キーを順に押して離す
+fn type_keys(keys: &[Key]) {
+    for &key in keys {
+        keyboard::inject_scancodes(&press_and_release(key));
+    }
+}
+
+/// 何もせずに1度だけポーリングする．入力を待っているところで止まる
+fn poll_once<F: Future + ?Sized>(future: Pin<&mut F>) -> Poll<F::Output> {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    future.poll(&mut cx)
+}
+
+fn screen_contains(s: &str) -> bool {
+    (0..BUFFER_HEIGHT).any(|row| vga_buffer::read_row(row).contains(s))
+}
+
+/// 入力した文字が書かれる，一番下の行
+fn last_line() -> String {
+    vga_buffer::read_row(BUFFER_HEIGHT - 1)
+}
+
+#[test_case]
+fn injected_scancodes_reach_subscribers() {
+    let mut stream = ScancodeStream::new().unwrap();
+    let scancodes = press_and_release(N);
+    keyboard::inject_scancodes(&scancodes);
+
+    let mut received = Vec::new();
+    while let Poll::Ready(Some(scancode)) = poll_once(Pin::new(&mut stream.next())) {
+        received.push(scancode);
+    }
+    assert_eq!(received, scancodes);
+}
+
+#[test_case]
+fn full_subscribers_count_dropped_input() {
+    let mut stream = ScancodeStream::with_capacity(1).unwrap();
+    let before = keyboard::drop_stats().subscriber_full;
+    let scancodes = press_and_release(N);
+    keyboard::inject_scancodes(&scancodes);
+
+    assert_eq!(
+        poll_once(Pin::new(&mut stream.next())),
+        Poll::Ready(Some(scancodes[0]))
+    );
+    // 1つしか入らないので，残りは捨てられる
+    assert_eq!(
+        keyboard::drop_stats().subscriber_full,
+        before + scancodes.len() as u64 - 1
+    );
+}
+
+#[test_case]
+fn print_keypresses_echoes_typed_keys() {
+    vga_buffer::clear_screen();
+    let mut task = Box::pin(keyboard::print_keypresses());
+    // 最初のポーリングで購読を始める
+    assert!(poll_once(task.as_mut()).is_pending());
+
+    type_keys(&[H, I]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    assert!(last_line().trim_end().ends_with("hi"));
+}
+
+#[test_case]
+fn card_shows_sections_for_legend_keys() {
+    vga_buffer::clear_screen();
+    let mut task = Box::pin(introduction::print_keypresses());
+    assert!(poll_once(task.as_mut()).is_pending());
+
+    type_keys(&[N]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    assert!(screen_contains("name: neruneruna7"));
+
+    type_keys(&[A]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    assert!(screen_contains("age: 20"));
+}
+
+#[test_case]
+fn card_returns_to_icon_on_escape() {
+    vga_buffer::clear_screen();
+    let mut task = Box::pin(introduction::print_keypresses());
+    assert!(poll_once(task.as_mut()).is_pending());
+
+    type_keys(&[ESCAPE]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    // アイコンの下に凡例が書かれている
+    assert!(screen_contains("n: name, a: age"));
+}
+
+#[test_case]
+fn card_echoes_unbound_keys() {
+    vga_buffer::clear_screen();
+    let mut task = Box::pin(introduction::print_keypresses());
+    assert!(poll_once(task.as_mut()).is_pending());
+
+    type_keys(&[Z]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    assert!(last_line().trim_end().ends_with('z'));
+}
+
+#[test_case]
+fn card_echoes_keys_bound_to_shell_commands() {
+    let combo = KeyCombo::char('z');
+    hotkey::bind(combo, Action::Command(String::from("clear"))).unwrap();
+    vga_buffer::clear_screen();
+    let mut task = Box::pin(introduction::print_keypresses());
+    assert!(poll_once(task.as_mut()).is_pending());
+
+    type_keys(&[Z]);
+    assert!(poll_once(task.as_mut()).is_pending());
+    hotkey::unbind(combo);
+    // シェルがないのでコマンドは実行されず，キーがそのまま表示される
+    assert!(last_line().trim_end().ends_with('z'));
+}
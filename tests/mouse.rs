@@ -0,0 +1,147 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use wos_os_n71::interrupts::{InterruptIndex, IrqError};
+use wos_os_n71::ps2::Ps2Error;
+use wos_os_n71::task::keyboard::introduction::section_at;
+use wos_os_n71::task::mouse::{self, Cursor, MouseButtons, MouseEvent, MouseStream, PacketParser};
+use wos_os_n71::vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+fn feed(parser: &mut PacketParser, bytes: &[u8]) -> Option<MouseEvent> {
+    bytes
+        .iter()
+        .fold(None, |last, &byte| parser.push(byte).or(last))
+}
+
+#[test_case]
+fn mouse_initializes() {
+    let info = mouse::init().expect("mouse initialization failed");
+    assert!(info.packet_size() == 3 || info.packet_size() == 4);
+}
+
+#[test_case]
+fn second_init_reports_irq_error() {
+    assert_eq!(
+        mouse::init(),
+        Err(Ps2Error::Irq(IrqError::AlreadyRegistered(
+            InterruptIndex::Mouse.as_irq()
+        )))
+    );
+}
+
+#[test_case]
+fn mouse_stream_is_created_once() {
+    let _stream = MouseStream::new().expect("mouse stream creation failed");
+    assert_eq!(
+        MouseStream::new().err(),
+        Some(Ps2Error::StreamAlreadyCreated)
+    );
+}
+
+#[test_case]
+fn parses_three_byte_packets() {
+    let mut parser = PacketParser::new(3);
+    // 左ボタン，右に5，下に3 (Yは符号付きで上が正)
+    assert_eq!(parser.push(0x08 | 0x20 | 0x01), None);
+    assert_eq!(parser.push(5), None);
+    let event = parser.push(0xfd).unwrap();
+    assert_eq!(event.dx, 5);
+    assert_eq!(event.dy, -3);
+    assert_eq!(event.wheel, 0);
+    assert_eq!(event.buttons, MouseButtons::LEFT);
+}
+
+#[test_case]
+fn parses_wheel_in_four_byte_packets() {
+    let mut parser = PacketParser::new(4);
+    let event = feed(&mut parser, &[0x08 | 0x10 | 0x02, 0xff, 0, 0x0f]).unwrap();
+    assert_eq!(event.dx, -1);
+    assert_eq!(event.wheel, -1);
+    assert_eq!(event.buttons, MouseButtons::RIGHT);
+}
+
+#[test_case]
+fn resynchronizes_after_garbage() {
+    let mut parser = PacketParser::new(3);
+    // 1バイト目のビット3が立っていないものは読み飛ばす
+    let event = feed(&mut parser, &[0x00, 0x05, 0x08, 1, 2]).unwrap();
+    assert_eq!((event.dx, event.dy), (1, 2));
+}
+
+#[test_case]
+fn overflowed_movement_is_dropped() {
+    let mut parser = PacketParser::new(3);
+    let event = feed(&mut parser, &[0x08 | 0x40 | 0x04, 0x80, 0x10]).unwrap();
+    assert_eq!((event.dx, event.dy), (0, 0x10));
+    assert!(event.buttons.contains(MouseButtons::MIDDLE));
+}
+
+#[test_case]
+fn cursor_stays_on_screen() {
+    let mut cursor = Cursor::new();
+    let far = MouseEvent {
+        dx: i16::MIN,
+        dy: i16::MIN,
+        wheel: 0,
+        buttons: MouseButtons::NONE,
+    };
+    for _ in 0..4 {
+        cursor.apply(&far);
+    }
+    assert_eq!(cursor.position(), (BUFFER_HEIGHT - 1, 0));
+
+    let back = MouseEvent {
+        dx: i16::MAX,
+        dy: i16::MAX,
+        ..far
+    };
+    for _ in 0..4 {
+        cursor.apply(&back);
+    }
+    assert_eq!(cursor.position(), (0, BUFFER_WIDTH - 1));
+}
+
+#[test_case]
+fn legend_entries_map_to_sections() {
+    let line = "pWWyy n: name, a: age,         ";
+    assert_eq!(section_at(line, 6), Some("name"));
+    assert_eq!(section_at(line, 11), Some("name"));
+    assert_eq!(section_at(line, 13), None);
+    assert_eq!(section_at(line, 16), Some("age"));
+    assert_eq!(section_at(line, 25), None);
+    assert_eq!(section_at(line, 0), None);
+    assert_eq!(
+        section_at(" g: grade, p: programing lang ", 20),
+        Some("programing")
+    );
+}
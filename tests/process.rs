@@ -0,0 +1,200 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use spin::Mutex;
+use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+use wos_os_n71::process::{ExitStatus, Fault, Process, USER_REGION_START};
+use x86_64::structures::paging::{OffsetPageTable, PageTableFlags};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+// ring3で動かすテスト用のプログラム
+global_asm!(
+    ".global exit_with_pid_start",
+    ".global exit_with_pid_end",
+    "exit_with_pid_start:",
+    "mov eax, 3",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 1",
+    "syscall",
+    "exit_with_pid_end:",
+    "",
+    ".global read_null_start",
+    ".global read_null_end",
+    "read_null_start:",
+    "xor eax, eax",
+    "mov rax, [rax]",
+    "read_null_end:",
+    "",
+    ".global write_kernel_start",
+    ".global write_kernel_end",
+    "write_kernel_start:",
+    "mov rax, 0x444444440000",
+    "mov qword ptr [rax], 1",
+    "write_kernel_end:",
+    "",
+    ".global privileged_start",
+    ".global privileged_end",
+    "privileged_start:",
+    "hlt",
+    "privileged_end:",
+    "",
+    ".global invalid_opcode_start",
+    ".global invalid_opcode_end",
+    "invalid_opcode_start:",
+    "ud2",
+    "invalid_opcode_end:",
+);
+
+extern "C" {
+    static exit_with_pid_start: u8;
+    static exit_with_pid_end: u8;
+    static read_null_start: u8;
+    static read_null_end: u8;
+    static write_kernel_start: u8;
+    static write_kernel_end: u8;
+    static privileged_start: u8;
+    static privileged_end: u8;
+    static invalid_opcode_start: u8;
+    static invalid_opcode_end: u8;
+}
+
+/// 2つのラベルの間のコードをコピーしたプロセスを作る
+fn new_process(start: *const u8, end: *const u8) -> Process {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory not initialized");
+
+    let code = unsafe { core::slice::from_raw_parts(start, end as usize - start as usize) };
+    let entry = VirtAddr::new(USER_REGION_START);
+
+    let mut process = Process::new(mapper, frame_allocator).expect("failed to create process");
+    process
+        .map_region(entry, 4096, PageTableFlags::empty(), frame_allocator)
+        .expect("failed to map user code");
+    process
+        .copy_to_user(entry, code)
+        .expect("failed to copy user code");
+    process.set_entry(entry);
+    process
+}
+
+#[test_case]
+fn process_exits_with_its_pid() {
+    let mut process = new_process(
+        core::ptr::addr_of!(exit_with_pid_start),
+        core::ptr::addr_of!(exit_with_pid_end),
+    );
+    let pid = process.pid().as_u64() as i64;
+
+    assert_eq!(process.run(), ExitStatus::Exited(pid));
+    assert_eq!(process.exit_status(), Some(ExitStatus::Exited(pid)));
+    assert_eq!(
+        wos_os_n71::process::current_pid(),
+        wos_os_n71::process::Pid::KERNEL
+    );
+}
+
+#[test_case]
+fn page_fault_kills_only_the_process() {
+    let mut process = new_process(
+        core::ptr::addr_of!(read_null_start),
+        core::ptr::addr_of!(read_null_end),
+    );
+
+    assert_eq!(
+        process.run(),
+        ExitStatus::Killed(Fault::PageFault(VirtAddr::new(0)))
+    );
+}
+
+#[test_case]
+fn kernel_memory_is_not_writable_from_user_mode() {
+    let mut process = new_process(
+        core::ptr::addr_of!(write_kernel_start),
+        core::ptr::addr_of!(write_kernel_end),
+    );
+
+    assert_eq!(
+        process.run(),
+        ExitStatus::Killed(Fault::PageFault(VirtAddr::new(
+            wos_os_n71::allocator::HEAP_START as u64
+        )))
+    );
+}
+
+#[test_case]
+fn privileged_instruction_kills_the_process() {
+    let mut process = new_process(
+        core::ptr::addr_of!(privileged_start),
+        core::ptr::addr_of!(privileged_end),
+    );
+
+    assert_eq!(
+        process.run(),
+        ExitStatus::Killed(Fault::GeneralProtection(0))
+    );
+}
+
+#[test_case]
+fn invalid_opcode_kills_the_process() {
+    let mut process = new_process(
+        core::ptr::addr_of!(invalid_opcode_start),
+        core::ptr::addr_of!(invalid_opcode_end),
+    );
+
+    assert_eq!(process.run(), ExitStatus::Killed(Fault::InvalidOpcode));
+}
+
+#[test_case]
+fn dropping_a_process_frees_its_frames() {
+    let mut process = new_process(
+        core::ptr::addr_of!(exit_with_pid_start),
+        core::ptr::addr_of!(exit_with_pid_end),
+    );
+    process.run();
+
+    let before = memory::free_frame_count();
+    drop(process);
+    // レベル4テーブル，スタック4ページ，コード1ページ
+    // コードとスタックはレベル4の別のエントリの下にあるので，下の3段のテーブルが2組
+    let freed = memory::free_frame_count();
+    assert_eq!(freed, before + 1 + 4 + 1 + 2 * 3);
+
+    // 返したフレームから使われる
+    let process = new_process(
+        core::ptr::addr_of!(exit_with_pid_start),
+        core::ptr::addr_of!(exit_with_pid_end),
+    );
+    assert!(memory::free_frame_count() < freed);
+    drop(process);
+}
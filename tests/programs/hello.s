@@ -0,0 +1,52 @@
+# ELFローダのテストに使う，静的リンクされたユーザプログラム
+#
+# argv[1]をstdoutに書き出し，argc * 100 + strlen(argv[1])を終了コードにしてexitする
+# .dataへの書き込みと，.bssが0で埋められていることも確かめる
+#
+# ビルド方法:
+#   as -o hello.o hello.s
+#   ld -static -nostdlib -T hello.ld -o hello.elf hello.o
+#   strip hello.elf
+
+.intel_syntax noprefix
+
+.section .text
+.global _start
+_start:
+    mov r12, [rsp]
+    mov r13, [rsp + 16]
+
+    xor edx, edx
+1:
+    cmp byte ptr [r13 + rdx], 0
+    je 2f
+    inc rdx
+    jmp 1b
+2:
+    mov r14, rdx
+
+    # write(1, argv[1], len)
+    mov eax, 0
+    mov edi, 1
+    mov rsi, r13
+    syscall
+
+    mov rax, [rip + counter]
+    add rax, r14
+    mov [rip + counter], rax
+
+    # exit(argc * 100 + counter + scratch[4096])
+    imul rdi, r12, 100
+    add rdi, [rip + counter]
+    add rdi, [rip + scratch + 4096]
+    mov eax, 1
+    syscall
+    ud2
+
+.section .data
+counter:
+    .quad 0
+
+.section .bss
+scratch:
+    .skip 8192
@@ -0,0 +1,75 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use wos_os_n71::ps2::{self, RepeatDelay, ScancodeSet, Typematic, LED_CAPS_LOCK, LED_NUM_LOCK};
+use wos_os_n71::task::keyboard::event::LockState;
+
+entry_point!(main);
+
+fn main(_boot_info: &'static BootInfo) -> ! {
+    wos_os_n71::init();
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+#[test_case]
+fn controller_is_initialized() {
+    let info = ps2::info().expect("PS/2 controller not initialized");
+    // QEMUのi8042はマウス用のポートも持っている
+    assert!(info.dual_port);
+    assert!(info.translation);
+    // 変換されていれば，キーボードのセットによらずセット1が届く
+    assert_eq!(info.scancode_set, ScancodeSet::Set1);
+    assert_eq!(ps2::scancode_set(), ScancodeSet::Set1);
+}
+
+#[test_case]
+fn scancode_set_is_detected_without_translation() {
+    let info = ps2::init_with_translation(false).expect("PS/2 re-initialization failed");
+    assert!(!info.translation);
+    // QEMUのキーボードは，電源を入れたときのセット2のまま
+    assert_eq!(info.scancode_set, ScancodeSet::Set2);
+    assert_eq!(ps2::scancode_set(), ScancodeSet::Set2);
+
+    // 他のテストのために戻しておく
+    let info = ps2::init_with_translation(true).expect("PS/2 re-initialization failed");
+    assert!(info.translation);
+    assert_eq!(info.scancode_set, ScancodeSet::Set1);
+}
+
+#[test_case]
+fn keyboard_acknowledges_led_commands() {
+    assert_eq!(ps2::set_leds(LED_CAPS_LOCK | LED_NUM_LOCK), Ok(()));
+    assert_eq!(ps2::set_leds(0), Ok(()));
+}
+
+#[test_case]
+fn keyboard_acknowledges_typematic_commands() {
+    let fastest = Typematic {
+        delay: RepeatDelay::Ms250,
+        rate: 0,
+    };
+    assert_eq!(ps2::set_typematic(fastest), Ok(()));
+    assert_eq!(ps2::set_typematic(Typematic::default()), Ok(()));
+}
+
+#[test_case]
+fn lock_state_maps_to_leds() {
+    assert_eq!(LockState::default().leds(), LED_NUM_LOCK);
+    let locks = LockState {
+        caps_lock: true,
+        num_lock: false,
+        scroll_lock: true,
+    };
+    assert_eq!(locks.leds(), LED_CAPS_LOCK | ps2::LED_SCROLL_LOCK);
+}
@@ -0,0 +1,153 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{string::String, vec, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use wos_os_n71::allocator;
+use wos_os_n71::shell::{self, Command, CommandError, Context, Shell};
+use wos_os_n71::task::executor::Executor;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+fn new_shell() -> Shell {
+    Shell::new(Executor::new().monitor())
+}
+
+#[test_case]
+fn parse_splits_on_whitespace() {
+    assert_eq!(
+        shell::parse("  profile   name ").unwrap(),
+        vec![String::from("profile"), String::from("name")]
+    );
+    assert_eq!(shell::parse("").unwrap(), Vec::<String>::new());
+}
+
+#[test_case]
+fn parse_keeps_quoted_spaces() {
+    assert_eq!(
+        shell::parse(r#"echo "hello world" """#).unwrap(),
+        vec![
+            String::from("echo"),
+            String::from("hello world"),
+            String::new()
+        ]
+    );
+    assert_eq!(
+        shell::parse(r#"echo "oops"#),
+        Err(CommandError::UnterminatedQuote)
+    );
+}
+
+#[test_case]
+fn builtins_are_registered() {
+    let shell = new_shell();
+    for name in &[
+        "help", "clear", "meminfo", "tasks", "uptime", "reboot", "profile", "bind",
+    ] {
+        assert!(shell.registry().get(name).is_some(), "{} missing", name);
+    }
+}
+
+#[test_case]
+fn execute_runs_builtins() {
+    let shell = new_shell();
+    assert_eq!(shell.execute(""), Ok(()));
+    assert_eq!(shell.execute("help"), Ok(()));
+    assert_eq!(shell.execute("help tasks"), Ok(()));
+    assert_eq!(shell.execute("meminfo"), Ok(()));
+    assert_eq!(shell.execute("tasks"), Ok(()));
+    assert_eq!(shell.execute("uptime"), Ok(()));
+    assert_eq!(shell.execute("profile name"), Ok(()));
+}
+
+#[test_case]
+fn execute_reports_errors() {
+    let shell = new_shell();
+    assert_eq!(
+        shell.execute("frobnicate"),
+        Err(CommandError::UnknownCommand(String::from("frobnicate")))
+    );
+    assert_eq!(
+        shell.execute("uptime now"),
+        Err(CommandError::Usage("uptime"))
+    );
+    assert_eq!(
+        shell.execute("profile nickname"),
+        Err(CommandError::InvalidArgument(String::from("nickname")))
+    );
+}
+
+#[test_case]
+fn bind_validates_function_keys() {
+    let shell = new_shell();
+    assert_eq!(shell.execute(r#"bind F5 "profile name""#), Ok(()));
+    assert_eq!(shell.execute("bind F5"), Ok(()));
+    assert_eq!(
+        shell.execute("bind F12 help"),
+        Err(CommandError::InvalidArgument(String::from("F12")))
+    );
+    assert_eq!(
+        shell.execute("bind F0 help"),
+        Err(CommandError::InvalidArgument(String::from("F0")))
+    );
+}
+
+static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+fn count(_context: &Context<'_>, args: &[String]) -> Result<(), CommandError> {
+    CALLS.fetch_add(args.len(), Ordering::Relaxed);
+    Ok(())
+}
+
+#[test_case]
+fn registered_commands_receive_arguments() {
+    let mut shell = new_shell();
+    let previous = shell.registry_mut().register(Command {
+        name: "count",
+        usage: "count [args...]",
+        description: "count arguments",
+        run: count,
+    });
+    assert!(previous.is_none());
+
+    assert_eq!(shell.execute(r#"count a "b c" d"#), Ok(()));
+    assert_eq!(CALLS.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn heap_stats_track_allocations() {
+    let before = allocator::heap_stats();
+    let buffer: Vec<u8> = Vec::with_capacity(4096);
+    let during = allocator::heap_stats();
+    assert!(during.used >= before.used + 4096);
+    assert_eq!(during.size, allocator::HEAP_SIZE);
+    drop(buffer);
+    assert_eq!(allocator::heap_stats().used, before.used);
+}
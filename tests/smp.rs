@@ -0,0 +1,101 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::sync::Arc;
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use wos_os_n71::interrupts;
+use wos_os_n71::smp;
+use wos_os_n71::task::executor::{self, Executor};
+
+entry_point!(main);
+
+/// テストはQEMUの`-smp 4`で実行する
+const EXPECTED_CPUS: usize = 4;
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    smp::init(&mut mapper, &mut frame_allocator).expect("failed to start application processors");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+#[test_case]
+fn all_cpus_checked_in() {
+    assert_eq!(smp::online_cpus(), EXPECTED_CPUS);
+    assert_eq!(smp::cpu_index(), 0);
+}
+
+#[test_case]
+fn idle_application_processors_halt() {
+    // 仕事がなければ，APは回り続けずにhltする
+    let until = interrupts::timer_ticks() + 10;
+    while smp::idle_cpus() < EXPECTED_CPUS - 1 && interrupts::timer_ticks() < until {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(smp::idle_cpus(), EXPECTED_CPUS - 1);
+}
+
+#[test_case]
+fn tasks_run_on_every_cpu() {
+    // CPUごとのビット
+    let seen_cpus = Arc::new(AtomicU64::new(0));
+    let finished = Arc::new(AtomicUsize::new(0));
+    const TASKS: usize = 64;
+
+    let mut bsp_executor = Executor::new();
+    // BSPのキューに積むので，APは盗まないと仕事がない
+    for _ in 0..TASKS {
+        let (seen, finished) = (seen_cpus.clone(), finished.clone());
+        executor::spawn_shared(async move {
+            seen.fetch_or(1 << smp::cpu_index(), Ordering::Relaxed);
+            // 他のCPUが盗む時間を作るため，少し回る
+            for _ in 0..100_000 {
+                core::hint::spin_loop();
+            }
+            finished.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    bsp_executor.run_until_complete();
+
+    assert_eq!(finished.load(Ordering::Relaxed), TASKS);
+    assert_eq!(executor::live_shared_tasks(), 0);
+    assert_eq!(seen_cpus.load(Ordering::Relaxed), (1 << EXPECTED_CPUS) - 1);
+    let stolen: u64 = (1..EXPECTED_CPUS)
+        .map(|cpu| executor::cpu_stats(cpu).stolen)
+        .sum();
+    assert!(stolen > 0);
+}
+
+#[test_case]
+fn local_tasks_stay_on_their_executor() {
+    let mut bsp_executor = Executor::new();
+    let cpu = Arc::new(AtomicUsize::new(usize::MAX));
+    let seen = cpu.clone();
+    bsp_executor.spawn(async move {
+        seen.store(smp::cpu_index(), Ordering::Relaxed);
+    });
+    bsp_executor.run_until_complete();
+    assert_eq!(cpu.load(Ordering::Relaxed), 0);
+}
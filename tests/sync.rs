@@ -0,0 +1,242 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::future::Future;
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use core::task::Context;
+use futures_util::task::noop_waker_ref;
+use wos_os_n71::task::executor::Executor;
+use wos_os_n71::task::sync::{
+    mpsc::{self, TrySendError},
+    oneshot, Mutex, Notify,
+};
+use wos_os_n71::task::{block_on, yield_now};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+#[test_case]
+fn mutex_is_held_across_await() {
+    let mutex = Arc::new(Mutex::new(0usize));
+    let inside = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    for _ in 0..5 {
+        let (mutex, inside) = (mutex.clone(), inside.clone());
+        executor.spawn(async move {
+            for _ in 0..3 {
+                let mut value = mutex.lock().await;
+                // ロックを持っているのは常に1つのタスクだけ
+                assert_eq!(inside.fetch_add(1, Ordering::Relaxed), 0);
+                yield_now().await;
+                *value += 1;
+                inside.fetch_sub(1, Ordering::Relaxed);
+            }
+        });
+    }
+    executor.run_until_complete();
+
+    assert_eq!(*mutex.try_lock().unwrap(), 15);
+}
+
+#[test_case]
+fn channel_can_be_driven_with_block_on() {
+    let (tx, mut rx) = mpsc::channel(4);
+    block_on(async {
+        for i in 0..4 {
+            tx.send(i).await.unwrap();
+        }
+    });
+    drop(tx);
+
+    let sum = block_on(async {
+        let mut sum = 0;
+        while let Some(value) = rx.recv().await {
+            sum += value;
+        }
+        sum
+    });
+    assert_eq!(sum, 6);
+}
+
+#[test_case]
+fn try_lock_fails_while_locked() {
+    let mutex = Mutex::new(());
+    let guard = mutex.try_lock().unwrap();
+    assert!(mutex.try_lock().is_none());
+    drop(guard);
+    assert!(mutex.try_lock().is_some());
+}
+
+#[test_case]
+fn channel_delivers_in_order_and_closes() {
+    let received = Arc::new(spin::Mutex::new(Vec::new()));
+    let mut executor = Executor::new();
+    let (tx, mut rx) = mpsc::channel(2);
+
+    for producer in 0..2 {
+        let tx = tx.clone();
+        executor.spawn(async move {
+            for i in 0..10 {
+                tx.send(producer * 100 + i).await.unwrap();
+            }
+        });
+    }
+    drop(tx);
+    let r = received.clone();
+    executor.spawn(async move {
+        // 送信者がすべてドロップされるとNoneになる
+        while let Some(value) = rx.recv().await {
+            r.lock().push(value);
+        }
+    });
+    executor.run_until_complete();
+
+    let received = received.lock();
+    assert_eq!(received.len(), 20);
+    for producer in 0..2 {
+        let values: Vec<_> = received
+            .iter()
+            .copied()
+            .filter(|value| value / 100 == producer)
+            .collect();
+        assert_eq!(
+            values,
+            (0..10).map(|i| producer * 100 + i).collect::<Vec<_>>()
+        );
+    }
+}
+
+#[test_case]
+fn try_send_reports_full_and_closed() {
+    let (tx, mut rx) = mpsc::channel(1);
+    assert_eq!(tx.try_send(1), Ok(()));
+    assert_eq!(tx.try_send(2), Err(TrySendError::Full(2)));
+    assert_eq!(rx.try_recv(), Some(1));
+    drop(rx);
+    assert!(tx.is_closed());
+    assert_eq!(tx.try_send(3), Err(TrySendError::Closed(3)));
+}
+
+#[test_case]
+fn oneshot_delivers_value_or_error() {
+    let results = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    let (tx, rx) = oneshot::channel();
+    let (dropped_tx, dropped_rx) = oneshot::channel::<usize>();
+    let r = results.clone();
+    executor.spawn(async move {
+        r.fetch_add(rx.await.unwrap(), Ordering::Relaxed);
+        if dropped_rx.await == Err(oneshot::RecvError) {
+            r.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    executor.spawn(async move {
+        yield_now().await;
+        tx.send(41).unwrap();
+        drop(dropped_tx);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(results.load(Ordering::Relaxed), 42);
+}
+
+#[test_case]
+fn notify_one_is_remembered() {
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    // 待っているタスクがいないうちに通知する
+    notify.notify_one();
+    let (n, w) = (notify.clone(), woken.clone());
+    executor.spawn(async move {
+        n.notified().await;
+        w.fetch_add(1, Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(woken.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn notify_waiters_wakes_every_waiting_task() {
+    let notify = Arc::new(Notify::new());
+    let woken = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    for _ in 0..3 {
+        let (n, w) = (notify.clone(), woken.clone());
+        executor.spawn(async move {
+            n.notified().await;
+            w.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    let n = notify.clone();
+    executor.spawn(async move {
+        // 3つのタスクが待ち始めてから通知する
+        yield_now().await;
+        n.notify_waiters();
+    });
+    executor.run_until_complete();
+
+    assert_eq!(woken.load(Ordering::Relaxed), 3);
+}
+
+#[test_case]
+fn dropped_waiter_passes_on_notify_one() {
+    let notify = Notify::new();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut first = Box::pin(notify.notified());
+    let mut second = Box::pin(notify.notified());
+    assert!(first.as_mut().poll(&mut cx).is_pending());
+    assert!(second.as_mut().poll(&mut cx).is_pending());
+
+    notify.notify_one();
+    // 起こされた方が受け取らずに諦めたら，次に待っている方に回る
+    drop(first);
+    assert!(second.as_mut().poll(&mut cx).is_ready());
+}
+
+#[test_case]
+fn dropped_waiter_does_not_keep_notify_waiters() {
+    let notify = Notify::new();
+    let mut cx = Context::from_waker(noop_waker_ref());
+    let mut notified = Box::pin(notify.notified());
+    assert!(notified.as_mut().poll(&mut cx).is_pending());
+
+    notify.notify_waiters();
+    drop(notified);
+    // notify_waitersの通知は後から待ち始めたものには届かない
+    let mut later = Box::pin(notify.notified());
+    assert!(later.as_mut().poll(&mut cx).is_pending());
+}
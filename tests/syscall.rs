@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::arch::global_asm;
+use core::panic::PanicInfo;
+use spin::Mutex;
+use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+use wos_os_n71::process::ExitStatus;
+use wos_os_n71::syscall;
+use x86_64::structures::paging::{OffsetPageTable, PageTableFlags, Translate};
+use x86_64::VirtAddr;
+
+entry_point!(main);
+
+/// ユーザプログラムを置くアドレス
+const USER_CODE: u64 = 0x0000_4000_0000_0000;
+const USER_STACK: u64 = 0x0000_4000_0010_0000;
+const USER_STACK_SIZE: u64 = 4096 * 2;
+
+static MEMORY: Mutex<Option<(OffsetPageTable<'static>, BootInfoFrameAllocator)>> = Mutex::new(None);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *MEMORY.lock() = Some((mapper, frame_allocator));
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+// ring3で動かすテスト用のプログラム
+// 位置独立なので，ユーザページにコピーしてそのまま実行できる
+// getpid, write, sleepを呼び，writeの戻り値を終了コードにしてexitする
+global_asm!(
+    ".global user_program_start",
+    ".global user_program_end",
+    "user_program_start:",
+    "mov eax, 3",
+    "syscall",
+    "mov eax, 0",
+    "mov edi, 1",
+    "lea rsi, [rip + 2f]",
+    "lea rdx, [rip + 3f]",
+    "sub rdx, rsi",
+    "syscall",
+    "mov rbx, rax",
+    "mov eax, 2",
+    "mov edi, 1",
+    "syscall",
+    "mov eax, 1",
+    "mov rdi, rbx",
+    "syscall",
+    "ud2",
+    "2:",
+    ".ascii \"hello from ring 3\\n\"",
+    "3:",
+    "user_program_end:",
+);
+
+// writeに渡すポインタを最後の8バイトから読み，writeの戻り値を終了コードにしてexitする
+// その手前には，書き出せる8バイトの文字列を置いておく
+global_asm!(
+    ".global write_program_start",
+    ".global write_program_end",
+    "write_program_start:",
+    "mov eax, 0",
+    "mov edi, 1",
+    "mov rsi, [rip + 2f]",
+    "mov edx, 8",
+    "syscall",
+    "mov rdi, rax",
+    "mov eax, 1",
+    "syscall",
+    "ud2",
+    ".balign 8",
+    ".ascii \"checked\\n\"",
+    "2:",
+    ".quad 0",
+    "write_program_end:",
+);
+
+extern "C" {
+    static user_program_start: u8;
+    static user_program_end: u8;
+    static write_program_start: u8;
+    static write_program_end: u8;
+}
+
+fn program_bytes(start: &'static u8, end: &'static u8) -> &'static [u8] {
+    let start = start as *const u8;
+    let len = end as *const u8 as usize - start as usize;
+    unsafe { core::slice::from_raw_parts(start, len) }
+}
+
+/// テスト用のプログラムをユーザページにコピーし，ring3で実行する
+fn run_user_program() -> ExitStatus {
+    let program = unsafe { program_bytes(&user_program_start, &user_program_end) };
+    run_program(program, None)
+}
+
+/// `ptr`をwriteに渡すプログラムを実行する
+fn run_write_program(ptr: u64) -> ExitStatus {
+    let program = unsafe { program_bytes(&write_program_start, &write_program_end) };
+    run_program(program, Some(ptr))
+}
+
+/// `program`をユーザページにコピーし，ring3で実行する
+///
+/// `argument`があれば，プログラムの最後の8バイトに書き込む
+fn run_program(program: &[u8], argument: Option<u64>) -> ExitStatus {
+    let mut memory = MEMORY.lock();
+    let (mapper, frame_allocator) = memory.as_mut().expect("memory not initialized");
+
+    let code_start = VirtAddr::new(USER_CODE);
+    let stack_bottom = VirtAddr::new(USER_STACK);
+    // テストを何度実行しても良いように，マップ済みなら使い回す
+    if mapper.translate_addr(code_start).is_none() {
+        memory::map_user_region(
+            code_start,
+            4096,
+            PageTableFlags::WRITABLE,
+            mapper,
+            frame_allocator,
+        )
+        .expect("failed to map user code");
+        memory::map_user_region(
+            stack_bottom,
+            USER_STACK_SIZE,
+            PageTableFlags::WRITABLE,
+            mapper,
+            frame_allocator,
+        )
+        .expect("failed to map user stack");
+    }
+    drop(memory);
+
+    unsafe {
+        let code: *mut u8 = code_start.as_mut_ptr();
+        core::ptr::copy_nonoverlapping(program.as_ptr(), code, program.len());
+        if let Some(argument) = argument {
+            let slot = code.add(program.len() - 8) as *mut u64;
+            slot.write_unaligned(argument);
+        }
+        syscall::enter_user_mode(code_start, stack_bottom + USER_STACK_SIZE)
+    }
+}
+
+#[test_case]
+fn syscalls_from_ring3() {
+    let exit_code = run_user_program();
+    assert_eq!(
+        exit_code,
+        ExitStatus::Exited("hello from ring 3\n".len() as i64)
+    );
+}
+
+#[test_case]
+fn user_mode_can_be_entered_again() {
+    let ticks = wos_os_n71::interrupts::timer_ticks();
+    assert_eq!(run_user_program(), ExitStatus::Exited(18));
+    // sleep(1)を呼んでいるので，少なくとも1回はタイマ割り込みが入っている
+    assert!(wos_os_n71::interrupts::timer_ticks() > ticks);
+}
+
+#[test_case]
+fn write_rejects_kernel_heap_pointers() {
+    let heap = wos_os_n71::allocator::HEAP_START as u64;
+    assert_eq!(run_write_program(heap), ExitStatus::Exited(syscall::EFAULT));
+}
+
+#[test_case]
+fn write_rejects_unmapped_user_pointers() {
+    // ユーザ領域の中だが，マップしていない
+    let unmapped = USER_STACK + USER_STACK_SIZE + 4096;
+    assert_eq!(
+        run_write_program(unmapped),
+        ExitStatus::Exited(syscall::EFAULT)
+    );
+}
+
+#[test_case]
+fn write_accepts_mapped_user_pointers() {
+    let program = unsafe { program_bytes(&write_program_start, &write_program_end) };
+    let text = USER_CODE + program.len() as u64 - 16;
+    assert_eq!(run_write_program(text), ExitStatus::Exited(8));
+}
@@ -0,0 +1,161 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec::Vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use wos_os_n71::task::{executor::Executor, sleep};
+use wos_os_n71::thread::{self, ThreadError, ThreadId, MAX_THREADS};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    thread::init(&mut mapper, &mut frame_allocator).expect("failed to map thread stacks");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+/// スレッドが終了するまでhltで待つ
+fn wait_for(id: ThreadId) {
+    while thread::is_alive(id) {
+        x86_64::instructions::hlt();
+    }
+}
+
+#[test_case]
+fn spinning_thread_is_preempted() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let spins = Arc::new(AtomicUsize::new(0));
+
+    let (s, n) = (stop.clone(), spins.clone());
+    // 自分からは一度も譲らないスレッド
+    let id = thread::spawn("spinner", move || {
+        while !s.load(Ordering::Relaxed) {
+            n.fetch_add(1, Ordering::Relaxed);
+        }
+    })
+    .expect("failed to spawn thread");
+    // タイマ割り込みで切り替わらなければ，ここから先に進まない
+    while spins.load(Ordering::Relaxed) == 0 {
+        x86_64::instructions::hlt();
+    }
+    assert_eq!(thread::current_id(), ThreadId::MAIN);
+
+    stop.store(true, Ordering::Relaxed);
+    wait_for(id);
+}
+
+#[test_case]
+fn yield_now_runs_threads_round_robin() {
+    let log = Arc::new(Mutex::new(Vec::new()));
+
+    let mut ids = Vec::new();
+    for n in 0..2 {
+        let log = log.clone();
+        let id = thread::spawn("worker", move || {
+            for _ in 0..3 {
+                x86_64::instructions::interrupts::without_interrupts(|| log.lock().push(n));
+                thread::yield_now();
+            }
+        })
+        .expect("failed to spawn thread");
+        ids.push(id);
+    }
+    for id in ids {
+        wait_for(id);
+    }
+
+    let log = log.lock();
+    assert_eq!(log.len(), 6);
+    // 各スレッドは譲るたびに後ろに回るので，交互に並ぶ
+    for pair in log.chunks(2) {
+        assert_ne!(pair[0], pair[1]);
+    }
+}
+
+#[test_case]
+fn finished_thread_is_not_alive() {
+    let ran = Arc::new(AtomicBool::new(false));
+    let r = ran.clone();
+    let id = thread::spawn("short", move || {
+        assert_eq!(thread::current_name(), "short");
+        r.store(true, Ordering::Relaxed);
+    })
+    .expect("failed to spawn thread");
+    wait_for(id);
+
+    assert!(ran.load(Ordering::Relaxed));
+    assert!(!thread::is_alive(id));
+    assert!(thread::is_alive(ThreadId::MAIN));
+}
+
+#[test_case]
+fn executor_runs_alongside_busy_thread() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let s = stop.clone();
+    let id = thread::spawn("busy", move || while !s.load(Ordering::Relaxed) {})
+        .expect("failed to spawn thread");
+
+    let finished = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+    for _ in 0..3 {
+        let finished = finished.clone();
+        executor.spawn(async move {
+            sleep(Duration::from_millis(100)).await;
+            finished.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+    executor.run_until_complete();
+    assert_eq!(finished.load(Ordering::Relaxed), 3);
+
+    stop.store(true, Ordering::Relaxed);
+    wait_for(id);
+}
+
+#[test_case]
+fn stacks_are_reused_after_threads_finish() {
+    let stop = Arc::new(AtomicBool::new(false));
+    let mut ids = Vec::new();
+    for _ in 0..MAX_THREADS {
+        let s = stop.clone();
+        let id = thread::spawn("waiter", move || while !s.load(Ordering::Relaxed) {})
+            .expect("failed to spawn thread");
+        ids.push(id);
+    }
+    // スタックを使い切っている
+    assert_eq!(
+        thread::spawn("extra", || {}).err(),
+        Some(ThreadError::TooManyThreads)
+    );
+
+    stop.store(true, Ordering::Relaxed);
+    for id in ids {
+        wait_for(id);
+    }
+    let id = thread::spawn("extra", || {}).expect("stack was not reused");
+    wait_for(id);
+}
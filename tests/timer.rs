@@ -0,0 +1,166 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(wos_os_n71::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use alloc::{sync::Arc, vec};
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::time::Duration;
+use wos_os_n71::interrupts::timer_ticks;
+use wos_os_n71::task::executor::Executor;
+use wos_os_n71::task::timer::{self, duration_to_ticks, Elapsed};
+use wos_os_n71::task::{block_on, select, select_all, sleep, timeout, Either};
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    use wos_os_n71::allocator;
+    use wos_os_n71::memory::{self, BootInfoFrameAllocator};
+    use x86_64::VirtAddr;
+
+    wos_os_n71::init();
+
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+
+    test_main();
+    loop {}
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    wos_os_n71::test_panic_handler(info)
+}
+
+#[test_case]
+fn duration_is_rounded_up_to_ticks() {
+    assert_eq!(duration_to_ticks(Duration::from_secs(0)), 0);
+    assert_eq!(duration_to_ticks(Duration::from_millis(1)), 1);
+    // 約18.2Hz
+    assert_eq!(duration_to_ticks(Duration::from_secs(1)), 19);
+    assert_eq!(duration_to_ticks(Duration::from_secs(10)), 183);
+}
+
+#[test_case]
+fn sleep_waits_for_timer_interrupts() {
+    let woke_at = Arc::new(AtomicU64::new(0));
+    let mut executor = Executor::new();
+
+    let start = timer_ticks();
+    let w = woke_at.clone();
+    executor.spawn(async move {
+        sleep(Duration::from_millis(200)).await;
+        w.store(timer_ticks(), Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert!(woke_at.load(Ordering::Relaxed) >= start + 4);
+    assert_eq!(timer::next_deadline(), None);
+}
+
+#[test_case]
+fn timeout_expires_on_pending_future() {
+    let result = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    let r = result.clone();
+    executor.spawn(async move {
+        let pending = futures_util::future::pending::<()>();
+        if timeout(Duration::from_millis(100), pending).await == Err(Elapsed) {
+            r.store(1, Ordering::Relaxed);
+        }
+    });
+    executor.run_until_complete();
+
+    assert_eq!(result.load(Ordering::Relaxed), 1);
+}
+
+#[test_case]
+fn timeout_returns_output_of_finished_future() {
+    let result = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    let r = result.clone();
+    executor.spawn(async move {
+        let output = timeout(Duration::from_secs(10), async { 42 }).await;
+        r.store(output.unwrap(), Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(result.load(Ordering::Relaxed), 42);
+    // 完了したtimeoutのタイマは残らない
+    assert_eq!(timer::next_deadline(), None);
+}
+
+#[test_case]
+fn select_returns_first_to_finish() {
+    let result = Arc::new(AtomicUsize::new(0));
+    let mut executor = Executor::new();
+
+    let r = result.clone();
+    executor.spawn(async move {
+        let slow = sleep(Duration::from_secs(10));
+        let fast = async {
+            sleep(Duration::from_millis(50)).await;
+            7
+        };
+        if let Either::Right(value) = select(slow, fast).await {
+            r.store(value, Ordering::Relaxed);
+        }
+
+        let sleeps = vec![
+            sleep(Duration::from_secs(10)),
+            sleep(Duration::from_millis(50)),
+            sleep(Duration::from_secs(5)),
+        ];
+        let ((), index) = select_all(sleeps).await;
+        r.fetch_add(index * 10, Ordering::Relaxed);
+    });
+    executor.run_until_complete();
+
+    assert_eq!(result.load(Ordering::Relaxed), 17);
+}
+
+#[test_case]
+fn block_on_returns_output() {
+    assert_eq!(block_on(async { 6 * 7 }), 42);
+}
+
+#[test_case]
+fn block_on_halts_until_timer_wakes() {
+    let start = timer_ticks();
+    let output = block_on(async {
+        sleep(Duration::from_millis(100)).await;
+        "woke"
+    });
+
+    assert_eq!(output, "woke");
+    assert!(timer_ticks() >= start + 2);
+    assert_eq!(
+        block_on(timeout(
+            Duration::from_millis(50),
+            futures_util::future::pending::<()>()
+        )),
+        Err(Elapsed)
+    );
+}
+
+#[test_case]
+fn block_on_restores_interrupt_state() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::disable();
+    block_on(sleep(Duration::from_millis(20)));
+    assert!(!interrupts::are_enabled());
+    interrupts::enable();
+
+    block_on(async {});
+    assert!(interrupts::are_enabled());
+}